@@ -0,0 +1,38 @@
+use crate::framing::Framing;
+use crate::net::NetOpts;
+use anyhow::anyhow;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+/// The tokio counterpart of `tcp_client::connect`: the printer is a task instead of a thread.
+pub fn connect(address: &str, framing: Framing, net: &NetOpts) -> anyhow::Result<()> {
+    let stream = net.connect_tcp(address)?;
+    stream.set_nonblocking(true)?;
+    let runtime = Runtime::new()?;
+    let result = runtime.block_on(async move {
+        let (mut reader, mut writer) = TcpStream::from_std(stream)?.into_split();
+        let printer = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            while let Some(reply) = framing.read_frame_async(&mut reader, &mut buf).await? {
+                println!("{}", String::from_utf8_lossy(&reply));
+            }
+            Ok::<_, std::io::Error>(())
+        });
+
+        let mut lines = BufReader::new(io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            framing
+                .write_frame_async(&mut writer, line.as_bytes())
+                .await?;
+        }
+        writer.shutdown().await?;
+        printer
+            .await
+            .map_err(|_| anyhow!("Printer task panicked"))??;
+        Ok(())
+    });
+    // Do not wait for the blocking stdin read if the server hung up first.
+    runtime.shutdown_background();
+    result
+}
@@ -0,0 +1,89 @@
+use crate::framing::Framing;
+use crate::net::NetOpts;
+use crate::shutdown::{self, Shutdown, ShutdownOpts};
+use log::{debug, error, info, warn};
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// The tokio counterpart of `tcp_server::EchoServer`: one task instead of one thread per connection.
+/// Stops on SIGINT or SIGTERM, giving open connections the drain timeout to finish.
+pub fn server(
+    address: &str,
+    framing: Framing,
+    net: &NetOpts,
+    shutdown: &ShutdownOpts,
+) -> anyhow::Result<()> {
+    let listener = net.bind_tcp(address)?;
+    listener.set_nonblocking(true)?;
+    let net = net.clone();
+    let stop = Shutdown::on_signals()?;
+    let drain_timeout = shutdown.drain_timeout();
+    Runtime::new()?.block_on(async move {
+        let listener = TcpListener::from_std(listener)?;
+        let mut tasks: Vec<JoinHandle<()>> = Vec::new();
+        let mut accepted = 0u64;
+        while !stop.is_triggered() {
+            tasks.retain(|task| !task.is_finished());
+            // Wake up now and then to see whether a signal asked us to stop.
+            let (stream, remote_addr) =
+                match time::timeout(shutdown::POLL_INTERVAL, listener.accept()).await {
+                    Err(_) => continue,
+                    Ok(Ok(accepted)) => accepted,
+                    Ok(Err(e)) => {
+                        // Usually transient, like running out of file descriptors,
+                        // so back off instead of giving up or spinning.
+                        warn!("Accept: {}", e);
+                        time::sleep(shutdown::POLL_INTERVAL).await;
+                        continue;
+                    }
+                };
+            if let Err(e) = net.configure_tcp(&stream) {
+                error!("{}: {}", remote_addr, e);
+                continue;
+            }
+            accepted += 1;
+            tasks.push(tokio::spawn(async move {
+                debug!("Handling data from {}", remote_addr);
+                handler(stream, framing)
+                    .await
+                    .unwrap_or_else(|error| error!("{:?}", error));
+            }));
+        }
+
+        let deadline = Instant::now() + drain_timeout;
+        info!(
+            "Stopping: waiting up to {:?} for {} connections",
+            drain_timeout,
+            tasks.len()
+        );
+        for task in tasks {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let abort = task.abort_handle();
+            if time::timeout(remaining, task).await.is_err() {
+                abort.abort();
+            }
+        }
+        info!("Stopped: served {} connections", accepted);
+        Ok(())
+    })
+}
+
+/// Echoes every message received on `stream` back to the peer until it closes the connection.
+pub async fn handler(mut stream: TcpStream, framing: Framing) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        let msg = match framing.read_frame_async(&mut stream, &mut buf).await? {
+            Some(msg) => msg,
+            None => {
+                debug!("Connection closed");
+                return Ok(());
+            }
+        };
+
+        debug!("Echoing {:?}", String::from_utf8_lossy(&msg));
+        framing.write_frame_async(&mut stream, &msg).await?;
+    }
+}
@@ -0,0 +1,56 @@
+use crate::multicast::MulticastOpts;
+use crate::net::{self, NetOpts};
+use crate::udp_server::MAX_UDP_PAYLOAD;
+use anyhow::{anyhow, Context};
+use std::time::Duration;
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+use tokio::time::{self, Instant};
+
+/// The tokio counterpart of `udp_client::communicate`.
+pub fn communicate(address: &str, multicast: &MulticastOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let target = net::resolve(address)?[0];
+    let socket = std::net::UdpSocket::bind(net::unspecified(&target))?;
+    net.configure_udp(&socket)?;
+    let many_replies = multicast.configure_client(&socket, &target)?;
+    // A unicast peer replies from the address we send to, so any of its addresses will do.
+    let socket = if many_replies {
+        socket
+    } else {
+        net.connect_udp(address)?
+    };
+    socket.set_nonblocking(true)?;
+    let wait = Duration::from_millis(multicast.wait);
+    let read_timeout = net.read_timeout.map(Duration::from_millis);
+
+    Runtime::new()?.block_on(async move {
+        let socket = UdpSocket::from_std(socket)?;
+        let mut lines = BufReader::new(io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            let input = line + "\n";
+            let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+            if !many_replies {
+                socket.send(input.as_bytes()).await?;
+                let received = match read_timeout {
+                    Some(timeout) => time::timeout(timeout, socket.recv(&mut buf))
+                        .await
+                        .map_err(|_| anyhow!("No reply from {} within {:?}", address, timeout))?,
+                    None => socket.recv(&mut buf).await,
+                };
+                let size = received.with_context(|| format!("Cannot receive from {}", address))?;
+                print!("{}", String::from_utf8_lossy(&buf[..size]));
+                continue;
+            }
+
+            // Any number of hosts may answer a group or broadcast datagram, so collect replies for a while.
+            socket.send_to(input.as_bytes(), target).await?;
+            let deadline = Instant::now() + wait;
+            while let Ok(result) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                let (size, src) = result?;
+                print!("{}: {}", src, String::from_utf8_lossy(&buf[..size]));
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,49 @@
+use crate::multicast::MulticastOpts;
+use crate::net::NetOpts;
+use crate::shutdown::{self, Shutdown, Summary};
+use crate::udp_server::MAX_UDP_PAYLOAD;
+use anyhow::anyhow;
+use log::{debug, info};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+use tokio::time::{self, Instant};
+
+/// The tokio counterpart of `udp_server::server`.
+pub fn server(address: &str, multicast: &MulticastOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let (socket, _membership) = multicast.bind_server(address, net)?;
+    socket.set_nonblocking(true)?;
+    let stop = Shutdown::on_signals()?;
+    let read_timeout = net.read_timeout.map(Duration::from_millis);
+    let summary = Runtime::new()?.block_on(async move {
+        let socket = UdpSocket::from_std(socket)?;
+        let mut summary = Summary::default();
+        let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+        let mut last_received = Instant::now();
+        while !stop.is_triggered() {
+            // Wake up now and then to see whether a signal asked us to stop.
+            let (size, src) =
+                match time::timeout(shutdown::POLL_INTERVAL, socket.recv_from(&mut buf)).await {
+                    Err(_) => match read_timeout {
+                        Some(timeout) if last_received.elapsed() >= timeout => {
+                            return Err(anyhow!("No datagram within {:?}", timeout))
+                        }
+                        _ => continue,
+                    },
+                    Ok(received) => received?,
+                };
+            last_received = Instant::now();
+            debug!("handling data from {}", src);
+            debug!("Echoing {:?}", String::from_utf8_lossy(&buf[..size]));
+            socket.send_to(&buf[..size], src).await?;
+            summary.connections += 1;
+            summary.bytes += size as u64;
+        }
+        Ok::<_, anyhow::Error>(summary)
+    })?;
+    info!(
+        "Stopped: echoed {} datagrams, {} bytes",
+        summary.connections, summary.bytes
+    );
+    Ok(())
+}
@@ -0,0 +1,385 @@
+use crate::framing::Framing;
+use crate::net::NetOpts;
+use crate::udp_server::MAX_UDP_PAYLOAD;
+use anyhow::anyhow;
+use clap::Clap;
+use log::{debug, error};
+use serde::Serialize;
+use std::io::{BufReader, ErrorKind};
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a UDP benchmark connection waits for an echo before counting the datagram as lost.
+const UDP_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clap, Debug)]
+pub struct BenchOpts {
+    /// Number of concurrent connections opened by the benchmark.
+    #[clap(long, default_value = "1")]
+    pub connections: usize,
+    /// Size of each benchmark message in bytes.
+    #[clap(long, default_value = "64")]
+    pub payload_size: usize,
+    /// Target total messages per second across all connections. 0 sends as fast as possible.
+    #[clap(long, default_value = "0")]
+    pub rate: u64,
+    /// How long the benchmark runs, in seconds.
+    #[clap(long, default_value = "10")]
+    pub duration: u64,
+    /// Format of the benchmark report.
+    #[clap(long, arg_enum, default_value = "table")]
+    pub output: Output,
+}
+
+impl Default for BenchOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+#[derive(Clap, Debug, Clone, Copy)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+/// What one connection measured.
+#[derive(Default)]
+struct Sample {
+    rtts: Vec<Duration>,
+    lost: u64,
+    errors: u64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    protocol: &'static str,
+    connections: usize,
+    payload_size: usize,
+    elapsed_secs: f64,
+    messages: u64,
+    lost: u64,
+    errors: u64,
+    messages_per_sec: f64,
+    bytes_per_sec: f64,
+    latency_us: Latency,
+}
+
+#[derive(Serialize)]
+struct Latency {
+    min: u128,
+    mean: u128,
+    p50: u128,
+    p95: u128,
+    p99: u128,
+    max: u128,
+}
+
+pub fn tcp(address: &str, framing: Framing, opts: &BenchOpts, net: &NetOpts) -> anyhow::Result<()> {
+    check(opts, 1)?;
+    let address = address.to_string();
+    let net = net.clone();
+    let payload = vec![b'x'; opts.payload_size];
+    run(opts, "tcp", move |deadline, interval| {
+        let mut sample = Sample::default();
+        let stream = match net.connect_tcp(&address) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("{}", e);
+                sample.errors += 1;
+                return sample;
+            }
+        };
+        let mut reader = BufReader::new(stream);
+        pace(deadline, interval, |_| {
+            let start = Instant::now();
+            let result = framing
+                .write_frame(reader.get_mut(), &payload)
+                .and_then(|_| framing.read_frame(&mut reader));
+            match result {
+                Ok(Some(reply)) if reply.len() == payload.len() => {
+                    sample.rtts.push(start.elapsed());
+                    true
+                }
+                Ok(_) => {
+                    error!("Connection closed or echo corrupted");
+                    sample.errors += 1;
+                    false
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    sample.errors += 1;
+                    false
+                }
+            }
+        });
+        sample
+    })
+}
+
+pub fn udp(address: &str, opts: &BenchOpts, net: &NetOpts) -> anyhow::Result<()> {
+    // Every datagram carries its sequence number so that late echoes are not mistaken for the current one.
+    check(opts, 8)?;
+    if opts.payload_size > MAX_UDP_PAYLOAD {
+        return Err(anyhow!(
+            "--payload-size must be at most {} for UDP",
+            MAX_UDP_PAYLOAD
+        ));
+    }
+    let address = address.to_string();
+    let net = net.clone();
+    let payload_size = opts.payload_size;
+    run(opts, "udp", move |deadline, interval| {
+        let mut sample = Sample::default();
+        let socket = match connect_udp(&address, &net) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("{}", e);
+                sample.errors += 1;
+                return sample;
+            }
+        };
+        let mut payload = vec![b'x'; payload_size];
+        let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+        pace(deadline, interval, |seq| {
+            payload[..8].copy_from_slice(&seq.to_be_bytes());
+            let start = Instant::now();
+            if let Err(e) = socket.send(&payload) {
+                error!("{}", e);
+                sample.errors += 1;
+                return false;
+            }
+            loop {
+                match socket.recv(&mut buf) {
+                    Ok(size) if size == payload_size && buf[..8] == payload[..8] => {
+                        sample.rtts.push(start.elapsed());
+                        return true;
+                    }
+                    Ok(_) => debug!("Ignoring a late or foreign datagram"),
+                    Err(e)
+                        if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                    {
+                        sample.lost += 1;
+                        return true;
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        sample.errors += 1;
+                        return false;
+                    }
+                }
+            }
+        });
+        sample
+    })
+}
+
+fn check(opts: &BenchOpts, min_payload: usize) -> anyhow::Result<()> {
+    if opts.connections == 0 {
+        return Err(anyhow!("--connections must be at least 1"));
+    }
+    if opts.payload_size < min_payload {
+        return Err(anyhow!("--payload-size must be at least {}", min_payload));
+    }
+    Ok(())
+}
+
+fn connect_udp(address: &str, net: &NetOpts) -> std::io::Result<UdpSocket> {
+    let socket = net.connect_udp(address)?;
+    socket.set_read_timeout(Some(UDP_REPLY_TIMEOUT))?;
+    Ok(socket)
+}
+
+/// Calls `send_one` with increasing sequence numbers until `deadline`, at most once per `interval`.
+/// Stops early when `send_one` returns false.
+fn pace<F: FnMut(u64) -> bool>(deadline: Instant, interval: Option<Duration>, mut send_one: F) {
+    let mut next = Instant::now();
+    let mut seq = 0u64;
+    while Instant::now() < deadline {
+        if let Some(interval) = interval {
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            }
+            next += interval;
+        }
+        if !send_one(seq) {
+            return;
+        }
+        seq += 1;
+    }
+}
+
+/// Runs `connection` on `--connections` threads and prints the combined report.
+fn run<F>(opts: &BenchOpts, protocol: &'static str, connection: F) -> anyhow::Result<()>
+where
+    F: Fn(Instant, Option<Duration>) -> Sample + Send + Clone + 'static,
+{
+    let interval = match opts.rate {
+        0 => None,
+        rate => Some(Duration::from_secs_f64(
+            opts.connections as f64 / rate as f64,
+        )),
+    };
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(opts.duration);
+
+    let handles: Vec<_> = (0..opts.connections)
+        .map(|_| {
+            let connection = connection.clone();
+            thread::spawn(move || connection(deadline, interval))
+        })
+        .collect();
+    let mut total = Sample::default();
+    for handle in handles {
+        let sample = handle
+            .join()
+            .map_err(|_| anyhow!("Benchmark thread panicked"))?;
+        total.rtts.extend(sample.rtts);
+        total.lost += sample.lost;
+        total.errors += sample.errors;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let report = make_report(opts, protocol, total, elapsed);
+    match opts.output {
+        Output::Table => print_table(&report),
+        Output::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+fn make_report(
+    opts: &BenchOpts,
+    protocol: &'static str,
+    mut total: Sample,
+    elapsed: f64,
+) -> Report {
+    total.rtts.sort();
+    let rtts = &total.rtts;
+    let messages = rtts.len() as u64;
+    // Nearest-rank percentile.
+    let percentile = |p: f64| -> u128 {
+        if rtts.is_empty() {
+            return 0;
+        }
+        let rank = ((p / 100.0) * rtts.len() as f64).ceil() as usize;
+        rtts[rank.max(1) - 1].as_micros()
+    };
+    let mean = if rtts.is_empty() {
+        0
+    } else {
+        rtts.iter().map(Duration::as_micros).sum::<u128>() / rtts.len() as u128
+    };
+
+    Report {
+        protocol,
+        connections: opts.connections,
+        payload_size: opts.payload_size,
+        elapsed_secs: elapsed,
+        messages,
+        lost: total.lost,
+        errors: total.errors,
+        messages_per_sec: messages as f64 / elapsed,
+        bytes_per_sec: (messages * opts.payload_size as u64) as f64 / elapsed,
+        latency_us: Latency {
+            min: rtts.first().map_or(0, Duration::as_micros),
+            mean,
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+            max: rtts.last().map_or(0, Duration::as_micros),
+        },
+    }
+}
+
+fn print_table(report: &Report) {
+    println!("{:<16}{}", "protocol", report.protocol);
+    println!("{:<16}{}", "connections", report.connections);
+    println!("{:<16}{} B", "payload", report.payload_size);
+    println!("{:<16}{:.2} s", "elapsed", report.elapsed_secs);
+    println!("{:<16}{}", "messages", report.messages);
+    println!("{:<16}{}", "lost", report.lost);
+    println!("{:<16}{}", "errors", report.errors);
+    println!("{:<16}{:.1} msg/s", "throughput", report.messages_per_sec);
+    println!("{:<16}{:.1} KiB/s", "", report.bytes_per_sec / 1024.0);
+    println!();
+    println!("{:<8}{:>12}", "latency", "us");
+    let latency = &report.latency_us;
+    for (name, value) in &[
+        ("min", latency.min),
+        ("mean", latency.mean),
+        ("p50", latency.p50),
+        ("p95", latency.p95),
+        ("p99", latency.p99),
+        ("max", latency.max),
+    ] {
+        println!("{:<8}{:>12}", name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(micros: &[u64], elapsed: f64) -> Report {
+        let opts = BenchOpts {
+            payload_size: 100,
+            ..BenchOpts::default()
+        };
+        let sample = Sample {
+            rtts: micros.iter().map(|us| Duration::from_micros(*us)).collect(),
+            lost: 2,
+            errors: 1,
+        };
+        make_report(&opts, "tcp", sample, elapsed)
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        // 1..=100 µs in shuffled order: the p-th percentile is p µs.
+        let micros: Vec<u64> = (1..=100).map(|i| (i * 37) % 100 + 1).collect();
+        let latency = report(&micros, 1.0).latency_us;
+        assert_eq!((latency.min, latency.max), (1, 100));
+        assert_eq!((latency.p50, latency.p95, latency.p99), (50, 95, 99));
+
+        // With few samples the rank rounds up: 95% of 3 samples is the 3rd.
+        let latency = report(&[30, 10, 20], 1.0).latency_us;
+        assert_eq!((latency.p50, latency.p95, latency.p99), (20, 30, 30));
+        let latency = report(&[7], 1.0).latency_us;
+        assert_eq!(
+            (latency.min, latency.p50, latency.p99, latency.max),
+            (7, 7, 7, 7)
+        );
+    }
+
+    #[test]
+    fn mean_and_rates_cover_all_messages() {
+        let report = report(&[10, 20, 60], 2.0);
+        assert_eq!(report.latency_us.mean, 30);
+        assert_eq!(report.messages, 3);
+        assert_eq!((report.lost, report.errors), (2, 1));
+        assert_eq!(report.messages_per_sec, 1.5);
+        assert_eq!(report.bytes_per_sec, 150.0);
+    }
+
+    #[test]
+    fn no_samples_report_zero_latency() {
+        let report = report(&[], 1.0);
+        assert_eq!(report.messages, 0);
+        let latency = report.latency_us;
+        assert_eq!(
+            [
+                latency.min,
+                latency.mean,
+                latency.p50,
+                latency.p95,
+                latency.p99,
+                latency.max
+            ],
+            [0; 6]
+        );
+        assert_eq!(report.messages_per_sec, 0.0);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::net::NetOpts;
+use crate::tcp_server;
+use log::{debug, error};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const DEFAULT_ROOM: &str = "lobby";
+/// Lines queued for one client before further messages to it are dropped.
+const OUTBOX_CAPACITY: usize = 256;
+
+struct Client {
+    nick: String,
+    room: String,
+    outbox: SyncSender<String>,
+}
+
+/// Everyone connected to the chat server, by connection ID.
+#[derive(Default)]
+struct Clients {
+    clients: BTreeMap<usize, Client>,
+}
+
+impl Clients {
+    /// Queues `line` to everyone in `room` except `from`. Never blocks on a slow client.
+    fn broadcast(&self, room: &str, from: Option<usize>, line: &str) {
+        for (id, client) in &self.clients {
+            if client.room != room || Some(*id) == from {
+                continue;
+            }
+            match client.outbox.try_send(line.to_string()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    debug!("Dropping a message to slow client {}", client.nick)
+                }
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+
+    fn send_to(&self, id: usize, line: &str) {
+        if let Some(client) = self.clients.get(&id) {
+            let _ = client.outbox.try_send(line.to_string());
+        }
+    }
+
+    fn nick_taken(&self, nick: &str) -> bool {
+        self.clients.values().any(|client| client.nick == nick)
+    }
+}
+
+pub fn server(address: &str, net: &NetOpts) -> anyhow::Result<()> {
+    let clients = Arc::new(Mutex::new(Clients::default()));
+    let next_id = Arc::new(AtomicUsize::new(1));
+    tcp_server::serve(address, net, move |stream| {
+        let id = next_id.fetch_add(1, Ordering::SeqCst);
+        handler(stream, id, &clients)
+    })
+}
+
+fn handler(stream: TcpStream, id: usize, clients: &Mutex<Clients>) -> anyhow::Result<()> {
+    let (outbox, inbox) = mpsc::sync_channel(OUTBOX_CAPACITY);
+    let writer = stream.try_clone()?;
+    let writer_thread = thread::spawn(move || write_lines(writer, inbox));
+
+    {
+        let mut clients = clients.lock().unwrap();
+        // Someone may have picked our guest name with /nick already.
+        let nick = (id..)
+            .map(|n| format!("guest{}", n))
+            .find(|nick| !clients.nick_taken(nick))
+            .expect("some guest name is free");
+        clients.clients.insert(
+            id,
+            Client {
+                nick: nick.clone(),
+                room: DEFAULT_ROOM.to_string(),
+                outbox,
+            },
+        );
+        clients.send_to(
+            id,
+            &format!("* Welcome {}, you are in #{}", nick, DEFAULT_ROOM),
+        );
+        clients.broadcast(
+            DEFAULT_ROOM,
+            Some(id),
+            &format!("* {} joined #{}", nick, DEFAULT_ROOM),
+        );
+    }
+
+    let result = read_commands(&stream, id, clients);
+
+    {
+        let mut clients = clients.lock().unwrap();
+        if let Some(client) = clients.clients.remove(&id) {
+            clients.broadcast(
+                &client.room,
+                None,
+                &format!("* {} left #{}", client.nick, client.room),
+            );
+        }
+        // Dropping the client drops its outbox, which ends the writer thread.
+    }
+    let _ = writer_thread.join();
+    result
+}
+
+fn read_commands(stream: &TcpStream, id: usize, clients: &Mutex<Clients>) -> anyhow::Result<()> {
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut clients = clients.lock().unwrap();
+        let (nick, room) = match clients.clients.get(&id) {
+            Some(client) => (client.nick.clone(), client.room.clone()),
+            None => return Ok(()),
+        };
+        let mut words = line.splitn(2, ' ');
+        match (words.next().unwrap_or(""), words.next().map(str::trim)) {
+            ("/nick", Some(new_nick)) if !new_nick.is_empty() && !new_nick.contains(' ') => {
+                if clients.nick_taken(new_nick) {
+                    clients.send_to(id, &format!("* {} is already taken", new_nick));
+                    continue;
+                }
+                if let Some(client) = clients.clients.get_mut(&id) {
+                    client.nick = new_nick.to_string();
+                }
+                clients.broadcast(
+                    &room,
+                    None,
+                    &format!("* {} is now known as {}", nick, new_nick),
+                );
+            }
+            ("/join", Some(new_room)) if !new_room.is_empty() => {
+                let new_room = new_room.trim_start_matches('#').to_string();
+                if new_room == room {
+                    continue;
+                }
+                clients.broadcast(&room, Some(id), &format!("* {} left #{}", nick, room));
+                if let Some(client) = clients.clients.get_mut(&id) {
+                    client.room = new_room.clone();
+                }
+                clients.broadcast(&new_room, None, &format!("* {} joined #{}", nick, new_room));
+            }
+            ("/list", None) => {
+                let rooms: BTreeSet<&str> =
+                    clients.clients.values().map(|c| c.room.as_str()).collect();
+                let members: Vec<&str> = clients
+                    .clients
+                    .values()
+                    .filter(|c| c.room == room)
+                    .map(|c| c.nick.as_str())
+                    .collect();
+                let rooms: Vec<String> = rooms.iter().map(|r| format!("#{}", r)).collect();
+                let listing = format!(
+                    "* rooms: {}; in #{}: {}",
+                    rooms.join(" "),
+                    room,
+                    members.join(" ")
+                );
+                clients.send_to(id, &listing);
+            }
+            ("/quit", None) => {
+                clients.send_to(id, "* Bye");
+                return Ok(());
+            }
+            (command, _) if command.starts_with('/') => {
+                clients.send_to(id, "* Commands: /nick <name>, /join <room>, /list, /quit");
+            }
+            _ => clients.broadcast(&room, Some(id), &format!("<{}> {}", nick, line)),
+        }
+    }
+    debug!("Connection closed");
+    Ok(())
+}
+
+/// Writes queued lines to one client, so that a slow reader only delays itself.
+fn write_lines(mut stream: TcpStream, inbox: Receiver<String>) {
+    for line in inbox {
+        if let Err(e) = writeln!(stream, "{}", line) {
+            error!("{}", e);
+            break;
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Duration;
+
+    /// Starts a chat server on a loopback port. It runs until the test process exits.
+    fn start() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let clients = Arc::new(Mutex::new(Clients::default()));
+        thread::spawn(move || {
+            for (id, stream) in listener.incoming().enumerate() {
+                let clients = Arc::clone(&clients);
+                thread::spawn(move || handler(stream.unwrap(), id + 1, &clients));
+            }
+        });
+        address
+    }
+
+    struct User {
+        reader: BufReader<TcpStream>,
+    }
+
+    impl User {
+        /// Connects and reads the welcome line.
+        fn join(address: SocketAddr) -> (User, String) {
+            let stream = TcpStream::connect(address).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut user = User {
+                reader: BufReader::new(stream),
+            };
+            let welcome = user.line();
+            (user, welcome)
+        }
+
+        fn say(&mut self, line: &str) {
+            writeln!(self.reader.get_mut(), "{}", line).unwrap();
+        }
+
+        fn line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line.trim_end().to_string()
+        }
+    }
+
+    #[test]
+    fn messages_reach_the_others_in_the_room() {
+        let address = start();
+        let (mut first, welcome) = User::join(address);
+        assert_eq!(welcome, "* Welcome guest1, you are in #lobby");
+        let (mut second, _) = User::join(address);
+        assert_eq!(first.line(), "* guest2 joined #lobby");
+
+        second.say("hello");
+        assert_eq!(first.line(), "<guest2> hello");
+        // The sender does not get its own message back: the next line it sees is the reply.
+        first.say("hi");
+        assert_eq!(second.line(), "<guest1> hi");
+
+        second.say("/join #other");
+        assert_eq!(first.line(), "* guest2 left #lobby");
+        assert_eq!(second.line(), "* guest2 joined #other");
+        first.say("anyone?");
+        second.say("/list");
+        assert_eq!(second.line(), "* rooms: #lobby #other; in #other: guest2");
+    }
+
+    #[test]
+    fn nicks_are_unique() {
+        let address = start();
+        let (mut first, _) = User::join(address);
+        first.say("/nick alice");
+        assert_eq!(first.line(), "* guest1 is now known as alice");
+
+        let (mut second, _) = User::join(address);
+        assert_eq!(first.line(), "* guest2 joined #lobby");
+        second.say("/nick alice");
+        assert_eq!(second.line(), "* alice is already taken");
+
+        // A guest name taken with /nick is skipped for the client it would have gone to.
+        second.say("/nick guest3");
+        assert_eq!(second.line(), "* guest2 is now known as guest3");
+        let (_third, welcome) = User::join(address);
+        assert_eq!(welcome, "* Welcome guest4, you are in #lobby");
+    }
+
+    #[test]
+    fn leaving_is_announced() {
+        let address = start();
+        let (mut first, _) = User::join(address);
+        let (mut second, _) = User::join(address);
+        assert_eq!(first.line(), "* guest2 joined #lobby");
+
+        second.say("/quit");
+        assert_eq!(second.line(), "* Bye");
+        assert_eq!(first.line(), "* guest2 left #lobby");
+
+        let (third, _) = User::join(address);
+        assert_eq!(first.line(), "* guest3 joined #lobby");
+        drop(third);
+        assert_eq!(first.line(), "* guest3 left #lobby");
+    }
+}
@@ -0,0 +1,1194 @@
+use crate::net::NetOpts;
+use crate::tcp_server;
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TYPE_A: u16 = 1;
+const TYPE_NS: u16 = 2;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_PTR: u16 = 12;
+const TYPE_MX: u16 = 15;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+const OPCODE_QUERY: u16 = 0;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+const HEADER_LEN: usize = 12;
+/// Largest DNS message over UDP without EDNS (RFC 1035 4.2.1).
+const MAX_UDP_LEN: usize = 512;
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+/// CNAMEs the server follows within its zone before giving up on a chain.
+const MAX_CNAME_CHAIN: usize = 8;
+const DEFAULT_TTL: u32 = 3600;
+/// How long the client waits for each UDP answer, and how often it asks.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_TRIES: u32 = 3;
+/// How long the server keeps a TCP connection open without a query on it.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clap, Debug)]
+pub struct DnsOpts {
+    /// Zone file the DNS server answers from.
+    #[clap(long, parse(from_os_str))]
+    pub zone: Option<PathBuf>,
+    /// Name the DNS client looks up. An IP address is turned into its reverse name for PTR.
+    #[clap(long)]
+    pub query: Option<String>,
+    /// Record type the DNS client asks for.
+    #[clap(long, default_value = "A")]
+    pub qtype: String,
+    /// Send the DNS query over TCP instead of trying UDP first.
+    #[clap(long)]
+    pub force_tcp: bool,
+}
+
+impl Default for DnsOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+/// A DNS message. Names are kept without the trailing dot, in the case they arrived in.
+#[derive(Debug, Clone, Default)]
+struct Message {
+    id: u16,
+    flags: u16,
+    questions: Vec<Question>,
+    answers: Vec<Record>,
+    authority: Vec<Record>,
+    additional: Vec<Record>,
+}
+
+#[derive(Debug, Clone)]
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    name: String,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata: RData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Ptr(String),
+    /// One or more character-strings of up to 255 bytes each.
+    Txt(Vec<Vec<u8>>),
+    /// Types this module does not interpret, as they came off the wire.
+    Other(Vec<u8>),
+}
+
+impl Message {
+    fn rcode(&self) -> u16 {
+        self.flags & 0x000f
+    }
+
+    fn opcode(&self) -> u16 {
+        (self.flags >> 11) & 0x000f
+    }
+
+    fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut writer = Writer::default();
+        writer.u16(self.id);
+        writer.u16(self.flags);
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authority.len(),
+            self.additional.len(),
+        ] {
+            writer.u16(u16::try_from(count).context("Too many records")?);
+        }
+        for question in &self.questions {
+            writer.name(&question.name)?;
+            writer.u16(question.qtype);
+            writer.u16(question.qclass);
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authority)
+            .chain(&self.additional)
+        {
+            writer.record(record)?;
+        }
+        Ok(writer.buf)
+    }
+
+    fn parse(buf: &[u8]) -> anyhow::Result<Message> {
+        let mut reader = Reader { buf, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+        let mut questions = Vec::new();
+        for _ in 0..counts[0] {
+            questions.push(Question {
+                name: reader.name()?,
+                qtype: reader.u16()?,
+                qclass: reader.u16()?,
+            });
+        }
+        let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+        for (section, &count) in sections.iter_mut().zip(&counts[1..]) {
+            for _ in 0..count {
+                section.push(reader.record()?);
+            }
+        }
+        let [answers, authority, additional] = sections;
+        Ok(Message {
+            id,
+            flags,
+            questions,
+            answers,
+            authority,
+            additional,
+        })
+    }
+}
+
+/// Encodes a message, compressing every name against the names written before it.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+    /// Offsets of the names (and their suffixes) already written, by lowercase name.
+    names: HashMap<String, u16>,
+}
+
+impl Writer {
+    fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn name(&mut self, name: &str) -> anyhow::Result<()> {
+        let labels = labels(name)?;
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".").to_ascii_lowercase();
+            if let Some(&offset) = self.names.get(&suffix) {
+                self.u16(0xc000 | offset);
+                return Ok(());
+            }
+            // Pointers have 14 bits, so only names early in the message can be pointed at.
+            if let Ok(offset) = u16::try_from(self.buf.len()) {
+                if offset < 0x4000 {
+                    self.names.insert(suffix, offset);
+                }
+            }
+            self.buf.push(labels[i].len() as u8);
+            self.buf.extend_from_slice(labels[i].as_bytes());
+        }
+        self.buf.push(0);
+        Ok(())
+    }
+
+    fn record(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.name(&record.name)?;
+        self.u16(record.rtype);
+        self.u16(record.class);
+        self.buf.extend_from_slice(&record.ttl.to_be_bytes());
+        let len_at = self.buf.len();
+        self.u16(0);
+        match &record.rdata {
+            RData::A(ip) => self.buf.extend_from_slice(&ip.octets()),
+            RData::Aaaa(ip) => self.buf.extend_from_slice(&ip.octets()),
+            RData::Cname(name) | RData::Ptr(name) => self.name(name)?,
+            RData::Txt(strings) => {
+                for string in strings {
+                    self.buf.push(string.len() as u8);
+                    self.buf.extend_from_slice(string);
+                }
+            }
+            RData::Other(data) => self.buf.extend_from_slice(data),
+        }
+        let len = u16::try_from(self.buf.len() - len_at - 2).context("Record data is too long")?;
+        self.buf[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("Message ends early"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a possibly compressed name. Pointers may only point backwards, so they cannot loop.
+    fn name(&mut self) -> anyhow::Result<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut len = 0;
+        let mut pos = self.pos;
+        // Where reading continues once the name is done: after the first pointer, if any.
+        let mut resume = None;
+        loop {
+            let start = pos;
+            let tag = *self
+                .buf
+                .get(pos)
+                .ok_or_else(|| anyhow!("Name runs off the message"))?;
+            match tag & 0xc0 {
+                0x00 if tag == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self
+                        .buf
+                        .get(pos + 1..pos + 1 + tag as usize)
+                        .ok_or_else(|| anyhow!("Label runs off the message"))?;
+                    len += label.len() + 1;
+                    if len > MAX_NAME_LEN {
+                        return Err(anyhow!("Name is longer than {} bytes", MAX_NAME_LEN));
+                    }
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + tag as usize;
+                }
+                0xc0 => {
+                    let low = *self
+                        .buf
+                        .get(pos + 1)
+                        .ok_or_else(|| anyhow!("Pointer runs off the message"))?;
+                    let target = (usize::from(tag & 0x3f) << 8) | usize::from(low);
+                    if target >= start {
+                        return Err(anyhow!("Compression pointer does not point backwards"));
+                    }
+                    resume.get_or_insert(pos + 2);
+                    pos = target;
+                }
+                _ => return Err(anyhow!("Unsupported label type {:#04x}", tag)),
+            }
+        }
+        self.pos = resume.unwrap_or(pos);
+        Ok(labels.join("."))
+    }
+
+    fn record(&mut self) -> anyhow::Result<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = usize::from(self.u16()?);
+        let end = self.pos + len;
+        if end > self.buf.len() {
+            return Err(anyhow!("Record data runs off the message"));
+        }
+        let rdata = match rtype {
+            TYPE_A if len == 4 => {
+                let b = self.bytes(4)?;
+                RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            TYPE_AAAA if len == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(self.bytes(16)?);
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            TYPE_CNAME => RData::Cname(self.name()?),
+            TYPE_PTR => RData::Ptr(self.name()?),
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                while self.pos < end {
+                    let len = usize::from(self.u8()?);
+                    strings.push(self.bytes(len)?.to_vec());
+                }
+                RData::Txt(strings)
+            }
+            _ => RData::Other(self.bytes(len)?.to_vec()),
+        };
+        if self.pos != end {
+            return Err(anyhow!("Record data has the wrong length"));
+        }
+        Ok(Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+/// Splits a name into labels, checking their lengths. The root is the empty name.
+fn labels(name: &str) -> anyhow::Result<Vec<&str>> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return Ok(Vec::new());
+    }
+    if name.len() + 2 > MAX_NAME_LEN {
+        return Err(anyhow!("{} is longer than {} bytes", name, MAX_NAME_LEN));
+    }
+    let labels: Vec<&str> = name.split('.').collect();
+    if labels
+        .iter()
+        .any(|label| label.is_empty() || label.len() > MAX_LABEL_LEN)
+    {
+        return Err(anyhow!("{} has an empty or too long label", name));
+    }
+    Ok(labels)
+}
+
+fn type_name(rtype: u16) -> String {
+    match rtype {
+        TYPE_A => "A".to_string(),
+        TYPE_NS => "NS".to_string(),
+        TYPE_CNAME => "CNAME".to_string(),
+        TYPE_SOA => "SOA".to_string(),
+        TYPE_PTR => "PTR".to_string(),
+        TYPE_MX => "MX".to_string(),
+        TYPE_TXT => "TXT".to_string(),
+        TYPE_AAAA => "AAAA".to_string(),
+        TYPE_ANY => "ANY".to_string(),
+        other => format!("TYPE{}", other),
+    }
+}
+
+fn parse_type(name: &str) -> Option<u16> {
+    let upper = name.to_ascii_uppercase();
+    match upper.as_str() {
+        "A" => Some(TYPE_A),
+        "NS" => Some(TYPE_NS),
+        "CNAME" => Some(TYPE_CNAME),
+        "SOA" => Some(TYPE_SOA),
+        "PTR" => Some(TYPE_PTR),
+        "MX" => Some(TYPE_MX),
+        "TXT" => Some(TYPE_TXT),
+        "AAAA" => Some(TYPE_AAAA),
+        "ANY" => Some(TYPE_ANY),
+        _ => upper.strip_prefix("TYPE")?.parse().ok(),
+    }
+}
+
+fn rcode_name(rcode: u16) -> String {
+    match rcode {
+        RCODE_NOERROR => "NOERROR".to_string(),
+        RCODE_FORMERR => "FORMERR".to_string(),
+        RCODE_SERVFAIL => "SERVFAIL".to_string(),
+        RCODE_NXDOMAIN => "NXDOMAIN".to_string(),
+        RCODE_NOTIMP => "NOTIMP".to_string(),
+        RCODE_REFUSED => "REFUSED".to_string(),
+        other => format!("RCODE{}", other),
+    }
+}
+
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(ip) => write!(f, "{}", ip),
+            RData::Aaaa(ip) => write!(f, "{}", ip),
+            RData::Cname(name) | RData::Ptr(name) => write!(f, "{}.", name),
+            RData::Txt(strings) => {
+                let quoted: Vec<String> = strings
+                    .iter()
+                    .map(|s| format!("{:?}", String::from_utf8_lossy(s)))
+                    .collect();
+                f.write_str(&quoted.join(" "))
+            }
+            // The RFC 3597 notation for data of unknown types.
+            RData::Other(data) => {
+                let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, "\\# {} {}", data.len(), hex)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.\t{}\t{}\t{}\t{}",
+            self.name,
+            self.ttl,
+            if self.class == CLASS_IN {
+                "IN".to_string()
+            } else {
+                format!("CLASS{}", self.class)
+            },
+            type_name(self.rtype),
+            self.rdata
+        )
+    }
+}
+
+/// The records the server answers from, by lowercase owner name.
+#[derive(Debug, Default)]
+struct Zone {
+    records: HashMap<String, Vec<Record>>,
+}
+
+impl Zone {
+    /// Reads a zone file in a subset of the RFC 1035 master file format: `$ORIGIN`, `$TTL`,
+    /// `@`, relative names, comments and A, AAAA, CNAME, TXT and PTR records on one line each.
+    fn parse(text: &str) -> anyhow::Result<Zone> {
+        let mut zone = Zone::default();
+        let mut origin = String::new();
+        let mut default_ttl = DEFAULT_TTL;
+        let mut last_owner: Option<String> = None;
+        for (number, line) in text.lines().enumerate() {
+            let context = || format!("line {}", number + 1);
+            let line = strip_comment(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = tokens(line).with_context(context)?;
+            match fields[0].as_str() {
+                "$ORIGIN" => {
+                    let name = fields
+                        .get(1)
+                        .with_context(|| format!("{}: $ORIGIN needs a name", context()))?;
+                    origin = absolute(name, &origin);
+                    continue;
+                }
+                "$TTL" => {
+                    let ttl = fields
+                        .get(1)
+                        .with_context(|| format!("{}: $TTL needs a value", context()))?;
+                    default_ttl = ttl.parse().with_context(context)?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            // A line starting with blanks belongs to the owner of the line before.
+            let owner = if line.starts_with(char::is_whitespace) {
+                last_owner
+                    .clone()
+                    .with_context(|| format!("{}: no owner name", context()))?
+            } else {
+                absolute(&fields.remove(0), &origin)
+            };
+            last_owner = Some(owner.clone());
+
+            let mut ttl = default_ttl;
+            let mut rest = fields.into_iter().peekable();
+            while let Some(field) = rest.peek() {
+                if let Ok(value) = field.parse::<u32>() {
+                    ttl = value;
+                } else if field.eq_ignore_ascii_case("IN") {
+                } else {
+                    break;
+                }
+                rest.next();
+            }
+            let rtype = rest
+                .next()
+                .with_context(|| format!("{}: no record type", context()))?;
+            let args: Vec<String> = rest.collect();
+            let first = || {
+                args.first()
+                    .cloned()
+                    .with_context(|| format!("{}: {} needs data", context(), rtype))
+            };
+            let (rtype, rdata) = match rtype.to_ascii_uppercase().as_str() {
+                "A" => (TYPE_A, RData::A(first()?.parse().with_context(context)?)),
+                "AAAA" => (
+                    TYPE_AAAA,
+                    RData::Aaaa(first()?.parse().with_context(context)?),
+                ),
+                "CNAME" => (TYPE_CNAME, RData::Cname(absolute(&first()?, &origin))),
+                "PTR" => (TYPE_PTR, RData::Ptr(absolute(&first()?, &origin))),
+                "TXT" => {
+                    if args.is_empty() || args.iter().any(|s| s.len() > 255) {
+                        return Err(anyhow!(
+                            "{}: TXT needs strings of up to 255 bytes",
+                            context()
+                        ));
+                    }
+                    (
+                        TYPE_TXT,
+                        RData::Txt(args.iter().map(|s| s.as_bytes().to_vec()).collect()),
+                    )
+                }
+                other => return Err(anyhow!("{}: unsupported record type {}", context(), other)),
+            };
+            labels(&owner).with_context(context)?;
+            if let RData::Cname(name) | RData::Ptr(name) = &rdata {
+                labels(name).with_context(context)?;
+            }
+            zone.records
+                .entry(owner.to_ascii_lowercase())
+                .or_default()
+                .push(Record {
+                    name: owner,
+                    rtype,
+                    class: CLASS_IN,
+                    ttl,
+                    rdata,
+                });
+        }
+        Ok(zone)
+    }
+
+    /// Builds the answer to `query` as an authoritative server for everything in the zone.
+    fn answer(&self, query: &Message) -> Message {
+        let mut response = Message {
+            id: query.id,
+            flags: FLAG_QR | FLAG_AA | (query.flags & FLAG_RD) | (query.flags & 0x7800),
+            questions: query.questions.clone(),
+            ..Message::default()
+        };
+        if query.opcode() != OPCODE_QUERY {
+            response.flags = (response.flags & !FLAG_AA) | RCODE_NOTIMP;
+            return response;
+        }
+        let question = match query.questions.as_slice() {
+            [question] => question,
+            _ => {
+                response.flags = (response.flags & !FLAG_AA) | RCODE_FORMERR;
+                return response;
+            }
+        };
+        if question.qclass != CLASS_IN && question.qclass != TYPE_ANY {
+            response.flags = (response.flags & !FLAG_AA) | RCODE_REFUSED;
+            return response;
+        }
+
+        let mut name = question.name.to_ascii_lowercase();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let records = match self.records.get(&name) {
+                Some(records) => records,
+                None if response.answers.is_empty() => {
+                    response.flags |= RCODE_NXDOMAIN;
+                    return response;
+                }
+                // A CNAME pointing out of the zone: the client has to follow it elsewhere.
+                None => return response,
+            };
+            let matching: Vec<Record> = records
+                .iter()
+                .filter(|r| question.qtype == TYPE_ANY || r.rtype == question.qtype)
+                .cloned()
+                .collect();
+            if !matching.is_empty() || question.qtype == TYPE_CNAME {
+                response.answers.extend(matching);
+                return response;
+            }
+            match records.iter().find(|r| r.rtype == TYPE_CNAME) {
+                Some(cname) => {
+                    response.answers.push(cname.clone());
+                    if let RData::Cname(target) = &cname.rdata {
+                        name = target.to_ascii_lowercase();
+                    }
+                }
+                // The name exists without records of this type (NODATA).
+                None => return response,
+            }
+        }
+        debug!("CNAME chain for {} is too long", question.name);
+        response
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits a zone file line into fields. Double-quoted strings become one field.
+fn tokens(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut field = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => field.extend(chars.next()),
+                    Some(c) => field.push(c),
+                    None => return Err(anyhow!("Unterminated string")),
+                }
+            }
+            fields.push(field);
+        } else {
+            let mut field = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                field.push(c);
+                chars.next();
+            }
+            fields.push(field);
+        }
+    }
+    Ok(fields)
+}
+
+/// Makes a zone file name absolute. Names ending in a dot already are, and `@` is the origin.
+fn absolute(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if let Some(name) = name.strip_suffix('.') {
+        name.to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+pub fn server(address: &str, opts: &DnsOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let path = opts
+        .zone
+        .as_ref()
+        .context("The DNS server requires --zone")?;
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let zone = Arc::new(Zone::parse(&text).with_context(|| format!("In {}", path.display()))?);
+    debug!(
+        "Loaded {} names from {}",
+        zone.records.len(),
+        path.display()
+    );
+
+    // The same port answers over TCP, for clients that got a truncated answer.
+    let socket = net.bind_udp(address)?;
+    let tcp_address = socket.local_addr()?.to_string();
+    let tcp_zone = Arc::clone(&zone);
+    let tcp_net = net.clone();
+    thread::spawn(move || {
+        let result = tcp_server::serve(&tcp_address, &tcp_net, move |stream| {
+            serve_tcp(stream, &tcp_zone)
+        });
+        if let Err(e) = result {
+            error!("DNS over TCP: {:#}", e);
+        }
+    });
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let (size, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Receive: {}", e);
+                continue;
+            }
+        };
+        let response = match respond(&zone, &buf[..size], MAX_UDP_LEN) {
+            Some(response) => response,
+            None => continue,
+        };
+        debug!(
+            "{}: {} bytes answered with {} bytes",
+            src,
+            size,
+            response.len()
+        );
+        if let Err(e) = socket.send_to(&response, src) {
+            warn!("{}: {}", src, e);
+        }
+    }
+}
+
+fn serve_tcp(mut stream: TcpStream, zone: &Zone) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    loop {
+        let mut len = [0u8; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                debug!("Closing a TCP connection idle for {:?}", TCP_IDLE_TIMEOUT);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let mut query = vec![0u8; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut query)?;
+        if let Some(response) = respond(zone, &query, usize::from(u16::MAX)) {
+            stream.write_all(&(response.len() as u16).to_be_bytes())?;
+            stream.write_all(&response)?;
+        }
+    }
+}
+
+/// Answers one wire-format query, truncating the answer if it is longer than `max_len`.
+/// Returns `None` for anything that is not worth an answer.
+fn respond(zone: &Zone, query: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    let query = match Message::parse(query) {
+        Ok(query) if query.flags & FLAG_QR == 0 => query,
+        Ok(_) => return None,
+        Err(e) => {
+            debug!("Malformed query: {}", e);
+            // Echo the ID back if there is one, so the client can match the error.
+            if query.len() < HEADER_LEN {
+                return None;
+            }
+            let response = Message {
+                id: u16::from_be_bytes([query[0], query[1]]),
+                flags: FLAG_QR | RCODE_FORMERR,
+                ..Message::default()
+            };
+            return response.to_bytes().ok();
+        }
+    };
+    let mut response = zone.answer(&query);
+    for question in &response.questions {
+        debug!(
+            "Query {} {} -> {} with {} answers",
+            question.name,
+            type_name(question.qtype),
+            rcode_name(response.rcode()),
+            response.answers.len()
+        );
+    }
+    let mut bytes = response.to_bytes().ok()?;
+    if bytes.len() > max_len {
+        response.answers.clear();
+        response.authority.clear();
+        response.additional.clear();
+        response.flags |= FLAG_TC;
+        bytes = response.to_bytes().ok()?;
+    }
+    Some(bytes)
+}
+
+pub fn client(address: &str, opts: &DnsOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let query_name = opts
+        .query
+        .as_ref()
+        .context("The DNS client requires --query")?;
+    let qtype =
+        parse_type(&opts.qtype).with_context(|| format!("Unknown record type {}", opts.qtype))?;
+    // Like `dig -x`: an address asked for PTR becomes its reverse name.
+    let name = match (qtype, query_name.parse::<IpAddr>()) {
+        (TYPE_PTR, Ok(ip)) => reverse_name(ip),
+        _ => query_name.trim_end_matches('.').to_string(),
+    };
+    labels(&name)?;
+    let query = Message {
+        id: rand::random(),
+        flags: FLAG_RD,
+        questions: vec![Question {
+            name,
+            qtype,
+            qclass: CLASS_IN,
+        }],
+        ..Message::default()
+    };
+    let bytes = query.to_bytes()?;
+
+    let start = Instant::now();
+    let (response, transport) = if opts.force_tcp {
+        (query_tcp(address, &bytes, net)?, "tcp")
+    } else {
+        let response = query_udp(address, &bytes, query.id, net)?;
+        if response.flags & FLAG_TC != 0 {
+            debug!("Answer truncated, retrying over TCP");
+            (query_tcp(address, &bytes, net)?, "tcp")
+        } else {
+            (response, "udp")
+        }
+    };
+    let elapsed = start.elapsed();
+    if response.id != query.id {
+        return Err(anyhow!(
+            "Answer has ID {}, expected {}",
+            response.id,
+            query.id
+        ));
+    }
+    print_response(&response);
+    println!(";; Query time: {} msec", elapsed.as_millis());
+    println!(";; SERVER: {}({})", address, transport);
+    Ok(())
+}
+
+fn query_udp(address: &str, query: &[u8], id: u16, net: &NetOpts) -> anyhow::Result<Message> {
+    let socket = net.connect_udp(address)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    let mut buf = [0u8; 4096];
+    for _ in 0..QUERY_TRIES {
+        socket.send(query)?;
+        loop {
+            let size = match socket.recv(&mut buf) {
+                Ok(size) => size,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match Message::parse(&buf[..size]) {
+                Ok(response) if response.id == id && response.flags & FLAG_QR != 0 => {
+                    return Ok(response)
+                }
+                Ok(_) => debug!("Ignoring an answer to another query"),
+                Err(e) => debug!("Ignoring a malformed answer: {}", e),
+            }
+        }
+    }
+    Err(anyhow!(
+        "No answer from {} after {} tries",
+        address,
+        QUERY_TRIES
+    ))
+}
+
+fn query_tcp(address: &str, query: &[u8], net: &NetOpts) -> anyhow::Result<Message> {
+    let mut stream = net.connect_tcp(address)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT * QUERY_TRIES))?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0u8; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut response)?;
+    Message::parse(&response)
+}
+
+/// The name PTR records for `ip` live under.
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(ip) => {
+            let nibbles: Vec<String> = ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|b| [b & 0x0f, b >> 4])
+                .map(|n| format!("{:x}", n))
+                .collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+/// Prints the response the way dig does.
+fn print_response(response: &Message) {
+    println!(
+        ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+        if response.opcode() == OPCODE_QUERY {
+            "QUERY".to_string()
+        } else {
+            response.opcode().to_string()
+        },
+        rcode_name(response.rcode()),
+        response.id
+    );
+    let flags: Vec<&str> = [
+        (FLAG_QR, "qr"),
+        (FLAG_AA, "aa"),
+        (FLAG_TC, "tc"),
+        (FLAG_RD, "rd"),
+        (FLAG_RA, "ra"),
+    ]
+    .iter()
+    .filter(|(bit, _)| response.flags & bit != 0)
+    .map(|(_, name)| *name)
+    .collect();
+    println!(
+        ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+        flags.join(" "),
+        response.questions.len(),
+        response.answers.len(),
+        response.authority.len(),
+        response.additional.len()
+    );
+    println!();
+    println!(";; QUESTION SECTION:");
+    for question in &response.questions {
+        println!(";{}.\t\tIN\t{}", question.name, type_name(question.qtype));
+    }
+    for (title, records) in [
+        ("ANSWER", &response.answers),
+        ("AUTHORITY", &response.authority),
+        ("ADDITIONAL", &response.additional),
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+        println!();
+        println!(";; {} SECTION:", title);
+        for record in records {
+            println!("{}", record);
+        }
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 300
+@           IN  A     192.0.2.1
+www         60  CNAME @          ; relative to the origin
+alias           CNAME www
+ftp             A     192.0.2.2
+                AAAA  2001:db8::2
+text            TXT   "semi;colon" "two"
+out             CNAME elsewhere.org.
+"#;
+
+    fn query(name: &str, qtype: u16) -> Message {
+        Message {
+            id: 0x4242,
+            flags: FLAG_RD,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            ..Message::default()
+        }
+    }
+
+    fn record(name: &str, rdata: RData) -> Record {
+        let rtype = match rdata {
+            RData::A(_) => TYPE_A,
+            RData::Cname(_) => TYPE_CNAME,
+            _ => TYPE_TXT,
+        };
+        Record {
+            name: name.to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl: 60,
+            rdata,
+        }
+    }
+
+    fn zone() -> Zone {
+        Zone::parse(ZONE).unwrap()
+    }
+
+    #[test]
+    fn names_are_compressed_and_read_back() {
+        let mut message = query("www.Example.com", TYPE_A);
+        message.answers = vec![
+            record("www.example.com", RData::Cname("example.COM".to_string())),
+            record("example.com", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+        ];
+        let bytes = message.to_bytes().unwrap();
+        // The question spells out the name once. Everything after it is pointers to it:
+        // 2 for the CNAME owner and target, 2 for the A owner.
+        let question_len = 1 + 3 + 1 + 7 + 1 + 3 + 1 + 4;
+        let answers_len = (2 + 10 + 2) + (2 + 10 + 4);
+        assert_eq!(bytes.len(), HEADER_LEN + question_len + answers_len);
+        assert_eq!(&bytes[HEADER_LEN + question_len..][..2], &[0xc0, 12]);
+
+        let parsed = Message::parse(&bytes).unwrap();
+        assert_eq!(parsed.id, 0x4242);
+        assert_eq!(parsed.questions[0].name, "www.Example.com");
+        assert_eq!(parsed.answers[0].name, "www.Example.com");
+        assert_eq!(
+            parsed.answers[0].rdata,
+            RData::Cname("Example.com".to_string())
+        );
+        assert_eq!(
+            parsed.answers[1].rdata,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn pointers_must_point_backwards() {
+        let mut header = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        // A pointer to itself would loop forever.
+        let mut looping = header.clone();
+        looping.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert!(Message::parse(&looping).is_err());
+        // So would one pointing ahead to a pointer back.
+        header.extend_from_slice(&[0xc0, 14, 0xc0, 12, 0, 1, 0, 1]);
+        assert!(Message::parse(&header).is_err());
+    }
+
+    #[test]
+    fn overlong_names_are_rejected() {
+        let label = "a".repeat(MAX_LABEL_LEN);
+        let long = [label.as_str(); 4].join(".");
+        assert!(query(&long, TYPE_A).to_bytes().is_err());
+        assert!(query(&"a".repeat(64), TYPE_A).to_bytes().is_err());
+
+        let mut bytes = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        for _ in 0..5 {
+            bytes.push(MAX_LABEL_LEN as u8);
+            bytes.extend_from_slice(label.as_bytes());
+        }
+        bytes.extend_from_slice(&[0, 0, 1, 0, 1]);
+        assert!(Message::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        let bytes = query("example.com", TYPE_A).to_bytes().unwrap();
+        for len in [0, HEADER_LEN - 1, HEADER_LEN + 5, bytes.len() - 1] {
+            assert!(Message::parse(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn zone_files_use_origin_ttl_and_owners() {
+        let zone = zone();
+        let apex = &zone.records["example.com"][0];
+        assert_eq!(apex.ttl, 300);
+        assert_eq!(apex.rdata, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        let www = &zone.records["www.example.com"][0];
+        assert_eq!(www.ttl, 60);
+        assert_eq!(www.rdata, RData::Cname("example.com".to_string()));
+        assert_eq!(zone.records["ftp.example.com"].len(), 2);
+        assert_eq!(
+            zone.records["text.example.com"][0].rdata,
+            RData::Txt(vec![b"semi;colon".to_vec(), b"two".to_vec()])
+        );
+        assert_eq!(
+            zone.records["out.example.com"][0].rdata,
+            RData::Cname("elsewhere.org".to_string())
+        );
+
+        assert!(Zone::parse("  A 192.0.2.1").is_err());
+        assert!(Zone::parse("host MX 10 mail").is_err());
+        assert!(Zone::parse("host TXT \"open").is_err());
+    }
+
+    #[test]
+    fn answers_follow_cname_chains() {
+        let response = zone().answer(&query("ALIAS.example.com", TYPE_A));
+        assert_eq!(response.rcode(), RCODE_NOERROR);
+        assert_ne!(response.flags & FLAG_AA, 0);
+        let types: Vec<u16> = response.answers.iter().map(|r| r.rtype).collect();
+        assert_eq!(types, [TYPE_CNAME, TYPE_CNAME, TYPE_A]);
+
+        // A CNAME query gets the alias itself.
+        let response = zone().answer(&query("alias.example.com", TYPE_CNAME));
+        assert_eq!(response.answers.len(), 1);
+
+        // The client must chase a target outside the zone itself.
+        let response = zone().answer(&query("out.example.com", TYPE_A));
+        assert_eq!(response.rcode(), RCODE_NOERROR);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn missing_names_and_types_are_told_apart() {
+        let response = zone().answer(&query("nope.example.com", TYPE_A));
+        assert_eq!(response.rcode(), RCODE_NXDOMAIN);
+        let response = zone().answer(&query("text.example.com", TYPE_A));
+        assert_eq!(response.rcode(), RCODE_NOERROR);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn unsupported_queries_get_error_codes() {
+        let zone = zone();
+        let mut two = query("example.com", TYPE_A);
+        two.questions.push(two.questions[0].clone());
+        assert_eq!(zone.answer(&two).rcode(), RCODE_FORMERR);
+
+        let mut status = query("example.com", TYPE_A);
+        status.flags |= 2 << 11;
+        assert_eq!(zone.answer(&status).rcode(), RCODE_NOTIMP);
+
+        let mut chaos = query("example.com", TYPE_A);
+        chaos.questions[0].qclass = 3;
+        assert_eq!(zone.answer(&chaos).rcode(), RCODE_REFUSED);
+
+        // Too short to even carry an ID to answer to.
+        assert!(respond(&zone, &[0x12, 0x34, 0, 0, 0, 9], 512).is_none());
+        let garbage = [0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 5];
+        let response = Message::parse(&respond(&zone, &garbage, 512).unwrap()).unwrap();
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.rcode(), RCODE_FORMERR);
+    }
+
+    #[test]
+    fn long_answers_are_truncated() {
+        let mut text = String::from("$ORIGIN example.com.\n");
+        for i in 0..40 {
+            text.push_str(&format!("big TXT \"{}\"\n", "x".repeat(20 + i)));
+        }
+        let zone = Zone::parse(&text).unwrap();
+        let query = query("big.example.com", TYPE_TXT).to_bytes().unwrap();
+
+        let udp = Message::parse(&respond(&zone, &query, MAX_UDP_LEN).unwrap()).unwrap();
+        assert_ne!(udp.flags & FLAG_TC, 0);
+        assert!(udp.answers.is_empty());
+        let tcp = Message::parse(&respond(&zone, &query, usize::from(u16::MAX)).unwrap()).unwrap();
+        assert_eq!(tcp.flags & FLAG_TC, 0);
+        assert_eq!(tcp.answers.len(), 40);
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_name("192.0.2.10".parse().unwrap()),
+            "10.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name("2001:db8::567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+}
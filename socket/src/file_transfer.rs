@@ -0,0 +1,338 @@
+use crate::net::NetOpts;
+use crate::tcp_server;
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"SFT1";
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const STATUS_OK: u8 = 0;
+const STATUS_CHECKSUM_MISMATCH: u8 = 1;
+
+#[derive(Clap, Debug)]
+pub struct FileTransferOpts {
+    /// File to send with the send-file role.
+    #[clap(long, parse(from_os_str))]
+    pub file: Option<PathBuf>,
+    /// Directory the recv-file role stores received files in.
+    #[clap(long, parse(from_os_str), default_value = ".")]
+    pub dir: PathBuf,
+}
+
+impl Default for FileTransferOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+/// Sent before the data: MAGIC, name length (u16), name, size (u64) and SHA-256 of the whole file.
+struct Header {
+    name: String,
+    size: u64,
+    sha256: [u8; 32],
+}
+
+impl Header {
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let name_len = u16::try_from(self.name.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file name is too long"))?;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&name_len.to_be_bytes())?;
+        writer.write_all(self.name.as_bytes())?;
+        writer.write_all(&self.size.to_be_bytes())?;
+        writer.write_all(&self.sha256)?;
+        writer.flush()
+    }
+
+    fn read<R: Read>(reader: &mut R) -> anyhow::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow!("Not a file transfer header"));
+        }
+        let mut name_len = [0u8; 2];
+        reader.read_exact(&mut name_len)?;
+        let mut name = vec![0u8; u16::from_be_bytes(name_len) as usize];
+        reader.read_exact(&mut name)?;
+        let mut size = [0u8; 8];
+        reader.read_exact(&mut size)?;
+        let mut sha256 = [0u8; 32];
+        reader.read_exact(&mut sha256)?;
+        Ok(Header {
+            name: String::from_utf8(name)?,
+            size: u64::from_be_bytes(size),
+            sha256,
+        })
+    }
+}
+
+pub fn send(address: &str, opts: &FileTransferOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let path = opts
+        .file
+        .as_ref()
+        .context("The send-file role requires --file")?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("{} has no usable file name", path.display()))?;
+    let mut file = File::open(path)?;
+    let header = Header {
+        name: name.to_string(),
+        size: file.metadata()?.len(),
+        sha256: sha256_of(&mut file)?,
+    };
+
+    let mut stream = net.connect_tcp(address)?;
+    header.write(&mut stream)?;
+
+    let mut offset = [0u8; 8];
+    stream.read_exact(&mut offset)?;
+    let offset = u64::from_be_bytes(offset);
+    if offset > header.size {
+        return Err(anyhow!(
+            "Receiver asked to resume beyond the end of the file"
+        ));
+    }
+    if offset > 0 {
+        debug!("Resuming {} from byte {}", header.name, offset);
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    let sent = copy_chunks(&mut file, &mut stream, header.size - offset)?;
+    debug!("Sent {} bytes of {}", sent, header.name);
+
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status)?;
+    match status[0] {
+        STATUS_OK => {
+            println!(
+                "{} ({} bytes) sent, sha256 {}",
+                header.name,
+                header.size,
+                hex(&header.sha256)
+            );
+            Ok(())
+        }
+        STATUS_CHECKSUM_MISMATCH => Err(anyhow!("Receiver reported a checksum mismatch")),
+        status => Err(anyhow!("Unknown status {} from receiver", status)),
+    }
+}
+
+pub fn server(address: &str, opts: &FileTransferOpts, net: &NetOpts) -> anyhow::Result<()> {
+    fs::create_dir_all(&opts.dir)?;
+    let dir = opts.dir.clone();
+    tcp_server::serve(address, net, move |stream| receive(stream, &dir))
+}
+
+fn receive(mut stream: TcpStream, dir: &Path) -> anyhow::Result<()> {
+    let header = Header::read(&mut stream)?;
+    // Never let the sender choose where the file goes.
+    let name = match Path::new(&header.name).file_name() {
+        Some(name) if name == header.name.as_str() => header.name.clone(),
+        _ => return Err(anyhow!("Refusing file name {:?}", header.name)),
+    };
+
+    // The partial file is keyed by the content hash, so a resumed transfer can only continue the same content.
+    let part_path = dir.join(format!(".{}.{}.part", name, &hex(&header.sha256)[..16]));
+    let mut part = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part_path)?;
+    let mut offset = part.metadata()?.len();
+    if offset > header.size {
+        part.set_len(0)?;
+        offset = 0;
+    }
+    debug!(
+        "Receiving {} ({} bytes) from byte {}",
+        name, header.size, offset
+    );
+    stream.write_all(&offset.to_be_bytes())?;
+
+    part.seek(SeekFrom::Start(offset))?;
+    let mut writer = BufWriter::new(&part);
+    copy_chunks(&mut stream, &mut writer, header.size - offset)?;
+    writer.flush()?;
+    drop(writer);
+
+    part.seek(SeekFrom::Start(0))?;
+    let actual = sha256_of(&mut part)?;
+    if actual != header.sha256 {
+        fs::remove_file(&part_path)?;
+        stream.write_all(&[STATUS_CHECKSUM_MISMATCH])?;
+        return Err(anyhow!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            name,
+            hex(&header.sha256),
+            hex(&actual)
+        ));
+    }
+
+    fs::rename(&part_path, dir.join(&name))?;
+    stream.write_all(&[STATUS_OK])?;
+    println!(
+        "{} ({} bytes) received, sha256 {}",
+        name,
+        header.size,
+        hex(&actual)
+    );
+    Ok(())
+}
+
+/// Copies exactly `len` bytes in `CHUNK_SIZE` pieces, failing if the reader ends early.
+fn copy_chunks<R: Read, W: Write>(reader: &mut R, writer: &mut W, len: u64) -> io::Result<u64> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = remaining.min(CHUNK_SIZE as u64) as usize;
+        let nbytes = reader.read(&mut buf[..want])?;
+        if nbytes == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("connection closed with {} bytes left", remaining),
+            ));
+        }
+        writer.write_all(&buf[..nbytes])?;
+        remaining -= nbytes as u64;
+    }
+    writer.flush()?;
+    Ok(len)
+}
+
+fn sha256_of(file: &mut File) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, file);
+    io::copy(&mut reader, &mut hasher)?;
+    reader.into_inner().seek(SeekFrom::Start(0))?;
+    Ok(hasher.finalize().into())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread::{self, JoinHandle};
+
+    /// Receives one transfer into `dir` on a loopback port.
+    fn receive_once(dir: &Path) -> (SocketAddr, JoinHandle<anyhow::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let dir = dir.to_path_buf();
+        let receiver = thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            receive(stream, &dir)
+        });
+        (address, receiver)
+    }
+
+    fn header(name: &str, content: &[u8]) -> Header {
+        Header {
+            name: name.to_string(),
+            size: content.len() as u64,
+            sha256: Sha256::digest(content).into(),
+        }
+    }
+
+    /// Sends `header`, then `content` from wherever the receiver asks, and returns that
+    /// offset and the final status.
+    fn transfer(address: SocketAddr, header: &Header, content: &[u8]) -> (u64, u8) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        header.write(&mut stream).unwrap();
+        let mut offset = [0u8; 8];
+        stream.read_exact(&mut offset).unwrap();
+        let offset = u64::from_be_bytes(offset);
+        stream.write_all(&content[offset as usize..]).unwrap();
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status).unwrap();
+        (offset, status[0])
+    }
+
+    fn content() -> Vec<u8> {
+        (0..200_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn a_file_is_sent_whole() {
+        let from = tempfile::tempdir().unwrap();
+        let to = tempfile::tempdir().unwrap();
+        let path = from.path().join("data.bin");
+        fs::write(&path, content()).unwrap();
+        let (address, receiver) = receive_once(to.path());
+
+        let opts = FileTransferOpts {
+            file: Some(path),
+            ..FileTransferOpts::default()
+        };
+        send(&address.to_string(), &opts, &NetOpts::default()).unwrap();
+        receiver.join().unwrap().unwrap();
+        assert_eq!(fs::read(to.path().join("data.bin")).unwrap(), content());
+    }
+
+    #[test]
+    fn a_partial_file_is_resumed() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = content();
+        let header = header("data.bin", &content);
+        let part = dir
+            .path()
+            .join(format!(".data.bin.{}.part", &hex(&header.sha256)[..16]));
+        fs::write(&part, &content[..70_000]).unwrap();
+        let (address, receiver) = receive_once(dir.path());
+
+        assert_eq!(transfer(address, &header, &content), (70_000, STATUS_OK));
+        receiver.join().unwrap().unwrap();
+        assert_eq!(fs::read(dir.path().join("data.bin")).unwrap(), content);
+        assert!(!part.exists());
+    }
+
+    #[test]
+    fn a_mismatched_hash_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = content();
+        let mut header = header("data.bin", &content);
+        header.sha256[0] ^= 1;
+        let (address, receiver) = receive_once(dir.path());
+
+        assert_eq!(
+            transfer(address, &header, &content),
+            (0, STATUS_CHECKSUM_MISMATCH)
+        );
+        let e = receiver.join().unwrap().unwrap_err();
+        assert!(e.to_string().contains("Checksum mismatch"), "{}", e);
+        // Neither the file nor a partial file to resume from is left behind.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn names_with_a_path_are_refused() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("inbox");
+        fs::create_dir(&dir).unwrap();
+        for name in ["../escaped", "sub/file", "/tmp/absolute", "..", ""] {
+            let (address, receiver) = receive_once(&dir);
+            let mut stream = TcpStream::connect(address).unwrap();
+            header(name, b"data").write(&mut stream).unwrap();
+            let e = receiver.join().unwrap().unwrap_err();
+            assert!(e.to_string().contains("Refusing"), "{:?}: {}", name, e);
+            // The receiver hangs up without asking for any data.
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty());
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        assert!(!root.path().join("escaped").exists());
+    }
+}
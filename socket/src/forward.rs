@@ -0,0 +1,24 @@
+use crate::net::NetOpts;
+use crate::relay;
+use crate::tcp_server;
+use anyhow::Context;
+use log::{debug, info};
+
+pub fn server(address: &str, upstream: &str, net: &NetOpts) -> anyhow::Result<()> {
+    let upstream = upstream.to_string();
+    let upstream_net = net.clone();
+    tcp_server::serve(address, net, move |client| {
+        let client_addr = client.peer_addr()?;
+        let upstream_stream = upstream_net
+            .connect_tcp(&upstream)
+            .with_context(|| format!("Failed to connect to upstream {}", upstream))?;
+        debug!("Forwarding {} to {}", client_addr, upstream);
+
+        let (sent, received) = relay::relay(client, upstream_stream)?;
+        info!(
+            "Closed {} <-> {}: {} bytes to upstream, {} bytes to client",
+            client_addr, upstream, sent, received
+        );
+        Ok(())
+    })
+}
@@ -0,0 +1,296 @@
+use clap::Clap;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, ErrorKind, Write};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound of a message, so that a broken prefix or a line without end cannot make us
+/// allocate gigabytes.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Clap, Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// Messages terminated by `\n`.
+    Line,
+    /// Messages prefixed with their length as a 4-byte big-endian integer.
+    Length,
+}
+
+impl Framing {
+    /// Reads one whole message, however many segments it arrives in.
+    /// Returns `None` when the peer closed the connection between two messages.
+    pub fn read_frame<R: BufRead>(self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Framing::Line => {
+                let mut buf = Vec::new();
+                let mut limited = io::Read::take(&mut *reader, MAX_FRAME_LEN as u64 + 1);
+                if limited.read_until(b'\n', &mut buf)? == 0 {
+                    return Ok(None);
+                }
+                if buf.last() != Some(&b'\n') {
+                    if buf.len() > MAX_FRAME_LEN {
+                        return Err(line_too_long());
+                    }
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "connection closed in the middle of a line",
+                    ));
+                }
+                buf.pop();
+                Ok(Some(buf))
+            }
+            Framing::Length => {
+                let mut prefix = [0u8; 4];
+                if !read_exact_or_eof(reader, &mut prefix)? {
+                    return Ok(None);
+                }
+                let len = u32::from_be_bytes(prefix) as usize;
+                if len > MAX_FRAME_LEN {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("frame of {} bytes exceeds the limit", len),
+                    ));
+                }
+                let mut buf = vec![0u8; len];
+                reader.read_exact(&mut buf)?;
+                Ok(Some(buf))
+            }
+        }
+    }
+
+    /// Writes `payload` as one message.
+    pub fn write_frame<W: Write>(self, writer: &mut W, payload: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(payload.len() + 4);
+        self.encode(payload, &mut buf)?;
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+
+    /// Appends `payload` as one message to `buf`.
+    pub fn encode(self, payload: &[u8], buf: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Framing::Line => {
+                if payload.contains(&b'\n') {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "line-delimited message must not contain a newline",
+                    ));
+                }
+                buf.extend_from_slice(payload);
+                buf.push(b'\n');
+            }
+            Framing::Length => {
+                let len = u32::try_from(payload.len())
+                    .ok()
+                    .filter(|len| *len as usize <= MAX_FRAME_LEN)
+                    .ok_or_else(|| {
+                        io::Error::new(ErrorKind::InvalidInput, "message is too large to frame")
+                    })?;
+                buf.extend_from_slice(&len.to_be_bytes());
+                buf.extend_from_slice(payload);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the first complete message from the front of `buf`.
+    /// Returns `None` and leaves `buf` untouched while the message is still incomplete.
+    pub fn decode(self, buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Framing::Line => match buf.iter().position(|b| *b == b'\n') {
+                Some(pos) => {
+                    let mut msg: Vec<u8> = buf.drain(..=pos).collect();
+                    msg.pop();
+                    Ok(Some(msg))
+                }
+                None if buf.len() > MAX_FRAME_LEN => Err(line_too_long()),
+                None => Ok(None),
+            },
+            Framing::Length => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+                if len > MAX_FRAME_LEN {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("frame of {} bytes exceeds the limit", len),
+                    ));
+                }
+                if buf.len() < 4 + len {
+                    return Ok(None);
+                }
+                let msg = buf[4..4 + len].to_vec();
+                buf.drain(..4 + len);
+                Ok(Some(msg))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Framing {
+    /// The async counterpart of `read_frame`. `buf` keeps the bytes read past the returned
+    /// message and must be passed again with the next call on the same stream.
+    pub async fn read_frame_async<R: AsyncRead + Unpin>(
+        self,
+        reader: &mut R,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(msg) = self.decode(buf)? {
+                return Ok(Some(msg));
+            }
+            if reader.read_buf(buf).await? == 0 {
+                if buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a message",
+                ));
+            }
+        }
+    }
+
+    /// The async counterpart of `write_frame`.
+    pub async fn write_frame_async<W: AsyncWrite + Unpin>(
+        self,
+        writer: &mut W,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(payload.len() + 4);
+        self.encode(payload, &mut buf)?;
+        writer.write_all(&buf).await?;
+        writer.flush().await
+    }
+}
+
+fn line_too_long() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("line exceeds the limit of {} bytes", MAX_FRAME_LEN),
+    )
+}
+
+/// Like `read_exact`, but a clean EOF before the first byte is reported as `false` instead of an error.
+fn read_exact_or_eof<R: BufRead>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a length prefix",
+                ))
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor, Read};
+
+    /// Hands out one byte per read, like a peer whose message arrives in many segments.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            Read::read(&mut self.0, &mut buf[..len])
+        }
+    }
+
+    fn encoded(framing: Framing, messages: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for msg in messages {
+            framing.encode(msg, &mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn read_frame_reassembles_segmented_messages() {
+        for framing in [Framing::Line, Framing::Length] {
+            let bytes = encoded(framing, &[b"hello", b"", b"world"]);
+            let mut reader = BufReader::with_capacity(1, Trickle(Cursor::new(bytes)));
+            assert_eq!(framing.read_frame(&mut reader).unwrap().unwrap(), b"hello");
+            assert_eq!(framing.read_frame(&mut reader).unwrap().unwrap(), b"");
+            assert_eq!(framing.read_frame(&mut reader).unwrap().unwrap(), b"world");
+            assert!(framing.read_frame(&mut reader).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn read_frame_rejects_truncated_messages() {
+        let mut line = Cursor::new(b"no newline".to_vec());
+        let e = Framing::Line.read_frame(&mut line).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        let mut prefix = Cursor::new(vec![0, 0]);
+        let e = Framing::Length.read_frame(&mut prefix).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        let mut body = Cursor::new(vec![0, 0, 0, 5, b'a']);
+        let e = Framing::Length.read_frame(&mut body).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_rejected() {
+        let prefix = (MAX_FRAME_LEN as u32 + 1).to_be_bytes().to_vec();
+        let e = Framing::Length
+            .read_frame(&mut Cursor::new(prefix.clone()))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let e = Framing::Length.decode(&mut prefix.clone()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn endless_line_is_rejected() {
+        let line = vec![b'a'; MAX_FRAME_LEN + 1];
+        let e = Framing::Line
+            .read_frame(&mut Cursor::new(line.clone()))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let e = Framing::Line.decode(&mut line.clone()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        let mut line = vec![b'a'; MAX_FRAME_LEN];
+        assert_eq!(Framing::Line.decode(&mut line).unwrap(), None);
+        line.push(b'\n');
+        let msg = Framing::Line.read_frame(&mut Cursor::new(line)).unwrap();
+        assert_eq!(msg.unwrap().len(), MAX_FRAME_LEN);
+    }
+
+    #[test]
+    fn line_payload_must_not_contain_newline() {
+        let e = Framing::Line.encode(b"a\nb", &mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn decode_waits_for_a_complete_message() {
+        for framing in [Framing::Line, Framing::Length] {
+            let bytes = encoded(framing, &[b"first", b"second"]);
+            let mut buf = Vec::new();
+            let mut messages = Vec::new();
+            for byte in bytes {
+                buf.push(byte);
+                while let Some(msg) = framing.decode(&mut buf).unwrap() {
+                    messages.push(msg);
+                }
+            }
+            assert_eq!(messages, [b"first".to_vec(), b"second".to_vec()]);
+            assert!(buf.is_empty());
+        }
+    }
+}
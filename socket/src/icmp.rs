@@ -0,0 +1,454 @@
+use crate::net::NetOpts;
+use crate::scan;
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::{debug, warn};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io::{self, ErrorKind};
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, SocketAddr};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+/// Type, code, checksum, identifier and sequence number.
+const ECHO_HEADER_LEN: usize = 8;
+const IPV6_HEADER_LEN: usize = 40;
+
+#[derive(Clap, Debug)]
+pub struct IcmpOpts {
+    /// Echo requests the ping role sends.
+    #[clap(long, default_value = "4")]
+    pub count: u16,
+    /// Milliseconds between two echo requests of the ping role.
+    #[clap(long, default_value = "1000")]
+    pub interval: u64,
+    /// How long ping and traceroute wait for each reply, in milliseconds. It takes the place of
+    /// `--read-timeout`.
+    #[clap(long, default_value = "1000")]
+    pub reply_timeout: u64,
+    /// Payload bytes in each echo request.
+    #[clap(long, default_value = "56")]
+    pub ping_size: usize,
+    /// Highest TTL (hop limit for IPv6) the traceroute role tries.
+    #[clap(long, default_value = "30")]
+    pub max_hops: u32,
+    /// Echo requests the traceroute role sends to each hop.
+    #[clap(long, default_value = "3")]
+    pub probes: u16,
+}
+
+impl Default for IcmpOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+/// What an incoming ICMP message says about one of our echo requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+    Echo,
+    TimeExceeded,
+    Unreachable(u8),
+}
+
+/// An ICMP or ICMPv6 socket sending echo requests to one target.
+struct Pinger {
+    socket: Socket,
+    target: IpAddr,
+    /// Raw sockets see every ICMP message on the host and, for IPv4, the IP header.
+    /// Unprivileged datagram sockets only see replies to their own requests.
+    raw: bool,
+    ident: u16,
+}
+
+impl Pinger {
+    /// Opens a raw socket, or an unprivileged ICMP datagram socket where raw ones are not allowed.
+    fn open(target: IpAddr, net: &NetOpts) -> anyhow::Result<Self> {
+        let (domain, protocol) = match target {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let (socket, raw) = match Socket::new(domain, Type::RAW, Some(protocol)) {
+            Ok(socket) => (socket, true),
+            Err(e) => {
+                debug!("Raw ICMP socket: {}", e);
+                let socket = Socket::new(domain, Type::DGRAM, Some(protocol)).context(
+                    "ICMP needs a raw socket (root or CAP_NET_RAW) \
+                     or unprivileged ICMP sockets (sysctl net.ipv4.ping_group_range)",
+                )?;
+                (socket, false)
+            }
+        };
+        net.prepare(&socket, false)?;
+        debug!(
+            "Using a {} ICMP socket",
+            if raw { "raw" } else { "datagram" }
+        );
+        Ok(Pinger {
+            socket,
+            target,
+            raw,
+            ident: process::id() as u16,
+        })
+    }
+
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        match self.target {
+            IpAddr::V4(_) => self.socket.set_ttl(ttl),
+            IpAddr::V6(_) => self.socket.set_unicast_hops_v6(ttl),
+        }
+    }
+
+    fn send(&self, seq: u16, payload_len: usize) -> io::Result<()> {
+        let request_type = match self.target {
+            IpAddr::V4(_) => ICMP_ECHO_REQUEST,
+            IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+        };
+        let mut packet = vec![0u8; ECHO_HEADER_LEN + payload_len];
+        packet[0] = request_type;
+        packet[4..6].copy_from_slice(&self.ident.to_be_bytes());
+        packet[6..8].copy_from_slice(&seq.to_be_bytes());
+        for (i, byte) in packet[ECHO_HEADER_LEN..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        // The kernel fills in the ICMPv6 checksum, which covers a pseudo-header we cannot see.
+        if self.target.is_ipv4() {
+            let sum = checksum(&packet);
+            packet[2..4].copy_from_slice(&sum.to_be_bytes());
+        }
+        self.socket
+            .send_to(&packet, &SocketAddr::new(self.target, 0).into())?;
+        Ok(())
+    }
+
+    /// Waits until `deadline` for a message about echo request `seq`.
+    /// Returns the message, who sent it and its size, or `None` on timeout.
+    fn recv(&self, seq: u16, deadline: Instant) -> io::Result<Option<(Reply, IpAddr, usize)>> {
+        let mut buf = [0u8; 2048];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            let (size, from) = match recv_from(&self.socket, &mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let from = match from.as_socket() {
+                Some(addr) => addr.ip(),
+                None => continue,
+            };
+            let message = self.strip_ip_header(&buf[..size]);
+            if let Some(reply) = self.parse(message, seq) {
+                return Ok(Some((reply, from, message.len())));
+            }
+        }
+    }
+
+    /// Raw IPv4 sockets receive the whole datagram; everything else starts at the ICMP header.
+    fn strip_ip_header<'a>(&self, packet: &'a [u8]) -> &'a [u8] {
+        if !self.raw || self.target.is_ipv6() || packet.is_empty() {
+            return packet;
+        }
+        let header_len = usize::from(packet[0] & 0x0f) * 4;
+        packet.get(header_len..).unwrap_or(&[])
+    }
+
+    /// Recognizes the reply to echo request `seq`, or an error message quoting it.
+    fn parse(&self, message: &[u8], seq: u16) -> Option<Reply> {
+        if message.len() < ECHO_HEADER_LEN {
+            return None;
+        }
+        let (echo_reply, time_exceeded, unreachable) = match self.target {
+            IpAddr::V4(_) => (ICMP_ECHO_REPLY, ICMP_TIME_EXCEEDED, ICMP_DEST_UNREACHABLE),
+            IpAddr::V6(_) => (
+                ICMPV6_ECHO_REPLY,
+                ICMPV6_TIME_EXCEEDED,
+                ICMPV6_DEST_UNREACHABLE,
+            ),
+        };
+        let reply = match message[0] {
+            t if t == echo_reply => {
+                return self.is_ours(message, seq).then_some(Reply::Echo);
+            }
+            t if t == time_exceeded => Reply::TimeExceeded,
+            t if t == unreachable => Reply::Unreachable(message[1]),
+            _ => return None,
+        };
+        // Error messages quote the IP header and the first bytes of the request they are about.
+        let quoted = &message[ECHO_HEADER_LEN..];
+        let quoted_header_len = match self.target {
+            IpAddr::V4(_) => usize::from(*quoted.first()? & 0x0f) * 4,
+            IpAddr::V6(_) => IPV6_HEADER_LEN,
+        };
+        let request = quoted.get(quoted_header_len..)?;
+        let request_type = match self.target {
+            IpAddr::V4(_) => ICMP_ECHO_REQUEST,
+            IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+        };
+        (request.len() >= ECHO_HEADER_LEN
+            && request[0] == request_type
+            && self.is_ours(request, seq))
+        .then_some(reply)
+    }
+
+    /// Whether an echo message carries our identifier and `seq`. Datagram sockets get a
+    /// kernel-chosen identifier and only our own replies, so only the sequence number counts.
+    fn is_ours(&self, echo: &[u8], seq: u16) -> bool {
+        let ident = u16::from_be_bytes([echo[4], echo[5]]);
+        (!self.raw || ident == self.ident) && u16::from_be_bytes([echo[6], echo[7]]) == seq
+    }
+}
+
+/// Receives into an ordinary byte buffer.
+fn recv_from(socket: &Socket, buf: &mut [u8]) -> io::Result<(usize, SockAddr)> {
+    // SAFETY: the buffer is already initialized and `recv_from` only ever writes bytes into it.
+    let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+    socket.recv_from(buf)
+}
+
+/// The Internet checksum (RFC 1071) of `data`.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn ping(address: &str, opts: &IcmpOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let target = scan::target_ip(address)?;
+    let pinger = Pinger::open(target, net)?;
+    let timeout = Duration::from_millis(opts.reply_timeout);
+    let interval = Duration::from_millis(opts.interval);
+    println!("PING {}: {} data bytes", target, opts.ping_size);
+
+    let start = Instant::now();
+    let mut rtts = Vec::new();
+    for seq in 1..=opts.count {
+        let sent_at = Instant::now();
+        pinger.send(seq, opts.ping_size)?;
+        match pinger.recv(seq, sent_at + timeout)? {
+            Some((Reply::Echo, from, size)) => {
+                let rtt = sent_at.elapsed();
+                println!(
+                    "{} bytes from {}: icmp_seq={} time={:.3} ms",
+                    size,
+                    from,
+                    seq,
+                    millis(rtt)
+                );
+                rtts.push(rtt);
+            }
+            Some((reply, from, _)) => {
+                println!("From {} icmp_seq={} {}", from, seq, describe(reply))
+            }
+            None => println!("Request timeout for icmp_seq={}", seq),
+        }
+        if seq < opts.count {
+            thread::sleep(interval.saturating_sub(sent_at.elapsed()));
+        }
+    }
+
+    let sent = usize::from(opts.count);
+    println!("--- {} ping statistics ---", target);
+    println!(
+        "{} packets transmitted, {} received, {:.1}% packet loss, time {}ms",
+        sent,
+        rtts.len(),
+        if sent == 0 {
+            0.0
+        } else {
+            100.0 * (sent - rtts.len()) as f64 / sent as f64
+        },
+        start.elapsed().as_millis(),
+    );
+    if !rtts.is_empty() {
+        let ms: Vec<f64> = rtts.iter().copied().map(millis).collect();
+        let min = ms.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = ms.iter().copied().fold(0.0, f64::max);
+        let avg = ms.iter().sum::<f64>() / ms.len() as f64;
+        // Like iputils: the standard deviation, from the mean of the squares.
+        let mdev = (ms.iter().map(|x| x * x).sum::<f64>() / ms.len() as f64 - avg * avg)
+            .max(0.0)
+            .sqrt();
+        println!(
+            "rtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+            min, avg, max, mdev
+        );
+    }
+    Ok(())
+}
+
+pub fn traceroute(address: &str, opts: &IcmpOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let target = scan::target_ip(address)?;
+    if opts.max_hops == 0 || opts.max_hops > 255 {
+        return Err(anyhow!("--max-hops must be between 1 and 255"));
+    }
+    let pinger = Pinger::open(target, net)?;
+    if !pinger.raw {
+        warn!("Datagram ICMP sockets do not receive time-exceeded messages; hops before the target show as *");
+    }
+    let timeout = Duration::from_millis(opts.reply_timeout);
+    println!("traceroute to {}, {} hops max", target, opts.max_hops);
+
+    let mut seq = 0u16;
+    for ttl in 1..=opts.max_hops {
+        pinger.set_ttl(ttl)?;
+        let mut line = format!("{:>2} ", ttl);
+        let mut last_from = None;
+        let mut done = false;
+        for _ in 0..opts.probes {
+            seq = seq.wrapping_add(1);
+            let sent_at = Instant::now();
+            pinger.send(seq, 0)?;
+            match pinger.recv(seq, sent_at + timeout)? {
+                Some((reply, from, _)) => {
+                    if last_from != Some(from) {
+                        line.push_str(&format!(" {}", from));
+                        last_from = Some(from);
+                    }
+                    line.push_str(&format!("  {:.3} ms", millis(sent_at.elapsed())));
+                    // Marked like traceroute(8) marks unreachable codes it has no letter for.
+                    if let Reply::Unreachable(code) = reply {
+                        line.push_str(&format!(" !<{}>", code));
+                    }
+                    done |= reply != Reply::TimeExceeded;
+                }
+                None => line.push_str(" *"),
+            }
+        }
+        println!("{}", line);
+        if done {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn describe(reply: Reply) -> String {
+    match reply {
+        Reply::Echo => "echo reply".to_string(),
+        Reply::TimeExceeded => "time to live exceeded".to_string(),
+        Reply::Unreachable(code) => format!("destination unreachable (code {})", code),
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// A pinger for parsing only: the socket is never used.
+    fn pinger(raw: bool) -> Pinger {
+        Pinger {
+            socket: Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap(),
+            target: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            raw,
+            ident: 0x1234,
+        }
+    }
+
+    fn echo(kind: u8, ident: u16, seq: u16) -> Vec<u8> {
+        let mut message = vec![kind, 0, 0, 0];
+        message.extend_from_slice(&ident.to_be_bytes());
+        message.extend_from_slice(&seq.to_be_bytes());
+        message
+    }
+
+    /// An ICMP error quoting a 20-byte IPv4 header and the echo request it is about.
+    fn error(kind: u8, code: u8, request: &[u8]) -> Vec<u8> {
+        let mut message = vec![kind, code, 0, 0, 0, 0, 0, 0];
+        let mut ip_header = vec![0u8; 20];
+        ip_header[0] = 0x45;
+        message.extend_from_slice(&ip_header);
+        message.extend_from_slice(request);
+        message
+    }
+
+    #[test]
+    fn checksum_matches_rfc_1071() {
+        assert_eq!(
+            checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]),
+            0x220d
+        );
+        // An odd length is padded with a zero byte.
+        assert_eq!(checksum(&[0xff]), 0x00ff);
+
+        let mut packet = echo(ICMP_ECHO_REQUEST, 1, 2);
+        packet.extend_from_slice(b"payload");
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+        assert_eq!(checksum(&packet), 0);
+    }
+
+    #[test]
+    fn echo_replies_must_be_ours() {
+        let raw = pinger(true);
+        assert_eq!(
+            raw.parse(&echo(ICMP_ECHO_REPLY, 0x1234, 7), 7),
+            Some(Reply::Echo)
+        );
+        assert_eq!(raw.parse(&echo(ICMP_ECHO_REPLY, 0x1234, 8), 7), None);
+        assert_eq!(raw.parse(&echo(ICMP_ECHO_REPLY, 0x4321, 7), 7), None);
+        assert_eq!(raw.parse(&echo(ICMP_ECHO_REQUEST, 0x1234, 7), 7), None);
+        assert_eq!(raw.parse(&[ICMP_ECHO_REPLY, 0, 0], 7), None);
+
+        // The kernel picks the identifier of a datagram socket.
+        let dgram = pinger(false);
+        assert_eq!(
+            dgram.parse(&echo(ICMP_ECHO_REPLY, 0x4321, 7), 7),
+            Some(Reply::Echo)
+        );
+    }
+
+    #[test]
+    fn errors_are_matched_by_the_quoted_request() {
+        let raw = pinger(true);
+        let ours = echo(ICMP_ECHO_REQUEST, 0x1234, 3);
+        let message = error(ICMP_TIME_EXCEEDED, 0, &ours);
+        assert_eq!(raw.parse(&message, 3), Some(Reply::TimeExceeded));
+        assert_eq!(raw.parse(&message, 4), None);
+
+        let message = error(ICMP_DEST_UNREACHABLE, 3, &ours);
+        assert_eq!(raw.parse(&message, 3), Some(Reply::Unreachable(3)));
+
+        let theirs = echo(ICMP_ECHO_REQUEST, 0x4321, 3);
+        assert_eq!(raw.parse(&error(ICMP_TIME_EXCEEDED, 0, &theirs), 3), None);
+        // Too short to quote the whole echo header.
+        assert_eq!(
+            raw.parse(&error(ICMP_TIME_EXCEEDED, 0, &ours[..6]), 3),
+            None
+        );
+    }
+
+    #[test]
+    fn raw_ipv4_packets_lose_their_ip_header() {
+        let mut packet = vec![0x46];
+        packet.extend_from_slice(&[0u8; 23]);
+        packet.extend_from_slice(&echo(ICMP_ECHO_REPLY, 0x1234, 1));
+        assert_eq!(pinger(true).strip_ip_header(&packet), &packet[24..]);
+        assert_eq!(pinger(false).strip_ip_header(&packet), &packet[..]);
+    }
+}
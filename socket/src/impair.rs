@@ -0,0 +1,633 @@
+use crate::net::NetOpts;
+use crate::tcp_server;
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::{debug, error};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Largest piece of a TCP stream that is scheduled as one unit.
+const TCP_CHUNK_SIZE: usize = 1460;
+const MAX_DATAGRAM: usize = 65536;
+/// How long a "lost" TCP chunk stalls the stream, like a retransmission after a timeout.
+const TCP_RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+/// Chunks of one TCP direction held back at once, so that a slow link pushes back on the sender.
+const TCP_QUEUE_LIMIT: usize = 1024;
+/// Datagrams of one UDP direction held back at once. More are dropped, like on a full router queue.
+const UDP_QUEUE_LIMIT: usize = 4096;
+/// How long a UDP session lives without datagrams in either direction.
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the UDP relay looks for idle sessions, and its reply threads check for closing.
+const UDP_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The input of a delay line: items with the time to deliver them.
+type Schedule<T> = SyncSender<(Instant, T)>;
+
+#[derive(Clap, Debug, Clone)]
+pub struct ImpairOpts {
+    /// Milliseconds added to every datagram or chunk passing the impair role.
+    #[clap(long, default_value = "0")]
+    pub delay: u64,
+    /// Milliseconds by which the delay varies at random, up or down.
+    #[clap(long, default_value = "0")]
+    pub jitter: u64,
+    /// Probability in [0, 1] of dropping a datagram. On TCP a drop stalls the stream instead.
+    #[clap(long, default_value = "0")]
+    pub drop: f64,
+    /// Probability in [0, 1] of sending a datagram twice. UDP only.
+    #[clap(long, default_value = "0")]
+    pub duplicate: f64,
+    /// Probability in [0, 1] of sending a datagram without the delay, ahead of earlier ones. UDP only.
+    #[clap(long, default_value = "0")]
+    pub reorder: f64,
+    /// Bandwidth of each direction in bytes per second. Unlimited if not given.
+    #[clap(long)]
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for ImpairOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+impl ImpairOpts {
+    fn check(&self) -> anyhow::Result<()> {
+        for (name, p) in [
+            ("--drop", self.drop),
+            ("--duplicate", self.duplicate),
+            ("--reorder", self.reorder),
+        ] {
+            if !(0.0..=1.0).contains(&p) {
+                return Err(anyhow!("{} must be between 0 and 1", name));
+            }
+        }
+        if self.bandwidth == Some(0) {
+            return Err(anyhow!("--bandwidth must be positive"));
+        }
+        Ok(())
+    }
+}
+
+/// Decides the fate of the traffic in one direction: when, if at all, and how often each
+/// piece is delivered. Keeps the state of the emulated link between calls.
+struct Link {
+    opts: ImpairOpts,
+    /// Whether the traffic is a byte stream, which must stay in order and complete.
+    stream: bool,
+    /// When the emulated link has finished serializing everything sent so far.
+    busy_until: Instant,
+    /// Latest delivery time handed out, which stream traffic never goes below.
+    last_delivery: Instant,
+    rng: StdRng,
+}
+
+impl Link {
+    fn new(opts: &ImpairOpts, stream: bool) -> Self {
+        let now = Instant::now();
+        Link {
+            opts: opts.clone(),
+            stream,
+            busy_until: now,
+            last_delivery: now,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Returns the delivery times of a piece of `len` bytes sent now:
+    /// none if it is dropped, two if it is duplicated.
+    fn schedule(&mut self, len: usize) -> Vec<Instant> {
+        let now = Instant::now();
+        let mut extra = Duration::ZERO;
+        if self.hit(self.opts.drop) {
+            if !self.stream {
+                debug!("Dropping {} bytes", len);
+                return Vec::new();
+            }
+            debug!("Stalling {} bytes as if they were lost", len);
+            extra = TCP_RETRANSMIT_DELAY;
+        }
+
+        // The time the link takes to carry the bytes, one piece after another.
+        let departure = match self.opts.bandwidth {
+            Some(rate) => {
+                let start = self.busy_until.max(now);
+                self.busy_until = start + Duration::from_secs_f64(len as f64 / rate as f64);
+                self.busy_until
+            }
+            None => now,
+        };
+
+        let mut at = departure + extra;
+        if !self.stream && self.hit(self.opts.reorder) {
+            debug!("Sending {} bytes ahead of the queue", len);
+        } else {
+            at += self.delay();
+        }
+        if self.stream {
+            at = at.max(self.last_delivery);
+        }
+        self.last_delivery = self.last_delivery.max(at);
+
+        if !self.stream && self.hit(self.opts.duplicate) {
+            debug!("Duplicating {} bytes", len);
+            let copy_at = departure + self.delay();
+            return vec![at, copy_at];
+        }
+        vec![at]
+    }
+
+    fn delay(&mut self) -> Duration {
+        let delay = self.opts.delay as i64;
+        let jitter = self.opts.jitter as i64;
+        let millis = if jitter > 0 {
+            delay + self.rng.gen_range(-jitter..=jitter)
+        } else {
+            delay
+        };
+        Duration::from_millis(millis.max(0) as u64)
+    }
+
+    fn hit(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_bool(probability)
+    }
+}
+
+/// Something waiting in a delay line. Ordered by delivery time, then by arrival.
+struct Scheduled<T> {
+    at: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Scheduled<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl<T> Eq for Scheduled<T> {}
+
+impl<T> PartialOrd for Scheduled<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Scheduled<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// Hands items to `deliver` at the times they were scheduled for, on a thread of its own.
+/// At most `limit` items wait at once; sending more blocks until the earliest is delivered.
+/// Once every sender is dropped, it delivers what is left and the thread ends.
+fn delay_line<T, F>(limit: usize, mut deliver: F) -> (Schedule<T>, JoinHandle<()>)
+where
+    T: Send + 'static,
+    F: FnMut(T) -> io::Result<()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::sync_channel(limit);
+    let thread = thread::spawn(move || {
+        let mut queue: BinaryHeap<Reverse<Scheduled<T>>> = BinaryHeap::new();
+        let mut seq = 0u64;
+        let mut open = true;
+        loop {
+            // Take in new items while there is room, but only until the next one is due.
+            if open && queue.len() < limit {
+                let received = match queue.peek() {
+                    Some(Reverse(next)) => {
+                        rx.recv_timeout(next.at.saturating_duration_since(Instant::now()))
+                    }
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok((at, item)) => {
+                        queue.push(Reverse(Scheduled { at, seq, item }));
+                        seq += 1;
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => open = false,
+                }
+            }
+
+            let next = match queue.pop() {
+                Some(Reverse(next)) => next,
+                None => return,
+            };
+            let now = Instant::now();
+            if next.at > now {
+                thread::sleep(next.at - now);
+            }
+            if let Err(e) = deliver(next.item) {
+                error!("{}", e);
+                return;
+            }
+        }
+    });
+    (tx, thread)
+}
+
+pub fn tcp(address: &str, upstream: &str, opts: &ImpairOpts, net: &NetOpts) -> anyhow::Result<()> {
+    opts.check()?;
+    let upstream = upstream.to_string();
+    let upstream_net = net.clone();
+    let opts = opts.clone();
+    tcp_server::serve(address, net, move |client| {
+        let client_addr = client.peer_addr()?;
+        let upstream_stream = upstream_net
+            .connect_tcp(&upstream)
+            .with_context(|| format!("Failed to connect to upstream {}", upstream))?;
+        debug!("Impairing {} <-> {}", client_addr, upstream);
+
+        let client_reader = client.try_clone()?;
+        let upstream_reader = upstream_stream.try_clone()?;
+        let up_opts = opts.clone();
+        let up = thread::spawn(move || impair_stream(client_reader, upstream_stream, &up_opts));
+        let received = impair_stream(upstream_reader, client, &opts)?;
+        let sent = up.join().map_err(|_| anyhow!("Impair thread panicked"))??;
+        debug!(
+            "Closed {} <-> {}: {} bytes to upstream, {} bytes to client",
+            client_addr, upstream, sent, received
+        );
+        Ok(())
+    })
+}
+
+/// Copies `from` to `to` through an impaired link until `from` ends, then forwards the EOF.
+fn impair_stream(mut from: TcpStream, to: TcpStream, opts: &ImpairOpts) -> io::Result<u64> {
+    let mut link = Link::new(opts, true);
+    let mut writer = to.try_clone()?;
+    let (tx, delivery) = delay_line(TCP_QUEUE_LIMIT, move |chunk: Vec<u8>| {
+        writer.write_all(&chunk)
+    });
+    let mut buf = [0u8; TCP_CHUNK_SIZE];
+    let mut total = 0;
+    let result = loop {
+        let size = match from.read(&mut buf) {
+            Ok(0) => break Ok(total),
+            Ok(size) => size,
+            Err(e) => break Err(e),
+        };
+        total += size as u64;
+        for at in link.schedule(size) {
+            if tx.send((at, buf[..size].to_vec())).is_err() {
+                // The writer gave up on `to`.
+                let _ = from.shutdown(Shutdown::Read);
+                return Ok(total);
+            }
+        }
+    };
+    drop(tx);
+    let _ = delivery.join();
+    if let Err(e) = to.shutdown(Shutdown::Write) {
+        debug!("shutdown: {}", e);
+    }
+    result
+}
+
+pub fn udp(address: &str, upstream: &str, opts: &ImpairOpts, net: &NetOpts) -> anyhow::Result<()> {
+    opts.check()?;
+    let socket = Arc::new(net.bind_udp(address)?);
+
+    // Replies from every session share the way back to the clients.
+    let down_link = Arc::new(Mutex::new(Link::new(opts, false)));
+    let reply_socket = Arc::clone(&socket);
+    let (down_tx, _down) = delay_line(
+        UDP_QUEUE_LIMIT,
+        move |(client, datagram): (SocketAddr, Vec<u8>)| {
+            if let Err(e) = reply_socket.send_to(&datagram, client) {
+                debug!("{}: {}", client, e);
+            }
+            Ok(())
+        },
+    );
+    let mut up_link = Link::new(opts, false);
+    let (up_tx, _up) = delay_line(
+        UDP_QUEUE_LIMIT,
+        |(upstream, datagram): (Arc<UdpSocket>, Vec<u8>)| {
+            // A refused datagram only means that nobody listens upstream right now.
+            if let Err(e) = upstream.send(&datagram) {
+                debug!("upstream: {}", e);
+            }
+            Ok(())
+        },
+    );
+
+    // The upstream socket standing in for each client, so that replies find their way back.
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut last_sweep = Instant::now();
+    socket.set_read_timeout(Some(UDP_SWEEP_INTERVAL))?;
+    loop {
+        if last_sweep.elapsed() >= UDP_SWEEP_INTERVAL {
+            sessions.retain(|client, session| {
+                let keep = !session.activity.is_closed()
+                    && session.activity.idle_for() < UDP_SESSION_TIMEOUT;
+                if !keep {
+                    debug!("Closing the session for {}", client);
+                    session.activity.close();
+                }
+                keep
+            });
+            last_sweep = Instant::now();
+        }
+        let (size, client) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let session = match sessions.entry(client) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let upstream_socket = match net.connect_udp(upstream) {
+                    Ok(socket) => Arc::new(socket),
+                    Err(e) => {
+                        // The other sessions are fine, and the client's next datagram tries again.
+                        error!("{}: cannot reach upstream {}: {:#}", client, upstream, e);
+                        continue;
+                    }
+                };
+                debug!(
+                    "New session for {} via {}",
+                    client,
+                    upstream_socket.local_addr()?
+                );
+                let session = Session {
+                    upstream: upstream_socket,
+                    activity: Arc::new(Activity::new()),
+                };
+                let replies = Arc::clone(&session.upstream);
+                let activity = Arc::clone(&session.activity);
+                let link = Arc::clone(&down_link);
+                let tx = down_tx.clone();
+                thread::spawn(move || {
+                    if let Err(e) = forward_replies(&replies, client, &activity, &link, &tx) {
+                        error!("{}: {}", client, e);
+                    }
+                    // A new session replaces this one if the client sends again.
+                    activity.close();
+                });
+                entry.insert(session)
+            }
+        };
+        session.activity.touch();
+        for at in up_link.schedule(size) {
+            enqueue(
+                &up_tx,
+                at,
+                (Arc::clone(&session.upstream), buf[..size].to_vec()),
+            )?;
+        }
+    }
+}
+
+/// The relay's stand-in for one UDP client towards the upstream.
+struct Session {
+    upstream: Arc<UdpSocket>,
+    activity: Arc<Activity>,
+}
+
+/// When a UDP session last carried a datagram, and whether it has been closed.
+struct Activity {
+    last: Mutex<Instant>,
+    closed: AtomicBool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            last: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last.lock().unwrap().elapsed()
+    }
+
+    fn close(&self) {
+        self.closed.store(true, AtomicOrdering::SeqCst);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(AtomicOrdering::SeqCst)
+    }
+}
+
+/// Feeds the datagrams `upstream` receives into the delay line back to `client`,
+/// until the session is closed.
+fn forward_replies(
+    upstream: &UdpSocket,
+    client: SocketAddr,
+    activity: &Activity,
+    link: &Mutex<Link>,
+    tx: &Schedule<(SocketAddr, Vec<u8>)>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    upstream.set_read_timeout(Some(UDP_SWEEP_INTERVAL))?;
+    while !activity.is_closed() {
+        let size = match upstream.recv(&mut buf) {
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            // An earlier datagram found nobody listening upstream; later ones may.
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                debug!("upstream: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        activity.touch();
+        let times = link.lock().unwrap().schedule(size);
+        for at in times {
+            enqueue(tx, at, (client, buf[..size].to_vec()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Queues a datagram for delivery, dropping it if too many are waiting already.
+fn enqueue<T>(tx: &Schedule<T>, at: Instant, datagram: T) -> anyhow::Result<()> {
+    match tx.try_send((at, datagram)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => {
+            debug!("Dropping a datagram on a full queue");
+            Ok(())
+        }
+        Err(TrySendError::Disconnected(_)) => Err(anyhow!("The delay line stopped")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(opts: ImpairOpts, stream: bool) -> Link {
+        Link::new(&opts, stream)
+    }
+
+    fn millis(at: Instant, since: Instant) -> u128 {
+        at.saturating_duration_since(since).as_millis()
+    }
+
+    #[test]
+    fn an_unimpaired_link_delivers_at_once() {
+        let start = Instant::now();
+        let times = link(ImpairOpts::default(), false).schedule(100);
+        assert_eq!(times.len(), 1);
+        assert!(millis(times[0], start) < 50);
+    }
+
+    #[test]
+    fn drops_lose_datagrams_but_only_stall_streams() {
+        let opts = ImpairOpts {
+            drop: 1.0,
+            ..ImpairOpts::default()
+        };
+        assert!(link(opts.clone(), false).schedule(100).is_empty());
+
+        let start = Instant::now();
+        let times = link(opts, true).schedule(100);
+        assert_eq!(times.len(), 1);
+        assert!(times[0] >= start + TCP_RETRANSMIT_DELAY);
+    }
+
+    #[test]
+    fn delay_varies_within_the_jitter() {
+        let mut link = link(
+            ImpairOpts {
+                delay: 100,
+                jitter: 50,
+                ..ImpairOpts::default()
+            },
+            false,
+        );
+        for _ in 0..100 {
+            let start = Instant::now();
+            let at = link.schedule(10)[0];
+            let delay = millis(at, start);
+            assert!((50..=160).contains(&delay), "{} ms", delay);
+        }
+    }
+
+    #[test]
+    fn jitter_never_reorders_a_stream() {
+        let mut link = link(
+            ImpairOpts {
+                delay: 50,
+                jitter: 50,
+                ..ImpairOpts::default()
+            },
+            true,
+        );
+        let times: Vec<Instant> = (0..200).flat_map(|_| link.schedule(10)).collect();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn reordered_datagrams_skip_the_delay() {
+        let opts = ImpairOpts {
+            delay: 200,
+            ..ImpairOpts::default()
+        };
+        let start = Instant::now();
+        let delayed = link(opts.clone(), false).schedule(10)[0];
+        let ahead = link(
+            ImpairOpts {
+                reorder: 1.0,
+                ..opts
+            },
+            false,
+        )
+        .schedule(10)[0];
+        assert!(millis(delayed, start) >= 200);
+        assert!(ahead < delayed);
+    }
+
+    #[test]
+    fn duplicates_are_delivered_twice() {
+        let times = link(
+            ImpairOpts {
+                duplicate: 1.0,
+                ..ImpairOpts::default()
+            },
+            false,
+        )
+        .schedule(10);
+        assert_eq!(times.len(), 2);
+    }
+
+    #[test]
+    fn bandwidth_spaces_pieces_out() {
+        let start = Instant::now();
+        let mut link = link(
+            ImpairOpts {
+                bandwidth: Some(1000),
+                ..ImpairOpts::default()
+            },
+            false,
+        );
+        let times: Vec<Instant> = (0..3).flat_map(|_| link.schedule(100)).collect();
+        for (i, at) in times.iter().enumerate() {
+            let expected = 100 * (i as u128 + 1);
+            let elapsed = millis(*at, start);
+            assert!(
+                (expected..expected + 50).contains(&elapsed),
+                "{} ms",
+                elapsed
+            );
+        }
+    }
+
+    #[test]
+    fn delay_line_delivers_by_time_not_by_arrival() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&delivered);
+        let start = Instant::now();
+        let (tx, thread) = delay_line(16, move |item: u32| {
+            sink.lock().unwrap().push((item, Instant::now()));
+            Ok(())
+        });
+        for (item, ms) in [(1, 150), (2, 50), (3, 100), (4, 50)] {
+            tx.send((start + Duration::from_millis(ms), item)).unwrap();
+        }
+        // Dropping the sender still delivers everything that is queued.
+        drop(tx);
+        thread.join().unwrap();
+
+        let delivered = delivered.lock().unwrap();
+        let items: Vec<u32> = delivered.iter().map(|(item, _)| *item).collect();
+        assert_eq!(items, [2, 4, 3, 1]);
+        for ((_, at), ms) in delivered.iter().zip([50, 50, 100, 150]) {
+            assert!(*at >= start + Duration::from_millis(ms));
+        }
+    }
+
+    #[test]
+    fn delay_line_stops_when_delivery_fails() {
+        let (tx, thread) = delay_line(4, |_: u32| Err(io::Error::from(ErrorKind::BrokenPipe)));
+        tx.send((Instant::now(), 1)).unwrap();
+        thread.join().unwrap();
+        assert!(tx.send((Instant::now(), 2)).is_err());
+    }
+}
@@ -0,0 +1,886 @@
+use crate::net::NetOpts;
+use crate::shutdown::{Shutdown, ShutdownOpts};
+use crate::tcp_server::EchoServer;
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often expired keys nobody asks for are removed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Limits on what one request may announce, so a bad header cannot make us allocate gigabytes.
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Longest inline command or header line, as in Redis.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+#[derive(Clap, Debug, Default)]
+pub struct KvOpts {
+    /// Append-only file the kv role replays at startup and logs every write to.
+    #[clap(long, parse(from_os_str))]
+    pub aof: Option<PathBuf>,
+}
+
+/// A RESP reply.
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            Reply::Error(e) => write!(writer, "-{}\r\n", e),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(data)) => {
+                write!(writer, "${}\r\n", data.len())?;
+                writer.write_all(data)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(writer))
+            }
+        }
+    }
+}
+
+fn wrong_args(name: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+fn not_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_string())
+}
+
+fn invalid_expire_time(command: &str) -> Reply {
+    Reply::Error(format!("ERR invalid expire time in '{}' command", command))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+struct Entry {
+    value: Vec<u8>,
+    /// Milliseconds since the Unix epoch, so that expiry times survive a restart.
+    expires_at: Option<u64>,
+}
+
+/// The keys, and the append-only file their writes go to.
+#[derive(Default)]
+struct Store {
+    entries: HashMap<Vec<u8>, Entry>,
+    aof: Option<File>,
+    /// Set while the append-only file is replayed. Keys then expire only once it is done, so
+    /// that a write logged before a key expired applies to the key as it was back then.
+    replaying: bool,
+}
+
+impl Store {
+    /// Replays the append-only file at `path`, creating it if need be, and keeps it for appending.
+    /// A command cut short by a crash is dropped from the end of the file; anything else that
+    /// cannot be read is an error, so that no write after it is lost.
+    fn open(path: &Path) -> anyhow::Result<Store> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Cannot open {}", path.display()))?;
+        let mut store = Store {
+            replaying: true,
+            ..Store::default()
+        };
+        let mut reader = BufReader::new(&mut file);
+        let mut replayed = 0;
+        let mut valid_len = 0;
+        loop {
+            match read_command(&mut reader) {
+                Ok(Some(args)) if args.is_empty() => {}
+                Ok(Some(args)) => {
+                    if let Reply::Error(e) = store.execute(&args) {
+                        return Err(anyhow!(
+                            "{}: command {}: {}",
+                            path.display(),
+                            replayed + 1,
+                            e
+                        ));
+                    }
+                    replayed += 1;
+                }
+                Ok(None) => break,
+                Err(e) if is_truncated(&e) => {
+                    warn!(
+                        "{}: dropping a truncated command at the end: {}",
+                        path.display(),
+                        e
+                    );
+                    break;
+                }
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "{} is corrupt after command {}",
+                        path.display(),
+                        replayed
+                    )))
+                }
+            }
+            valid_len = reader.stream_position()?;
+        }
+        file.set_len(valid_len)?;
+        store.replaying = false;
+        store.remove_expired();
+        info!("Replayed {} commands from {}", replayed, path.display());
+        store.aof = Some(file);
+        Ok(store)
+    }
+
+    /// The live entry for `key`. An expired entry is removed instead.
+    fn entry(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let now = now_millis();
+        if !self.replaying
+            && matches!(self.entries.get(key), Some(Entry { expires_at: Some(at), .. }) if *at <= now)
+        {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn remove_expired(&mut self) {
+        let now = now_millis();
+        self.entries
+            .retain(|_, entry| entry.expires_at.is_none_or(|at| at > now));
+    }
+
+    /// Appends a write to the append-only file. Relative expiry times must already be absolute,
+    /// so that replaying the file later gives the same result.
+    fn log(&mut self, args: &[&[u8]]) -> Option<Reply> {
+        let aof = self.aof.as_mut()?;
+        let mut command = Vec::new();
+        let reply = Reply::Array(
+            args.iter()
+                .map(|arg| Reply::Bulk(Some(arg.to_vec())))
+                .collect(),
+        );
+        let result = reply
+            .write_to(&mut command)
+            .and_then(|_| aof.write_all(&command));
+        match result {
+            Ok(()) => None,
+            Err(e) => {
+                error!("Append-only file: {}", e);
+                Some(Reply::Error(format!(
+                    "ERR cannot write the append-only file: {}",
+                    e
+                )))
+            }
+        }
+    }
+
+    /// Runs one command and returns its reply.
+    fn execute(&mut self, args: &[Vec<u8>]) -> Reply {
+        let command = String::from_utf8_lossy(&args[0]).into_owned();
+        let name = command.to_ascii_uppercase();
+        let args = &args[1..];
+        match (name.as_str(), args.len()) {
+            ("PING", 0) => Reply::Simple("PONG"),
+            ("PING", 1) => Reply::Bulk(Some(args[0].clone())),
+            ("GET", 1) => Reply::Bulk(self.entry(&args[0]).map(|entry| entry.value.clone())),
+            ("SET", n) if n >= 2 => self.set(args),
+            ("DEL", n) if n >= 1 => {
+                let removed = args
+                    .iter()
+                    .filter(|key| self.entry(key).is_some() && self.entries.remove(*key).is_some())
+                    .count();
+                if removed > 0 {
+                    let mut logged: Vec<&[u8]> = vec![b"DEL".as_slice()];
+                    logged.extend(args.iter().map(Vec::as_slice));
+                    if let Some(e) = self.log(&logged) {
+                        return e;
+                    }
+                }
+                Reply::Integer(removed as i64)
+            }
+            ("EXPIRE", 2) => match parse_int(&args[1]) {
+                Some(seconds) => match seconds
+                    .checked_mul(1000)
+                    .and_then(|ms| ms.checked_add(now_millis() as i64))
+                {
+                    Some(at) => self.expire_at(&args[0], at),
+                    None => invalid_expire_time("expire"),
+                },
+                None => not_integer(),
+            },
+            ("PEXPIREAT", 2) => match parse_int(&args[1]) {
+                Some(at) => self.expire_at(&args[0], at),
+                None => not_integer(),
+            },
+            ("TTL", 1) => {
+                let now = now_millis();
+                Reply::Integer(match self.entry(&args[0]) {
+                    None => -2,
+                    Some(Entry {
+                        expires_at: None, ..
+                    }) => -1,
+                    Some(Entry {
+                        expires_at: Some(at),
+                        ..
+                    }) => ((*at - now + 500) / 1000) as i64,
+                })
+            }
+            ("INCR", 1) => self.incr(&args[0]),
+            ("KEYS", 1) => {
+                self.remove_expired();
+                let mut keys: Vec<&Vec<u8>> = self
+                    .entries
+                    .keys()
+                    .filter(|key| glob_match(&args[0], key))
+                    .collect();
+                keys.sort();
+                Reply::Array(
+                    keys.into_iter()
+                        .map(|key| Reply::Bulk(Some(key.clone())))
+                        .collect(),
+                )
+            }
+            // redis-cli asks for the command table on startup; an empty one is fine.
+            ("COMMAND", _) => Reply::Array(Vec::new()),
+            ("PING", _)
+            | ("GET", _)
+            | ("SET", _)
+            | ("DEL", _)
+            | ("EXPIRE", _)
+            | ("PEXPIREAT", _)
+            | ("TTL", _)
+            | ("INCR", _)
+            | ("KEYS", _) => wrong_args(&name),
+            _ => Reply::Error(format!("ERR unknown command '{}'", command)),
+        }
+    }
+
+    /// `SET key value [EX seconds | PX milliseconds | PXAT unix-time-ms] [NX | XX]`
+    fn set(&mut self, args: &[Vec<u8>]) -> Reply {
+        let (key, value) = (&args[0], &args[1]);
+        let mut expires_at = None;
+        let (mut nx, mut xx) = (false, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let option = String::from_utf8_lossy(option).to_ascii_uppercase();
+            match option.as_str() {
+                "NX" => nx = true,
+                "XX" => xx = true,
+                "EX" | "PX" | "PXAT" if expires_at.is_none() => {
+                    let n = match options.next().and_then(|n| parse_int(n)) {
+                        Some(n) => n,
+                        None => return not_integer(),
+                    };
+                    let now = now_millis() as i64;
+                    let at = match option.as_str() {
+                        "EX" => n.checked_mul(1000).and_then(|ms| ms.checked_add(now)),
+                        "PX" => n.checked_add(now),
+                        _ => Some(n),
+                    };
+                    match at {
+                        Some(at) if n > 0 => expires_at = Some(at as u64),
+                        _ => return invalid_expire_time("set"),
+                    }
+                }
+                _ => return syntax_error(),
+            }
+        }
+        if nx && xx {
+            return syntax_error();
+        }
+        let exists = self.entry(key).is_some();
+        if (nx && exists) || (xx && !exists) {
+            return Reply::Bulk(None);
+        }
+        self.entries.insert(
+            key.clone(),
+            Entry {
+                value: value.clone(),
+                expires_at,
+            },
+        );
+        let at = expires_at.map(|at| at.to_string());
+        let mut logged: Vec<&[u8]> = vec![b"SET".as_slice(), key, value];
+        if let Some(at) = &at {
+            logged.extend([b"PXAT".as_slice(), at.as_bytes()]);
+        }
+        self.log(&logged).unwrap_or(Reply::Simple("OK"))
+    }
+
+    /// Sets when `key` expires, deleting it if that is already past.
+    fn expire_at(&mut self, key: &[u8], at: i64) -> Reply {
+        let entry = match self.entry(key) {
+            Some(entry) => entry,
+            None => return Reply::Integer(0),
+        };
+        let result = if at <= now_millis() as i64 {
+            self.entries.remove(key);
+            self.log(&[b"DEL".as_slice(), key])
+        } else {
+            entry.expires_at = Some(at as u64);
+            self.log(&[b"PEXPIREAT".as_slice(), key, at.to_string().as_bytes()])
+        };
+        result.unwrap_or(Reply::Integer(1))
+    }
+
+    /// Adds one to the integer at `key`, which counts as 0 if missing. Keeps the expiry time.
+    fn incr(&mut self, key: &[u8]) -> Reply {
+        let entry = self.entry(key);
+        let current = match &entry {
+            Some(entry) => match parse_int(&entry.value) {
+                Some(n) => n,
+                None => return not_integer(),
+            },
+            None => 0,
+        };
+        let next = match current.checked_add(1) {
+            Some(next) => next,
+            None => return Reply::Error("ERR increment or decrement would overflow".to_string()),
+        };
+        let expires_at = entry.as_ref().and_then(|entry| entry.expires_at);
+        let value = next.to_string().into_bytes();
+        match entry {
+            Some(entry) => entry.value = value,
+            None => {
+                self.entries.insert(
+                    key.to_vec(),
+                    Entry {
+                        value,
+                        expires_at: None,
+                    },
+                );
+            }
+        }
+        // Logged as the value it produced, like SET: replaying a relative INCR after its
+        // key has expired would bring the key back without the expiry.
+        let value = next.to_string();
+        let at = expires_at.map(|at| at.to_string());
+        let mut logged: Vec<&[u8]> = vec![b"SET".as_slice(), key, value.as_bytes()];
+        if let Some(at) = &at {
+            logged.extend([b"PXAT".as_slice(), at.as_bytes()]);
+        }
+        self.log(&logged).unwrap_or(Reply::Integer(next))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn parse_int(bytes: &[u8]) -> Option<i64> {
+    // Redis only takes canonical integers: no sign but `-`, no spaces.
+    if bytes.first() == Some(&b'+') {
+        return None;
+    }
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Matches `text` against a Redis glob pattern: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let (c, text_rest) = match text.split_first() {
+                Some(split) => split,
+                None => return false,
+            };
+            let negate = rest.first() == Some(&b'^');
+            let class = if negate { &rest[1..] } else { rest };
+            let mut matched = false;
+            let mut i = 0;
+            loop {
+                match class.get(i) {
+                    None => return false,
+                    Some(b']') => break,
+                    Some(b'\\') if i + 1 < class.len() => {
+                        matched |= class[i + 1] == *c;
+                        i += 2;
+                    }
+                    Some(&low)
+                        if class.get(i + 1) == Some(&b'-')
+                            && i + 2 < class.len()
+                            && class[i + 2] != b']' =>
+                    {
+                        let high = class[i + 2];
+                        let (low, high) = if low <= high {
+                            (low, high)
+                        } else {
+                            (high, low)
+                        };
+                        matched |= low <= *c && *c <= high;
+                        i += 3;
+                    }
+                    Some(&b) => {
+                        matched |= b == *c;
+                        i += 1;
+                    }
+                }
+            }
+            matched != negate && glob_match(&class[i + 1..], text_rest)
+        }
+        Some((b'\\', [escaped, rest @ ..])) | Some((escaped, rest)) => {
+            text.first() == Some(escaped) && glob_match(rest, &text[1..])
+        }
+    }
+}
+
+/// Reads one request: a RESP array of bulk strings, or an inline command as typed into telnet.
+/// Returns `None` at the end of the stream and an empty command for a blank line.
+fn read_command<R: BufRead>(reader: &mut R) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+    let count =
+        parse_len(&line[1..], MAX_ARGS).context("Protocol error: invalid multibulk length")?;
+    // Like the bulk strings below, the count is only a claim until the arguments arrive.
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| end_of_stream("bulk string header"))?;
+        if header.first() != Some(&b'$') {
+            return Err(anyhow!(
+                "Protocol error: expected '$', got '{}'",
+                String::from_utf8_lossy(&header)
+            ));
+        }
+        let len =
+            parse_len(&header[1..], MAX_BULK_LEN).context("Protocol error: invalid bulk length")?;
+        // Grow the buffer as the data arrives rather than trusting the announced length.
+        let mut arg = Vec::new();
+        reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(end_of_stream("bulk string").into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(anyhow!("Protocol error: bulk string not followed by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// A line without its CRLF, or `None` at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE_LEN as u64 + 1;
+    if reader.by_ref().take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') && line.len() > MAX_LINE_LEN {
+        return Err(anyhow!("Protocol error: too big inline request"));
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(end_of_stream("line").into());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn end_of_stream(what: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        format!("Protocol error: stream ends inside a {}", what),
+    )
+}
+
+/// Whether reading a command failed only because the stream ended in the middle of it.
+fn is_truncated(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<io::Error>(), Some(e) if e.kind() == ErrorKind::UnexpectedEof)
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Option<usize> {
+    let len: usize = std::str::from_utf8(digits).ok()?.parse().ok()?;
+    (len <= max).then_some(len)
+}
+
+pub fn server(
+    address: &str,
+    opts: &KvOpts,
+    net: &NetOpts,
+    shutdown: &ShutdownOpts,
+) -> anyhow::Result<()> {
+    let store = match &opts.aof {
+        Some(path) => Store::open(path)?,
+        None => Store::default(),
+    };
+    let store = Arc::new(Mutex::new(store));
+    spawn_sweeper(Arc::downgrade(&store));
+
+    let handler_store = Arc::clone(&store);
+    let summary = EchoServer::new(address)
+        .net(net.clone())
+        .handler(move |stream| handler(stream, &handler_store))
+        .shutdown(Shutdown::on_signals()?)
+        .drain_timeout(shutdown.drain_timeout())
+        .run()?;
+
+    let mut store = lock(&store);
+    if let Some(aof) = &store.aof {
+        aof.sync_all()?;
+    }
+    store.remove_expired();
+    info!(
+        "Stopped: served {} connections, {} keys left",
+        summary.connections,
+        store.entries.len()
+    );
+    Ok(())
+}
+
+/// Locks the store even if a thread panicked while holding it. Every command leaves the
+/// keys consistent between its steps, so one failed command must not take the store down.
+fn lock(store: &Mutex<Store>) -> MutexGuard<'_, Store> {
+    store.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Removes expired keys in the background until the store is dropped.
+fn spawn_sweeper(store: Weak<Mutex<Store>>) {
+    thread::spawn(move || loop {
+        thread::sleep(SWEEP_INTERVAL);
+        match store.upgrade() {
+            Some(store) => lock(&store).remove_expired(),
+            None => return,
+        }
+    });
+}
+
+/// Answers the commands of one client. Replies to pipelined commands go out together once
+/// every command that has already arrived is answered.
+fn handler(stream: TcpStream, store: &Mutex<Store>) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) => {
+                // Like Redis: report the protocol error, then close the connection.
+                Reply::Error(format!("ERR {}", e)).write_to(&mut writer)?;
+                writer.flush()?;
+                return Err(e);
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        if args[0].eq_ignore_ascii_case(b"QUIT") {
+            Reply::Simple("OK").write_to(&mut writer)?;
+            break;
+        }
+        debug!("{}", String::from_utf8_lossy(&args[0]));
+        let reply = lock(store).execute(&args);
+        reply.write_to(&mut writer)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn parse(input: &[u8]) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+        read_command(&mut Cursor::new(input.to_vec()))
+    }
+
+    /// Runs a command and returns its reply as it goes over the wire.
+    fn run(store: &mut Store, args: &[&str]) -> String {
+        let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        let mut reply = Vec::new();
+        store.execute(&args).write_to(&mut reply).unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn multibulk_commands_carry_binary_arguments() {
+        let args = parse(b"*3\r\n$3\r\nSET\r\n$4\r\na\r\nb\r\n$0\r\n\r\n").unwrap();
+        assert_eq!(
+            args,
+            Some(vec![b"SET".to_vec(), b"a\r\nb".to_vec(), Vec::new()])
+        );
+        assert_eq!(parse(b"").unwrap(), None);
+    }
+
+    #[test]
+    fn inline_commands_are_split_on_whitespace() {
+        let args = parse(b"  set  key\tvalue\r\n").unwrap();
+        assert_eq!(
+            args,
+            Some(vec![b"set".to_vec(), b"key".to_vec(), b"value".to_vec()])
+        );
+        assert_eq!(parse(b"\r\n").unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn cut_off_commands_are_told_from_corrupt_ones() {
+        for truncated in [
+            &b"*2\r\n$3\r\nGET\r\n"[..],
+            b"*1\r\n$3\r\nGE",
+            b"*1\r\n$3\r\nGET",
+            b"*1\r\n$3",
+            b"PING",
+        ] {
+            let e = parse(truncated).unwrap_err();
+            assert!(is_truncated(&e), "{:?}: {}", truncated, e);
+        }
+        for corrupt in [
+            &b"*x\r\n"[..],
+            b"*1\r\n:3\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*1\r\n$3\r\nGETXY",
+            b"*1\r\n$999999999999\r\n",
+        ] {
+            let e = parse(corrupt).unwrap_err();
+            assert!(!is_truncated(&e), "{:?}: {}", corrupt, e);
+        }
+    }
+
+    #[test]
+    fn overlong_inline_lines_are_rejected() {
+        let line = vec![b'a'; MAX_LINE_LEN + 1];
+        let e = parse(&line).unwrap_err();
+        assert!(!is_truncated(&e), "{}", e);
+        assert!(e.to_string().contains("too big inline request"), "{}", e);
+
+        let mut line = vec![b'a'; MAX_LINE_LEN - 2];
+        line.extend_from_slice(b"\r\n");
+        assert_eq!(parse(&line).unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn a_large_argument_count_waits_for_its_arguments() {
+        let e = parse(b"*1048576\r\n$3\r\nGET\r\n").unwrap_err();
+        assert!(is_truncated(&e), "{}", e);
+    }
+
+    #[test]
+    fn replies_are_encoded() {
+        let reply = Reply::Array(vec![
+            Reply::Simple("OK"),
+            Reply::Error("ERR no".to_string()),
+            Reply::Integer(-2),
+            Reply::Bulk(None),
+            Reply::Bulk(Some(b"hi".to_vec())),
+            Reply::Array(Vec::new()),
+        ]);
+        let mut bytes = Vec::new();
+        reply.write_to(&mut bytes).unwrap();
+        assert_eq!(
+            bytes,
+            b"*6\r\n+OK\r\n-ERR no\r\n:-2\r\n$-1\r\n$2\r\nhi\r\n*0\r\n"
+        );
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("user:*", "user:42", true),
+            ("user:*", "users", false),
+            ("h?llo", "hallo", true),
+            ("h?llo", "hllo", false),
+            ("h[ae]llo", "hello", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[c-a]llo", "hbllo", true),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("h[ab", "ha", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), text.as_bytes()),
+                *expected,
+                "{} ~ {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn set_options() {
+        let mut store = Store::default();
+        assert_eq!(run(&mut store, &["SET", "k", "v", "XX"]), "$-1\r\n");
+        assert_eq!(run(&mut store, &["SET", "k", "v", "NX"]), "+OK\r\n");
+        assert_eq!(run(&mut store, &["SET", "k", "w", "NX"]), "$-1\r\n");
+        assert_eq!(run(&mut store, &["get", "k"]), "$1\r\nv\r\n");
+        assert_eq!(run(&mut store, &["SET", "k", "w", "EX", "100"]), "+OK\r\n");
+        assert_eq!(run(&mut store, &["TTL", "k"]), ":100\r\n");
+        assert_eq!(
+            run(&mut store, &["SET", "k", "w", "NX", "XX"]),
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            run(&mut store, &["SET", "k", "w", "EX", "0"]),
+            "-ERR invalid expire time in 'set' command\r\n"
+        );
+        assert_eq!(
+            run(&mut store, &["SET", "k", "w", "EX", &i64::MAX.to_string()]),
+            "-ERR invalid expire time in 'set' command\r\n"
+        );
+        assert_eq!(
+            run(&mut store, &["SET", "k", "w", "EX", "soon"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn expire_and_ttl() {
+        let mut store = Store::default();
+        assert_eq!(run(&mut store, &["TTL", "k"]), ":-2\r\n");
+        assert_eq!(run(&mut store, &["EXPIRE", "k", "10"]), ":0\r\n");
+        run(&mut store, &["SET", "k", "v"]);
+        assert_eq!(run(&mut store, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&mut store, &["EXPIRE", "k", "10"]), ":1\r\n");
+        assert_eq!(run(&mut store, &["TTL", "k"]), ":10\r\n");
+        assert_eq!(
+            run(&mut store, &["EXPIRE", "k", &i64::MAX.to_string()]),
+            "-ERR invalid expire time in 'expire' command\r\n"
+        );
+        assert_eq!(run(&mut store, &["EXPIRE", "k", "-1"]), ":1\r\n");
+        assert_eq!(run(&mut store, &["GET", "k"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn incr_counts_and_refuses_non_integers() {
+        let mut store = Store::default();
+        assert_eq!(run(&mut store, &["INCR", "n"]), ":1\r\n");
+        assert_eq!(run(&mut store, &["INCR", "n"]), ":2\r\n");
+        run(&mut store, &["SET", "n", &i64::MAX.to_string()]);
+        assert_eq!(
+            run(&mut store, &["INCR", "n"]),
+            "-ERR increment or decrement would overflow\r\n"
+        );
+        run(&mut store, &["SET", "s", "abc"]);
+        assert_eq!(
+            run(&mut store, &["INCR", "s"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn wrong_and_unknown_commands() {
+        let mut store = Store::default();
+        assert_eq!(run(&mut store, &["PING"]), "+PONG\r\n");
+        assert_eq!(
+            run(&mut store, &["GET"]),
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(run(&mut store, &["FLY"]), "-ERR unknown command 'FLY'\r\n");
+    }
+
+    #[test]
+    fn append_only_file_is_replayed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("kv.aof");
+        {
+            let mut store = Store::open(&path).unwrap();
+            run(&mut store, &["SET", "a", "1"]);
+            run(&mut store, &["INCR", "a"]);
+            run(&mut store, &["SET", "b", "x", "EX", "100"]);
+            run(&mut store, &["SET", "c", "y"]);
+            run(&mut store, &["DEL", "c"]);
+        }
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(run(&mut store, &["GET", "a"]), "$1\r\n2\r\n");
+        assert_eq!(run(&mut store, &["TTL", "b"]), ":100\r\n");
+        assert_eq!(run(&mut store, &["GET", "c"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn incr_of_an_expired_key_stays_expired_after_replay() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("kv.aof");
+        // As an older version logged it: a relative INCR after a SET that has expired since.
+        fs::write(
+            &path,
+            b"*5\r\n$3\r\nSET\r\n$1\r\nc\r\n$1\r\n5\r\n$4\r\nPXAT\r\n$13\r\n1000000000000\r\n\
+              *2\r\n$4\r\nINCR\r\n$1\r\nc\r\n",
+        )
+        .unwrap();
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(run(&mut store, &["GET", "c"]), "$-1\r\n");
+        assert_eq!(run(&mut store, &["TTL", "c"]), ":-2\r\n");
+    }
+
+    #[test]
+    fn incr_keeps_the_expiry_across_a_restart() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("kv.aof");
+        {
+            let mut store = Store::open(&path).unwrap();
+            run(&mut store, &["SET", "n", "5", "EX", "100"]);
+            run(&mut store, &["INCR", "n"]);
+            run(&mut store, &["INCR", "fresh"]);
+        }
+        let aof = String::from_utf8(fs::read(&path).unwrap()).unwrap();
+        assert!(!aof.contains("INCR"), "{:?}", aof);
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(run(&mut store, &["GET", "n"]), "$1\r\n6\r\n");
+        assert_eq!(run(&mut store, &["TTL", "n"]), ":100\r\n");
+        assert_eq!(run(&mut store, &["TTL", "fresh"]), ":-1\r\n");
+    }
+
+    #[test]
+    fn truncated_tail_is_dropped_but_corruption_is_fatal() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("kv.aof");
+        let complete = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let mut contents = complete.to_vec();
+        contents.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb");
+        fs::write(&path, &contents).unwrap();
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(run(&mut store, &["GET", "a"]), "$1\r\n1\r\n");
+        drop(store);
+        assert_eq!(fs::read(&path).unwrap(), complete);
+
+        let mut contents = b"*1\r\n:3\r\n".to_vec();
+        contents.extend_from_slice(complete);
+        fs::write(&path, &contents).unwrap();
+        let e = Store::open(&path).err().unwrap();
+        assert!(format!("{:#}", e).contains("corrupt"), "{:#}", e);
+        assert_eq!(fs::read(&path).unwrap(), contents);
+    }
+}
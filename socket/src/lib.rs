@@ -0,0 +1,52 @@
+//! Echo servers, clients and the other roles of the `socket` binary as a library,
+//! so that they can be embedded, for example as fixtures in other crates' tests.
+//!
+//! Each role takes the options struct its flags are parsed into. Their fields are public,
+//! and `Default` gives the command line's defaults:
+//!
+//! ```
+//! use socket::scan::ScanOpts;
+//!
+//! let opts = ScanOpts {
+//!     ports: "22,80,8000-8100".parse().unwrap(),
+//!     ..ScanOpts::default()
+//! };
+//! assert_eq!(opts.parallelism, 100);
+//! ```
+
+#[cfg(feature = "async")]
+pub mod async_tcp_client;
+#[cfg(feature = "async")]
+pub mod async_tcp_server;
+#[cfg(feature = "async")]
+pub mod async_udp_client;
+#[cfg(feature = "async")]
+pub mod async_udp_server;
+pub mod bench;
+pub mod chat;
+pub mod dns;
+pub mod file_transfer;
+pub mod forward;
+pub mod framing;
+pub mod icmp;
+pub mod impair;
+pub mod kv;
+pub mod multicast;
+pub mod net;
+pub mod netcat;
+pub mod relay;
+pub mod rudp;
+pub mod scan;
+pub mod script;
+pub mod shutdown;
+pub mod socks5;
+pub mod stun;
+pub mod tcp_client;
+pub mod tcp_event_server;
+pub mod tcp_server;
+pub mod tftp;
+pub mod tls;
+pub mod udp_client;
+pub mod udp_server;
+pub mod unix_client;
+pub mod unix_server;
@@ -1,11 +1,34 @@
 use std::env;
-use log::error;
+use std::process;
+use log::{error, info};
+use anyhow::anyhow;
 use clap::Clap;
-
-mod tcp_server;
-mod tcp_client;
-mod udp_server;
-mod udp_client;
+use socket::bench::BenchOpts;
+use socket::dns::DnsOpts;
+use socket::file_transfer::FileTransferOpts;
+use socket::framing::Framing;
+use socket::icmp::IcmpOpts;
+use socket::impair::ImpairOpts;
+use socket::kv::KvOpts;
+use socket::multicast::MulticastOpts;
+use socket::net::NetOpts;
+use socket::netcat::NetcatOpts;
+use socket::rudp::RudpOpts;
+use socket::scan::ScanOpts;
+use socket::script::ScriptOpts;
+use socket::shutdown::{Shutdown, ShutdownOpts};
+use socket::socks5::Socks5Opts;
+use socket::stun::StunOpts;
+use socket::tcp_server::EchoServer;
+use socket::tftp::TftpOpts;
+use socket::tls::TlsOpts;
+use socket::{
+    bench, chat, dns, file_transfer, forward, icmp, impair, kv, netcat, rudp, scan, script, socks5,
+    stun, tcp_client, tcp_event_server, tftp, tls, udp_client, udp_server, unix_client,
+    unix_server,
+};
+#[cfg(feature = "async")]
+use socket::{async_tcp_client, async_tcp_server, async_udp_client, async_udp_server};
 
 #[derive(Clap, Debug)]
 struct Opts {
@@ -13,20 +36,134 @@ struct Opts {
     protocol: Protocol,
     #[clap(arg_enum)]
     role: Role,
+    /// `host:port` for TCP and UDP, or a socket file path for the Unix protocols.
+    /// The host may be a name, an IPv4 literal or a bracketed IPv6 literal such as `[::1]`.
     #[clap(long = "host", default_value = "127.0.0.1:33333")]
     address: String,
+    /// How messages are delimited on TCP streams.
+    #[clap(long, arg_enum, default_value = "line")]
+    framing: Framing,
+    /// How the TCP and UDP echo servers and clients do their I/O.
+    #[clap(long, arg_enum, default_value = "thread")]
+    mode: Mode,
+    /// Address the forward and impair roles relay every accepted connection or datagram to.
+    #[clap(long)]
+    upstream: Option<String>,
+    #[clap(flatten)]
+    net: NetOpts,
+    #[clap(flatten)]
+    tls: TlsOpts,
+    #[clap(flatten)]
+    multicast: MulticastOpts,
+    #[clap(flatten)]
+    rudp: RudpOpts,
+    #[clap(flatten)]
+    bench: BenchOpts,
+    #[clap(flatten)]
+    socks5: Socks5Opts,
+    #[clap(flatten)]
+    file_transfer: FileTransferOpts,
+    #[clap(flatten)]
+    script: ScriptOpts,
+    #[clap(flatten)]
+    netcat: NetcatOpts,
+    #[clap(flatten)]
+    scan: ScanOpts,
+    #[clap(flatten)]
+    shutdown: ShutdownOpts,
+    #[clap(flatten)]
+    impair: ImpairOpts,
+    #[clap(flatten)]
+    icmp: IcmpOpts,
+    #[clap(flatten)]
+    tftp: TftpOpts,
+    #[clap(flatten)]
+    dns: DnsOpts,
+    #[clap(flatten)]
+    kv: KvOpts,
+    #[clap(flatten)]
+    stun: StunOpts,
 }
 
 #[derive(Clap, Debug)]
 enum Protocol {
     Tcp,
     Udp,
+    /// Reliable datagrams with acknowledgements and retransmission over UDP.
+    Rudp,
+    Unix,
+    UnixDgram,
+    /// ICMP or ICMPv6 echo, over raw or unprivileged ICMP sockets.
+    Icmp,
+    /// DNS over UDP, falling back to TCP for truncated answers.
+    Dns,
+    /// STUN binding requests over UDP, to find the address a NAT maps us to.
+    Stun,
+}
+
+#[derive(Clap, Debug, PartialEq)]
+enum Mode {
+    /// One OS thread per accepted connection.
+    Thread,
+    /// A single-threaded readiness loop on mio. TCP server only.
+    Event,
+    /// Tasks on a tokio runtime. Needs a build with `--features async`.
+    Async,
 }
 
 #[derive(Clap, Debug)]
 enum Role {
     Server,
+    /// Send each line of stdin as one message and print the replies.
     Client,
+    /// Pipe raw bytes between stdin/stdout and a peer, like `nc host port`.
+    Connect,
+    /// Wait for one peer, then pipe raw bytes like `nc -l`.
+    Listen,
+    TlsServer,
+    TlsClient,
+    /// Measure throughput and latency against an echo server.
+    Bench,
+    /// A SOCKS5 proxy server.
+    Socks5,
+    /// Relay every accepted connection to `--upstream`.
+    Forward,
+    /// Send `--file` to a recv-file server.
+    SendFile,
+    /// Receive files into `--dir`.
+    RecvFile,
+    /// A line-based chat server with rooms and nicknames.
+    Chat,
+    /// An in-memory key-value store speaking the Redis protocol (RESP).
+    Kv,
+    /// Probe `--ports` on the host of `--host` and report which are open.
+    Scan,
+    /// Relay to `--upstream` with added delay, loss, duplication, reordering or a bandwidth limit.
+    Impair,
+    /// Send echo requests to the host of `--host` and report round-trip times.
+    Ping,
+    /// List the routers on the way to the host of `--host`.
+    Traceroute,
+    /// Serve files from `--root` over TFTP.
+    TftpServer,
+    /// Download `--get` or upload `--put` over TFTP.
+    TftpClient,
+}
+
+fn echo_server(
+    address: &str,
+    framing: Framing,
+    net: &NetOpts,
+    shutdown: &ShutdownOpts,
+) -> anyhow::Result<()> {
+    let summary = EchoServer::new(address)
+        .framing(framing)
+        .net(net.clone())
+        .shutdown(Shutdown::on_signals()?)
+        .drain_timeout(shutdown.drain_timeout())
+        .run()?;
+    info!("Stopped: {}", summary);
+    Ok(())
 }
 
 fn main() {
@@ -34,18 +171,88 @@ fn main() {
     env_logger::init();
     let opts = Opts::parse();
     let result = match (opts.protocol, opts.role) {
-        (Protocol::Tcp, Role::Server) => {
-            tcp_server::server(&opts.address)
+        #[cfg(not(feature = "async"))]
+        _ if opts.mode == Mode::Async => Err(anyhow!("--mode async needs a build with --features async")),
+        #[cfg(feature = "async")]
+        (Protocol::Tcp, Role::Server) if opts.mode == Mode::Async => {
+            async_tcp_server::server(&opts.address, opts.framing, &opts.net, &opts.shutdown)
+        }
+        (Protocol::Tcp, Role::Server) => match opts.mode {
+            Mode::Event => tcp_event_server::server(&opts.address, opts.framing, &opts.net),
+            _ => echo_server(&opts.address, opts.framing, &opts.net, &opts.shutdown),
+        },
+        (Protocol::Tcp, Role::Client) if opts.script.enabled() => {
+            script::tcp(&opts.address, opts.framing, &opts.script, &opts.net)
+        }
+        #[cfg(feature = "async")]
+        (Protocol::Tcp, Role::Client) if opts.mode == Mode::Async => {
+            async_tcp_client::connect(&opts.address, opts.framing, &opts.net)
         }
         (Protocol::Tcp, Role::Client) => {
-            tcp_client::connect(&opts.address)
+            tcp_client::connect(&opts.address, opts.framing, &opts.net)
+        }
+        (Protocol::Tcp, Role::Connect) => netcat::tcp_connect(&opts.address, &opts.netcat, &opts.net),
+        (Protocol::Tcp, Role::Listen) => netcat::tcp_listen(&opts.address, &opts.netcat, &opts.net),
+        (Protocol::Tcp, Role::TlsServer) => tls::server(&opts.address, opts.framing, &opts.tls, &opts.net),
+        (Protocol::Tcp, Role::TlsClient) => tls::connect(&opts.address, opts.framing, &opts.tls, &opts.net),
+        (Protocol::Tcp, Role::Bench) => bench::tcp(&opts.address, opts.framing, &opts.bench, &opts.net),
+        (Protocol::Tcp, Role::Socks5) => socks5::server(&opts.address, &opts.socks5, &opts.net),
+        (Protocol::Tcp, Role::Forward) => match &opts.upstream {
+            Some(upstream) => forward::server(&opts.address, upstream, &opts.net),
+            None => Err(anyhow!("The forward role requires --upstream")),
+        },
+        (Protocol::Tcp, Role::Impair) => match &opts.upstream {
+            Some(upstream) => impair::tcp(&opts.address, upstream, &opts.impair, &opts.net),
+            None => Err(anyhow!("The impair role requires --upstream")),
+        },
+        (Protocol::Tcp, Role::SendFile) => file_transfer::send(&opts.address, &opts.file_transfer, &opts.net),
+        (Protocol::Tcp, Role::RecvFile) => file_transfer::server(&opts.address, &opts.file_transfer, &opts.net),
+        (Protocol::Tcp, Role::Chat) => chat::server(&opts.address, &opts.net),
+        (Protocol::Tcp, Role::Kv) => kv::server(&opts.address, &opts.kv, &opts.net, &opts.shutdown),
+        (Protocol::Tcp, Role::Scan) => scan::tcp(&opts.address, &opts.scan, &opts.net),
+        #[cfg(feature = "async")]
+        (Protocol::Udp, Role::Server) if opts.mode == Mode::Async => {
+            async_udp_server::server(&opts.address, &opts.multicast, &opts.net)
         }
         (Protocol::Udp, Role::Server) => {
-            udp_server::server(&opts.address)
+            udp_server::server(&opts.address, &opts.multicast, &opts.net)
+        }
+        (Protocol::Udp, Role::Client) if opts.script.enabled() => {
+            script::udp(&opts.address, &opts.script, &opts.net)
+        }
+        #[cfg(feature = "async")]
+        (Protocol::Udp, Role::Client) if opts.mode == Mode::Async => {
+            async_udp_client::communicate(&opts.address, &opts.multicast, &opts.net)
         }
         (Protocol::Udp, Role::Client) => {
-            udp_client::communicate(&opts.address)
+            udp_client::communicate(&opts.address, &opts.multicast, &opts.net)
         }
+        (Protocol::Udp, Role::Connect) => netcat::udp_connect(&opts.address, &opts.netcat, &opts.net),
+        (Protocol::Udp, Role::Listen) => netcat::udp_listen(&opts.address, &opts.netcat, &opts.net),
+        (Protocol::Udp, Role::Scan) => scan::udp(&opts.address, &opts.scan, &opts.net),
+        (Protocol::Udp, Role::Impair) => match &opts.upstream {
+            Some(upstream) => impair::udp(&opts.address, upstream, &opts.impair, &opts.net),
+            None => Err(anyhow!("The impair role requires --upstream")),
+        },
+        (Protocol::Udp, Role::TftpServer) => tftp::server(&opts.address, &opts.tftp, &opts.net),
+        (Protocol::Udp, Role::TftpClient) => tftp::client(&opts.address, &opts.tftp, &opts.net),
+        (Protocol::Udp, Role::Bench) => bench::udp(&opts.address, &opts.bench, &opts.net),
+        (Protocol::Rudp, Role::Server) => rudp::server(&opts.address, &opts.rudp, &opts.net),
+        (Protocol::Rudp, Role::Client) => rudp::communicate(&opts.address, &opts.rudp, &opts.net),
+        (Protocol::Unix, Role::Server) => unix_server::server(&opts.address, opts.framing),
+        (Protocol::Unix, Role::Client) => unix_client::connect(&opts.address, opts.framing),
+        (Protocol::UnixDgram, Role::Server) => unix_server::dgram_server(&opts.address),
+        (Protocol::UnixDgram, Role::Client) => unix_client::communicate(&opts.address),
+        (Protocol::Dns, Role::Server) => dns::server(&opts.address, &opts.dns, &opts.net),
+        (Protocol::Dns, Role::Client) => dns::client(&opts.address, &opts.dns, &opts.net),
+        (Protocol::Stun, Role::Server) => stun::server(&opts.address, &opts.net),
+        (Protocol::Stun, Role::Client) => stun::client(&opts.address, &opts.stun, &opts.net),
+        (Protocol::Icmp, Role::Ping) => icmp::ping(&opts.address, &opts.icmp, &opts.net),
+        (Protocol::Icmp, Role::Traceroute) => icmp::traceroute(&opts.address, &opts.icmp, &opts.net),
+        (protocol, role) => Err(anyhow!("{:?} does not support the {:?} role", protocol, role)),
     };
-    result.unwrap_or_else(|err| error!("{}", err));
+    if let Err(err) = result {
+        error!("{:#}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,276 @@
+use crate::net::NetOpts;
+use anyhow::anyhow;
+use clap::Clap;
+use log::{debug, error};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+
+#[derive(Clap, Debug)]
+pub struct MulticastOpts {
+    /// Multicast group the UDP server joins, listening on the port of `--host`.
+    #[clap(long)]
+    pub group: Option<IpAddr>,
+    /// Interface for multicast traffic: an IPv4 address, or an interface index for IPv6.
+    #[clap(long)]
+    pub multicast_if: Option<Interface>,
+    /// TTL (IPv4) or hop limit (IPv6) of multicast datagrams sent by the UDP client.
+    #[clap(long)]
+    pub multicast_ttl: Option<u32>,
+    /// Do not deliver our own multicast datagrams back to listeners on this host.
+    #[clap(long)]
+    pub no_multicast_loop: bool,
+    /// Allow the UDP client to send to a broadcast address.
+    #[clap(long)]
+    pub broadcast: bool,
+    /// How long the UDP client collects replies to a multicast or broadcast datagram, in milliseconds.
+    #[clap(long, default_value = "1000")]
+    pub wait: u64,
+}
+
+impl Default for MulticastOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Interface {
+    V4(Ipv4Addr),
+    V6(u32),
+}
+
+impl FromStr for Interface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(Interface::V4(addr));
+        }
+        s.parse()
+            .map(Interface::V6)
+            .map_err(|_| format!("{} is neither an IPv4 address nor an interface index", s))
+    }
+}
+
+impl MulticastOpts {
+    /// Binds the server socket, joining `--group` when given. The membership is dropped with the returned guard.
+    pub fn bind_server(
+        &self,
+        address: &str,
+        net: &NetOpts,
+    ) -> anyhow::Result<(UdpSocket, Option<Membership>)> {
+        let group = match self.group {
+            Some(group) if group.is_multicast() => group,
+            Some(group) => return Err(anyhow!("{} is not a multicast address", group)),
+            None => return Ok((net.bind_udp(address)?, None)),
+        };
+        let port = address
+            .rsplit(':')
+            .next()
+            .and_then(|port| port.parse().ok())
+            .ok_or_else(|| anyhow!("Cannot find a port in {}", address))?;
+
+        // Bind the wildcard address so that datagrams sent to the group are delivered,
+        // and share the port with other listeners of the same group on this host.
+        let bind_addr = match group {
+            IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+            IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+        };
+        let socket = Socket::new(
+            Domain::for_address(bind_addr),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        net.prepare(&socket, true)?;
+        if bind_addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&bind_addr.into())?;
+        let socket = UdpSocket::from(socket);
+        net.configure_udp(&socket)?;
+
+        let membership = Membership::join(socket.try_clone()?, group, self.multicast_if)?;
+        Ok((socket, Some(membership)))
+    }
+
+    /// Applies the sender-side options for a datagram addressed to `target`.
+    /// Returns whether more than one host may reply.
+    pub fn configure_client(
+        &self,
+        socket: &UdpSocket,
+        target: &SocketAddr,
+    ) -> anyhow::Result<bool> {
+        if self.broadcast {
+            socket.set_broadcast(true)?;
+        }
+        if !target.ip().is_multicast() {
+            return Ok(self.broadcast);
+        }
+
+        let sock = SockRef::from(socket);
+        match (target, self.multicast_if) {
+            (SocketAddr::V4(_), Some(Interface::V4(iface))) => sock.set_multicast_if_v4(&iface)?,
+            (SocketAddr::V6(_), Some(Interface::V6(index))) => sock.set_multicast_if_v6(index)?,
+            (_, Some(iface)) => {
+                return Err(anyhow!(
+                    "{:?} does not match the family of {}",
+                    iface,
+                    target
+                ))
+            }
+            (_, None) => {}
+        }
+        match target {
+            SocketAddr::V4(_) => {
+                if let Some(ttl) = self.multicast_ttl {
+                    socket.set_multicast_ttl_v4(ttl)?;
+                }
+                socket.set_multicast_loop_v4(!self.no_multicast_loop)?;
+            }
+            SocketAddr::V6(_) => {
+                if let Some(hops) = self.multicast_ttl {
+                    sock.set_multicast_hops_v6(hops)?;
+                }
+                socket.set_multicast_loop_v6(!self.no_multicast_loop)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Membership of a multicast group, left when dropped.
+pub struct Membership {
+    socket: UdpSocket,
+    group: IpAddr,
+    interface: Option<Interface>,
+}
+
+impl Membership {
+    fn join(
+        socket: UdpSocket,
+        group: IpAddr,
+        interface: Option<Interface>,
+    ) -> anyhow::Result<Self> {
+        match (group, interface) {
+            (IpAddr::V4(group), None) => {
+                socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?
+            }
+            (IpAddr::V4(group), Some(Interface::V4(iface))) => {
+                socket.join_multicast_v4(&group, &iface)?
+            }
+            (IpAddr::V6(group), None) => socket.join_multicast_v6(&group, 0)?,
+            (IpAddr::V6(group), Some(Interface::V6(index))) => {
+                socket.join_multicast_v6(&group, index)?
+            }
+            (_, Some(iface)) => {
+                return Err(anyhow!(
+                    "{:?} does not match the family of {}",
+                    iface,
+                    group
+                ))
+            }
+        }
+        debug!("Joined multicast group {}", group);
+        Ok(Membership {
+            socket,
+            group,
+            interface,
+        })
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let result = match (self.group, self.interface) {
+            (IpAddr::V4(group), Some(Interface::V4(iface))) => {
+                self.socket.leave_multicast_v4(&group, &iface)
+            }
+            (IpAddr::V4(group), _) => self
+                .socket
+                .leave_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED),
+            (IpAddr::V6(group), Some(Interface::V6(index))) => {
+                self.socket.leave_multicast_v6(&group, index)
+            }
+            (IpAddr::V6(group), _) => self.socket.leave_multicast_v6(&group, 0),
+        };
+        match result {
+            Ok(()) => debug!("Left multicast group {}", self.group),
+            Err(e) => error!("Failed to leave multicast group {}: {}", self.group, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+
+    fn free_port() -> u16 {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn interfaces_are_parsed() {
+        assert!(matches!("127.0.0.1".parse(), Ok(Interface::V4(ip)) if ip == Ipv4Addr::LOCALHOST));
+        assert!(matches!("3".parse(), Ok(Interface::V6(3))));
+        assert!("eth0".parse::<Interface>().is_err());
+    }
+
+    #[test]
+    fn only_multicast_groups_are_joined() {
+        let opts = MulticastOpts {
+            group: Some(Ipv4Addr::LOCALHOST.into()),
+            ..MulticastOpts::default()
+        };
+        let e = opts
+            .bind_server("0.0.0.0:0", &NetOpts::default())
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("not a multicast address"), "{}", e);
+    }
+
+    #[test]
+    fn a_joined_group_receives_datagrams() {
+        let port = free_port();
+        let opts = MulticastOpts {
+            group: Some(GROUP.into()),
+            multicast_if: Some(Interface::V4(Ipv4Addr::LOCALHOST)),
+            ..MulticastOpts::default()
+        };
+        let address = format!("0.0.0.0:{}", port);
+        let (server, membership) = match opts.bind_server(&address, &NetOpts::default()) {
+            Ok(bound) => bound,
+            Err(e) => {
+                eprintln!("Skipping: cannot join {} on loopback: {}", GROUP, e);
+                return;
+            }
+        };
+        assert!(membership.is_some());
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = SocketAddr::new(GROUP.into(), port);
+        assert!(opts.configure_client(&client, &target).unwrap());
+        if let Err(e) = client.send_to(b"to the group", target) {
+            eprintln!("Skipping: cannot send to {} on loopback: {}", GROUP, e);
+            return;
+        }
+        let mut buf = [0u8; 64];
+        match server.recv_from(&mut buf) {
+            Ok((size, src)) => {
+                assert_eq!(&buf[..size], b"to the group");
+                assert_eq!(src, client.local_addr().unwrap());
+            }
+            Err(e) => eprintln!("Skipping: no multicast delivery on loopback: {}", e),
+        }
+    }
+}
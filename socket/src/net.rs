@@ -0,0 +1,554 @@
+use clap::Clap;
+use log::debug;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::fmt::{Display, Write};
+use std::io::{self, ErrorKind};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+};
+use std::os::unix::io::AsFd;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Delay before racing the next address when connecting (RFC 8305 "Connection Attempt Delay").
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Clap, Debug, Clone, Default)]
+pub struct NetOpts {
+    /// Let a server on the wildcard address accept both IPv6 and IPv4 clients on one socket.
+    #[clap(long)]
+    pub dual_stack: bool,
+    /// Set IPV6_V6ONLY on IPv6 server sockets instead of using the OS default.
+    #[clap(long)]
+    pub v6_only: Option<bool>,
+    /// Disable Nagle's algorithm (TCP_NODELAY) on TCP connections.
+    #[clap(long)]
+    pub nodelay: bool,
+    /// Send TCP keepalive probes (SO_KEEPALIVE) on idle connections.
+    #[clap(long)]
+    pub keepalive: bool,
+    /// Seconds a connection is idle before the first keepalive probe. Implies `--keepalive`.
+    #[clap(long)]
+    pub keepalive_idle: Option<u64>,
+    /// Seconds between keepalive probes. Implies `--keepalive`.
+    #[clap(long)]
+    pub keepalive_interval: Option<u64>,
+    /// Unanswered keepalive probes before the connection is dropped. Implies `--keepalive`.
+    #[clap(long)]
+    pub keepalive_count: Option<u32>,
+    /// Receive buffer size (SO_RCVBUF) in bytes. The kernel may round or double it.
+    #[clap(long)]
+    pub recv_buffer: Option<usize>,
+    /// Send buffer size (SO_SNDBUF) in bytes. The kernel may round or double it.
+    #[clap(long)]
+    pub send_buffer: Option<usize>,
+    /// Set SO_REUSEADDR on server sockets. It is on by default for TCP servers only.
+    #[clap(long)]
+    pub reuse_addr: Option<bool>,
+    /// Set SO_REUSEPORT so that several servers can bind the same port.
+    #[clap(long)]
+    pub reuse_port: bool,
+    /// Seconds `close` waits for unsent TCP data (SO_LINGER). 0 resets the connection instead.
+    #[clap(long)]
+    pub linger: Option<u64>,
+    /// Fail reads that wait longer than this many milliseconds (SO_RCVTIMEO).
+    #[clap(long)]
+    pub read_timeout: Option<u64>,
+    /// Fail writes that wait longer than this many milliseconds (SO_SNDTIMEO).
+    #[clap(long)]
+    pub write_timeout: Option<u64>,
+}
+
+impl NetOpts {
+    fn v6_only(&self) -> Option<bool> {
+        match (self.v6_only, self.dual_stack) {
+            (Some(v6_only), _) => Some(v6_only),
+            (None, true) => Some(false),
+            (None, false) => None,
+        }
+    }
+
+    /// Resolves a server address. With `--dual-stack`, `0.0.0.0` is widened to `[::]`.
+    fn bind_addrs(&self, address: &str) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = resolve(address)?;
+        if self.dual_stack {
+            for addr in addrs.iter_mut() {
+                if addr.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED) {
+                    *addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), addr.port());
+                }
+            }
+            addrs.dedup();
+        }
+        Ok(addrs)
+    }
+
+    fn socket(&self, addr: &SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol))?;
+        if let (SocketAddr::V6(_), Some(v6_only)) = (addr, self.v6_only()) {
+            socket.set_only_v6(v6_only)?;
+        }
+        Ok(socket)
+    }
+
+    /// Applies the options that have to be in place before the socket binds or connects.
+    /// `reuse_addr` is the SO_REUSEADDR setting used unless `--reuse-addr` overrides it.
+    pub fn prepare(&self, socket: &Socket, reuse_addr: bool) -> io::Result<()> {
+        socket.set_reuse_address(self.reuse_addr.unwrap_or(reuse_addr))?;
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        // Buffer sizes must be known before the handshake for TCP to pick a matching window scale.
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the per-connection options to a connected or accepted TCP stream.
+    pub fn configure_tcp<S: AsFd>(&self, stream: &S) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        self.set_timeouts(&socket)?;
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(keepalive) = self.tcp_keepalive() {
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(secs) = self.linger {
+            socket.set_linger(Some(Duration::from_secs(secs)))?;
+        }
+        log_effective(&socket, "TCP connection", true)
+    }
+
+    /// Applies the timeouts to a bound UDP socket.
+    pub fn configure_udp(&self, socket: &UdpSocket) -> io::Result<()> {
+        let socket = SockRef::from(socket);
+        self.set_timeouts(&socket)?;
+        log_effective(&socket, "UDP socket", false)
+    }
+
+    fn set_timeouts(&self, socket: &SockRef) -> io::Result<()> {
+        if let Some(ms) = self.read_timeout {
+            socket.set_read_timeout(Some(Duration::from_millis(ms)))?;
+        }
+        if let Some(ms) = self.write_timeout {
+            socket.set_write_timeout(Some(Duration::from_millis(ms)))?;
+        }
+        Ok(())
+    }
+
+    fn tcp_keepalive(&self) -> Option<TcpKeepalive> {
+        if !self.keepalive
+            && self.keepalive_idle.is_none()
+            && self.keepalive_interval.is_none()
+            && self.keepalive_count.is_none()
+        {
+            return None;
+        }
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(secs) = self.keepalive_idle {
+            keepalive = keepalive.with_time(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.keepalive_interval {
+            keepalive = keepalive.with_interval(Duration::from_secs(secs));
+        }
+        if let Some(count) = self.keepalive_count {
+            keepalive = keepalive.with_retries(count);
+        }
+        Some(keepalive)
+    }
+
+    pub fn bind_tcp(&self, address: &str) -> io::Result<TcpListener> {
+        try_each(self.bind_addrs(address)?, |addr| {
+            let socket = self.socket(&addr, Type::STREAM, Protocol::TCP)?;
+            // Like `TcpListener::bind`, allow restarting a server while old connections linger in TIME_WAIT.
+            self.prepare(&socket, true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(128)?;
+            debug!("Listening on {}", addr);
+            log_effective(&SockRef::from(&socket), "Listener", false)?;
+            Ok(socket.into())
+        })
+    }
+
+    pub fn bind_udp(&self, address: &str) -> io::Result<UdpSocket> {
+        try_each(self.bind_addrs(address)?, |addr| {
+            let socket = self.socket(&addr, Type::DGRAM, Protocol::UDP)?;
+            self.prepare(&socket, false)?;
+            socket.bind(&addr.into())?;
+            debug!("Bound to {}", addr);
+            let socket = socket.into();
+            self.configure_udp(&socket)?;
+            Ok(socket)
+        })
+    }
+
+    /// Connects to the first address of `address` that answers, racing IPv6 and IPv4
+    /// candidates Happy Eyeballs style instead of waiting for each one to time out.
+    pub fn connect_tcp(&self, address: &str) -> io::Result<TcpStream> {
+        self.connect_any(interleave(resolve(address)?))
+    }
+
+    fn connect_any(&self, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let (tx, rx) = mpsc::channel();
+        let mut started = 0;
+        let mut failed = 0;
+        let mut last_error = None;
+
+        while failed < addrs.len() {
+            if started < addrs.len() {
+                let addr = addrs[started];
+                let tx = tx.clone();
+                let net = self.clone();
+                thread::spawn(move || {
+                    let _ = tx.send((addr, net.connect_addr(addr)));
+                });
+                started += 1;
+            }
+            // Give the attempt a head start, but move on at once if it fails.
+            let result = if started < addrs.len() {
+                match rx.recv_timeout(CONNECTION_ATTEMPT_DELAY) {
+                    Ok(result) => result,
+                    Err(_) => continue,
+                }
+            } else {
+                rx.recv().expect("connection attempts hold a sender")
+            };
+            match result {
+                (addr, Ok(stream)) => {
+                    debug!("Connected to {}", addr);
+                    self.configure_tcp(&stream)?;
+                    return Ok(stream);
+                }
+                (addr, Err(e)) => {
+                    debug!("Failed to connect to {}: {}", addr, e);
+                    failed += 1;
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("at least one address was tried"))
+    }
+
+    fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        self.prepare(&socket, false)?;
+        socket.connect(&addr.into())?;
+        Ok(socket.into())
+    }
+
+    /// Creates a UDP socket connected to the first address of `address` that accepts a route.
+    pub fn connect_udp(&self, address: &str) -> io::Result<UdpSocket> {
+        try_each(interleave(resolve(address)?), |addr| {
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+            self.prepare(&socket, false)?;
+            socket.bind(&unspecified(&addr).into())?;
+            socket.connect(&addr.into())?;
+            debug!("Sending to {}", addr);
+            let socket = socket.into();
+            self.configure_udp(&socket)?;
+            Ok(socket)
+        })
+    }
+}
+
+/// Logs the options in effect on `socket` as the kernel reports them back.
+fn log_effective(socket: &SockRef, what: impl Display, tcp: bool) -> io::Result<()> {
+    let mut line = format!(
+        "{} options: SO_RCVBUF={} SO_SNDBUF={} SO_REUSEADDR={} SO_REUSEPORT={} SO_RCVTIMEO={:?} SO_SNDTIMEO={:?}",
+        what,
+        socket.recv_buffer_size()?,
+        socket.send_buffer_size()?,
+        socket.reuse_address()?,
+        socket.reuse_port()?,
+        socket.read_timeout()?,
+        socket.write_timeout()?,
+    );
+    if tcp {
+        let _ = write!(
+            line,
+            " TCP_NODELAY={} SO_LINGER={:?} SO_KEEPALIVE={}",
+            socket.nodelay()?,
+            socket.linger()?,
+            socket.keepalive()?,
+        );
+        if socket.keepalive()? {
+            let _ = write!(
+                line,
+                " (idle {:?}, interval {:?}, count {})",
+                socket.keepalive_time()?,
+                socket.keepalive_interval()?,
+                socket.keepalive_retries()?,
+            );
+        }
+    }
+    debug!("{}", line);
+    Ok(())
+}
+
+/// Resolves `host:port`, where host may be a name, an IPv4 literal or a bracketed IPv6 literal.
+pub fn resolve(address: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("{} did not resolve to any address", address),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Strips the port (and the brackets of an IPv6 literal) from `host:port`. A bare IP address,
+/// such as `::1`, has no port to strip.
+pub fn host_of(address: &str) -> &str {
+    if address.parse::<IpAddr>().is_ok() {
+        return address;
+    }
+    let host = match address.rfind(':') {
+        Some(pos) if !address[..pos].is_empty() && !address[pos..].contains(']') => &address[..pos],
+        _ => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// The wildcard address with port 0 in the family of `addr`.
+pub fn unspecified(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+/// Orders addresses alternating between families, starting with the first family returned.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs.first().is_none_or(SocketAddr::is_ipv6);
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+    first.reverse();
+    second.reverse();
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+fn try_each<T, F>(addrs: Vec<SocketAddr>, mut f: F) -> io::Result<T>
+where
+    F: FnMut(SocketAddr) -> io::Result<T>,
+{
+    let mut last_error = None;
+    for addr in addrs {
+        match f(addr) {
+            Ok(t) => return Ok(t),
+            Err(e) => {
+                debug!("{}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "no address to use")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn has_ipv6() -> bool {
+        UdpSocket::bind("[::1]:0").is_ok()
+    }
+
+    #[test]
+    fn host_of_strips_the_port() {
+        for (address, host) in [
+            ("example.com:80", "example.com"),
+            ("127.0.0.1:80", "127.0.0.1"),
+            ("[::1]:80", "::1"),
+            ("[::1]", "::1"),
+            ("::1", "::1"),
+            ("fe80::1:2", "fe80::1:2"),
+            ("127.0.0.1", "127.0.0.1"),
+            ("example.com", "example.com"),
+        ] {
+            assert_eq!(host_of(address), host, "{}", address);
+        }
+    }
+
+    #[test]
+    fn interleave_alternates_families() {
+        let addrs: Vec<SocketAddr> = [
+            "[::1]:1",
+            "[::2]:1",
+            "[::3]:1",
+            "127.0.0.1:1",
+            "127.0.0.2:1",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let ordered: Vec<String> = interleave(addrs).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            ordered,
+            [
+                "[::1]:1",
+                "127.0.0.1:1",
+                "[::2]:1",
+                "127.0.0.2:1",
+                "[::3]:1"
+            ]
+        );
+    }
+
+    #[test]
+    fn options_are_applied_to_bound_sockets() {
+        let net = NetOpts {
+            reuse_port: true,
+            recv_buffer: Some(64 * 1024),
+            read_timeout: Some(1500),
+            ..NetOpts::default()
+        };
+        let listener = net.bind_tcp("127.0.0.1:0").unwrap();
+        let socket = SockRef::from(&listener);
+        assert!(socket.reuse_address().unwrap());
+        assert!(socket.reuse_port().unwrap());
+        // Linux doubles the size it is given.
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+
+        let udp = net.bind_udp("127.0.0.1:0").unwrap();
+        assert!(!SockRef::from(&udp).reuse_address().unwrap());
+        assert_eq!(
+            udp.read_timeout().unwrap(),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn options_are_applied_to_connections() {
+        let net = NetOpts {
+            nodelay: true,
+            keepalive_idle: Some(30),
+            linger: Some(0),
+            write_timeout: Some(2000),
+            ..NetOpts::default()
+        };
+        let listener = net.bind_tcp("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let stream = net.connect_tcp(&address).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(0)));
+        assert_eq!(
+            stream.write_timeout().unwrap(),
+            Some(Duration::from_secs(2))
+        );
+
+        let udp = net.connect_udp(&address).unwrap();
+        assert_eq!(udp.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    #[test]
+    fn dual_stack_servers_accept_ipv4_clients() {
+        if !has_ipv6() {
+            return;
+        }
+        let net = NetOpts {
+            dual_stack: true,
+            ..NetOpts::default()
+        };
+        let listener = net.bind_tcp("0.0.0.0:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.is_ipv6(), "{}", addr);
+        let address = format!("127.0.0.1:{}", addr.port());
+        NetOpts::default().connect_tcp(&address).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip().to_canonical(), Ipv4Addr::LOCALHOST);
+
+        let udp = net.bind_udp("0.0.0.0:0").unwrap();
+        assert!(udp.local_addr().unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn v6_only_servers_refuse_ipv4_clients() {
+        if !has_ipv6() {
+            return;
+        }
+        let net = NetOpts {
+            v6_only: Some(true),
+            ..NetOpts::default()
+        };
+        let listener = net.bind_tcp("[::]:0").unwrap();
+        let address = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        assert!(NetOpts::default().connect_tcp(&address).is_err());
+    }
+
+    /// A listener whose accept queue is full, so that the next connection attempt hangs.
+    fn unresponsive_listener() -> (Socket, Vec<Socket>) {
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        listener
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut filling = Vec::new();
+        for _ in 0..4 {
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            let _ = socket.connect(&addr);
+            filling.push(socket);
+        }
+        thread::sleep(Duration::from_millis(100));
+        (listener, filling)
+    }
+
+    #[test]
+    fn a_hanging_address_does_not_hold_up_the_next() {
+        let (hanging, _filling) = unresponsive_listener();
+        let hanging = hanging.local_addr().unwrap().as_socket().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+
+        let start = Instant::now();
+        let stream = NetOpts::default().connect_any(vec![hanging, good]).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        assert!(start.elapsed() >= CONNECTION_ATTEMPT_DELAY);
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn a_refused_address_moves_on_at_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let start = Instant::now();
+        let stream = NetOpts::default().connect_any(vec![refused, good]).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        assert!(
+            start.elapsed() < CONNECTION_ATTEMPT_DELAY,
+            "{:?}",
+            start.elapsed()
+        );
+
+        let e = NetOpts::default()
+            .connect_any(vec![refused, refused])
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::net::NetOpts;
+use crate::shutdown::POLL_INTERVAL;
+use crate::udp_server::MAX_UDP_PAYLOAD;
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::debug;
+use std::io::{self, ErrorKind, IsTerminal, Read, Write};
+use std::net::{Shutdown, TcpStream, UdpSocket};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How long a UDP zero-I/O check waits for an ICMP port unreachable.
+const UDP_PROBE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Clap, Debug, Default)]
+pub struct NetcatOpts {
+    /// Run this shell command with its stdin and stdout bound to the peer instead of ours.
+    #[clap(short = 'e', long)]
+    pub exec: Option<String>,
+    /// Only check that the peer accepts a connection, without sending any data.
+    #[clap(short = 'z', long)]
+    pub zero_io: bool,
+    /// Close the connection after this many seconds without data in either direction.
+    #[clap(short = 'w', long)]
+    pub idle_timeout: Option<u64>,
+}
+
+/// A connected peer that bytes are piped to and from.
+trait Peer: Sized + Send + 'static {
+    /// The most bytes one `send` may carry.
+    const MAX_SEND: usize;
+
+    /// Returns `None` once the peer will send nothing more.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+    fn send(&self, buf: &[u8]) -> io::Result<()>;
+    /// Tells the peer that we are done sending, where the protocol can express it.
+    fn close_write(&self);
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Peer for TcpStream {
+    const MAX_SEND: usize = CHUNK_SIZE;
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match (&mut &*self).read(buf)? {
+            0 => Ok(None),
+            nbytes => Ok(Some(nbytes)),
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        (&mut &*self).write_all(buf)
+    }
+
+    fn close_write(&self) {
+        if let Err(e) = self.shutdown(Shutdown::Write) {
+            debug!("shutdown: {}", e);
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+impl Peer for UdpSocket {
+    const MAX_SEND: usize = MAX_UDP_PAYLOAD;
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        UdpSocket::recv(self, buf).map(Some)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        UdpSocket::send(self, buf).map(|_| ())
+    }
+
+    fn close_write(&self) {}
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        UdpSocket::try_clone(self)
+    }
+}
+
+pub fn tcp_connect(address: &str, opts: &NetcatOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let stream = net.connect_tcp(address)?;
+    if opts.zero_io {
+        println!("Connection to {} succeeded", stream.peer_addr()?);
+        return Ok(());
+    }
+    pipe(stream, opts)
+}
+
+pub fn tcp_listen(address: &str, opts: &NetcatOpts, net: &NetOpts) -> anyhow::Result<()> {
+    check_listen(opts)?;
+    let listener = net.bind_tcp(address)?;
+    let (stream, remote_addr) = listener.accept()?;
+    debug!("Connection from {}", remote_addr);
+    net.configure_tcp(&stream)?;
+    pipe(stream, opts)
+}
+
+pub fn udp_connect(address: &str, opts: &NetcatOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let socket = net.connect_udp(address)?;
+    if opts.zero_io {
+        return probe_udp(&socket);
+    }
+    pipe(socket, opts)
+}
+
+pub fn udp_listen(address: &str, opts: &NetcatOpts, net: &NetOpts) -> anyhow::Result<()> {
+    check_listen(opts)?;
+    let socket = net.bind_udp(address)?;
+    // The sender of the first datagram becomes the only peer.
+    let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+    let (size, src) = socket.recv_from(&mut buf)?;
+    debug!("Datagrams from {}", src);
+    socket.connect(src)?;
+    pipe_with_first(socket, opts, &buf[..size])
+}
+
+fn check_listen(opts: &NetcatOpts) -> anyhow::Result<()> {
+    if opts.zero_io {
+        return Err(anyhow!("--zero-io only checks a remote port"));
+    }
+    Ok(())
+}
+
+/// UDP has no handshake, so a port counts as open unless it answers with ICMP port unreachable.
+fn probe_udp(socket: &UdpSocket) -> anyhow::Result<()> {
+    let peer = socket.peer_addr()?;
+    socket.send(&[])?;
+    socket.set_read_timeout(Some(UDP_PROBE_WAIT))?;
+    let mut buf = [0u8; 1];
+    match socket.recv(&mut buf) {
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            Err(anyhow!("{} refused the datagram", peer))
+        }
+        _ => {
+            println!("Connection to {} succeeded (udp: open or filtered)", peer);
+            Ok(())
+        }
+    }
+}
+
+fn pipe<P: Peer>(peer: P, opts: &NetcatOpts) -> anyhow::Result<()> {
+    pipe_with_first(peer, opts, &[])
+}
+
+/// Copies raw bytes between the peer and our stdio, or the stdio of `--exec`,
+/// until the peer closes the connection or it has been idle for `--idle-timeout`.
+/// `first` is data already received from the peer.
+fn pipe_with_first<P: Peer>(peer: P, opts: &NetcatOpts, first: &[u8]) -> anyhow::Result<()> {
+    let mut child = match &opts.exec {
+        Some(command) => Some(spawn(command)?),
+        None => None,
+    };
+    let (input, mut output): (Box<dyn Read + Send>, Box<dyn Write>) = match child.as_mut() {
+        Some(child) => (
+            Box::new(child.stdout.take().expect("stdout is piped")),
+            Box::new(child.stdin.take().expect("stdin is piped")),
+        ),
+        None => (Box::new(io::stdin()), Box::new(io::stdout())),
+    };
+    if !first.is_empty() {
+        output.write_all(first)?;
+        output.flush()?;
+    }
+
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+    let sender = peer.try_clone()?;
+    let sender_activity = last_activity.clone();
+    let (sent_tx, sent_rx) = mpsc::channel();
+    // Never joined: a blocking read from our stdin cannot be interrupted, and exiting ends it anyway.
+    thread::spawn(move || {
+        let result = send_all(input, &sender, &sender_activity);
+        sender.close_write();
+        let _ = sent_tx.send(result);
+    });
+
+    let idle_timeout = opts.idle_timeout.map(Duration::from_secs);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut sent = false;
+    let mut timed_out = loop {
+        // A UDP peer may never send again, so a failed send is only noticed by polling.
+        match sent_rx.try_recv() {
+            Ok(Err(e)) => return Err(e),
+            Ok(Ok(())) => sent = true,
+            Err(_) => {}
+        }
+        let mut read_timeout = if sent { None } else { Some(POLL_INTERVAL) };
+        if let Some(idle_timeout) = idle_timeout {
+            let idle = last_activity.lock().unwrap().elapsed();
+            match idle_timeout.checked_sub(idle) {
+                Some(remaining) if remaining > Duration::from_secs(0) => {
+                    read_timeout = Some(read_timeout.map_or(remaining, |t| t.min(remaining)))
+                }
+                _ => break true,
+            }
+        }
+        peer.set_read_timeout(read_timeout)?;
+        match peer.recv(&mut buf) {
+            Ok(Some(nbytes)) => {
+                *last_activity.lock().unwrap() = Instant::now();
+                output.write_all(&buf[..nbytes])?;
+                output.flush()?;
+            }
+            Ok(None) => break false,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    };
+    // Closing the command's stdin tells it that the peer is gone.
+    drop(output);
+    // Let our side finish sending too, unless it is typed on a terminal that may never be closed.
+    if !timed_out && !sent && (child.is_some() || !io::stdin().is_terminal()) {
+        match wait_sent(&sent_rx, &last_activity, idle_timeout) {
+            Some(result) => result?,
+            None => timed_out = true,
+        }
+    }
+    if timed_out {
+        debug!("Idle for {:?}, closing", idle_timeout.unwrap_or_default());
+    }
+
+    if let Some(mut child) = child {
+        if timed_out {
+            let _ = child.kill();
+        }
+        let status = child.wait()?;
+        debug!("{:?} exited with {}", opts.exec, status);
+    }
+    Ok(())
+}
+
+/// Waits until everything has been sent and returns how that went, or `None` if the connection
+/// went idle first.
+fn wait_sent(
+    sent: &Receiver<anyhow::Result<()>>,
+    last_activity: &Mutex<Instant>,
+    idle_timeout: Option<Duration>,
+) -> Option<anyhow::Result<()>> {
+    loop {
+        let idle_timeout = match idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return Some(sent.recv().unwrap_or(Ok(()))),
+        };
+        let idle = last_activity.lock().unwrap().elapsed();
+        let remaining = match idle_timeout.checked_sub(idle) {
+            Some(remaining) if remaining > Duration::from_secs(0) => remaining,
+            _ => return None,
+        };
+        match sent.recv_timeout(remaining) {
+            Ok(result) => return Some(result),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Some(Ok(())),
+        }
+    }
+}
+
+/// Sends our input to the peer, at most `P::MAX_SEND` bytes at a time so that every read fits
+/// in one datagram.
+fn send_all<P: Peer>(
+    mut input: Box<dyn Read + Send>,
+    peer: &P,
+    last_activity: &Mutex<Instant>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; P::MAX_SEND];
+    loop {
+        let nbytes = match input.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(nbytes) => nbytes,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Cannot read the input"),
+        };
+        peer.send(&buf[..nbytes])
+            .context("Cannot send to the peer")?;
+        *last_activity.lock().unwrap() = Instant::now();
+    }
+}
+
+fn spawn(command: &str) -> anyhow::Result<Child> {
+    debug!("Running {:?}", command);
+    Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", command))
+}
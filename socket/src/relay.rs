@@ -0,0 +1,30 @@
+use log::debug;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+
+/// Copies bytes between two connections in both directions until both sides are done.
+/// When one side stops sending, only the write half towards the other side is closed,
+/// so half-closed connections keep working. Returns the bytes copied `(a -> b, b -> a)`.
+pub fn relay(a: TcpStream, b: TcpStream) -> io::Result<(u64, u64)> {
+    let a_reader = a.try_clone()?;
+    let b_writer = b.try_clone()?;
+    let upstream = thread::spawn(move || copy_half(a_reader, b_writer));
+    let downstream = copy_half(b, a);
+    let upstream = upstream
+        .join()
+        .map_err(|_| io::Error::other("relay thread panicked"))?;
+    Ok((upstream?, downstream?))
+}
+
+fn copy_half(mut from: TcpStream, mut to: TcpStream) -> io::Result<u64> {
+    let result = io::copy(&mut from, &mut to);
+    // Forward the EOF (or give up on the direction after an error).
+    if let Err(e) = to.shutdown(Shutdown::Write) {
+        debug!("shutdown: {}", e);
+    }
+    if result.is_err() {
+        let _ = from.shutdown(Shutdown::Read);
+    }
+    result
+}
@@ -0,0 +1,571 @@
+use crate::net::NetOpts;
+use anyhow::anyhow;
+use clap::Clap;
+use log::{debug, error};
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const HEADER_LEN: usize = 13;
+const MAX_PAYLOAD: usize = 1200;
+/// Number of unacknowledged packets in flight per peer.
+const WINDOW: usize = 32;
+const INITIAL_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(5);
+/// Consecutive timeouts without any progress after which the peer is considered gone.
+const MAX_RETRIES: u32 = 8;
+/// How long the server keeps the state of a peer it has not heard from.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the client waits for the echo of a message before giving up.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clap, Debug)]
+pub struct RudpOpts {
+    /// Probability in [0, 1] of silently dropping each outgoing reliable-UDP packet, to emulate loss.
+    #[clap(long, default_value = "0")]
+    pub loss: f64,
+}
+
+impl Default for RudpOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+/// A packet on the wire: a 1-byte kind, then the 4-byte big-endian stream ID, sequence number
+/// and base, then the payload.
+///
+/// Every session picks a random stream ID for the data it sends, so that a receiver notices when
+/// the sender has started over, and a sender can tell ACKs for its own data from stale ones.
+/// `base` is the oldest sequence number the sender still has unacknowledged; a receiver meeting
+/// a new stream starts expecting from there, so that it can join a stream it lost track of.
+/// For `ACK` the stream ID is that of the acknowledged stream, the sequence number is cumulative
+/// (the next sequence number the receiver expects) and `base` is unused.
+#[derive(Debug)]
+struct Packet {
+    kind: u8,
+    stream: u32,
+    seq: u32,
+    base: u32,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn parse(buf: &[u8]) -> Option<Packet> {
+        if buf.len() < HEADER_LEN || (buf[0] != DATA && buf[0] != ACK) {
+            return None;
+        }
+        let word = |at: usize| u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        Some(Packet {
+            kind: buf[0],
+            stream: word(1),
+            seq: word(5),
+            base: word(9),
+            payload: buf[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push(self.kind);
+        buf.extend_from_slice(&self.stream.to_be_bytes());
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.base.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+/// Reliability state towards one peer, independent of any socket.
+struct Session {
+    /// Stream ID of the data this session sends.
+    stream: u32,
+    /// Stream ID of the data the peer sends, once it has sent any.
+    peer_stream: Option<u32>,
+    /// Sequence number given to the next queued message.
+    next_seq: u32,
+    /// Messages sent but not acknowledged yet, oldest first.
+    unacked: VecDeque<(u32, Vec<u8>)>,
+    /// Messages waiting for room in the window.
+    pending: VecDeque<Vec<u8>>,
+    /// Next sequence number expected from the peer.
+    expected: u32,
+    rto: Duration,
+    retransmit_at: Option<Instant>,
+    retries: u32,
+    last_heard: Instant,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            stream: rand::random(),
+            peer_stream: None,
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            pending: VecDeque::new(),
+            expected: 0,
+            rto: INITIAL_RTO,
+            retransmit_at: None,
+            retries: 0,
+            last_heard: Instant::now(),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.unacked.is_empty() && self.pending.is_empty()
+    }
+
+    /// Queues a message and returns the packets that may be sent right away.
+    fn send(&mut self, payload: Vec<u8>) -> Vec<Packet> {
+        self.pending.push_back(payload);
+        self.fill_window()
+    }
+
+    fn fill_window(&mut self) -> Vec<Packet> {
+        let mut out = Vec::new();
+        while self.unacked.len() < WINDOW {
+            let payload = match self.pending.pop_front() {
+                Some(payload) => payload,
+                None => break,
+            };
+            let seq = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+            self.unacked.push_back((seq, payload.clone()));
+            out.push(self.data(seq, payload));
+        }
+        if !self.unacked.is_empty() && self.retransmit_at.is_none() {
+            self.retransmit_at = Some(Instant::now() + self.rto);
+        }
+        out
+    }
+
+    fn data(&self, seq: u32, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind: DATA,
+            stream: self.stream,
+            seq,
+            base: self.unacked.front().map_or(seq, |(base, _)| *base),
+            payload,
+        }
+    }
+
+    /// Handles an incoming packet.
+    /// Returns the messages delivered in order and the packets to send in response.
+    fn receive(&mut self, packet: Packet) -> (Vec<Vec<u8>>, Vec<Packet>) {
+        self.last_heard = Instant::now();
+        match packet.kind {
+            DATA => {
+                // The peer is new, or started over after forgetting us: join its stream.
+                if self.peer_stream != Some(packet.stream) {
+                    debug!(
+                        "Joining stream {:08x} at seq {}",
+                        packet.stream, packet.base
+                    );
+                    self.peer_stream = Some(packet.stream);
+                    self.expected = packet.base;
+                }
+                let mut delivered = Vec::new();
+                if packet.seq == self.expected {
+                    self.expected = self.expected.wrapping_add(1);
+                    delivered.push(packet.payload);
+                } else {
+                    // A duplicate of something already delivered, or a packet after a gap that
+                    // the sender will retransmit anyway. Either way, re-acknowledge and drop it.
+                    debug!(
+                        "Dropping out-of-order packet seq {}, expected {}",
+                        packet.seq, self.expected
+                    );
+                }
+                let ack = Packet {
+                    kind: ACK,
+                    stream: packet.stream,
+                    seq: self.expected,
+                    base: 0,
+                    payload: Vec::new(),
+                };
+                (delivered, vec![ack])
+            }
+            _ if packet.stream != self.stream => {
+                debug!("Ignoring ACK for stream {:08x}", packet.stream);
+                (Vec::new(), Vec::new())
+            }
+            _ => {
+                let mut progressed = false;
+                while let Some((seq, _)) = self.unacked.front() {
+                    // Wrapping comparison: `seq` is acknowledged if it lies before the cumulative ACK.
+                    if packet.seq.wrapping_sub(*seq).wrapping_sub(1) < WINDOW as u32 {
+                        self.unacked.pop_front();
+                        progressed = true;
+                    } else {
+                        break;
+                    }
+                }
+                if progressed {
+                    self.rto = INITIAL_RTO;
+                    self.retries = 0;
+                    self.retransmit_at = None;
+                }
+                (Vec::new(), self.fill_window())
+            }
+        }
+    }
+
+    /// Retransmits the window with exponential backoff once the timer expires.
+    fn on_timer(&mut self, now: Instant) -> io::Result<Vec<Packet>> {
+        match self.retransmit_at {
+            Some(at) if at <= now => {}
+            _ => return Ok(Vec::new()),
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "peer stopped acknowledging",
+            ));
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.retransmit_at = Some(now + self.rto);
+        debug!(
+            "Retransmitting {} packets, next timeout {:?}",
+            self.unacked.len(),
+            self.rto
+        );
+        Ok(self
+            .unacked
+            .iter()
+            .map(|(seq, payload)| self.data(*seq, payload.clone()))
+            .collect())
+    }
+}
+
+/// A `UdpSocket` that drops outgoing packets at random when loss injection is enabled.
+struct LossySocket {
+    socket: UdpSocket,
+    loss: f64,
+}
+
+impl LossySocket {
+    fn send_to(&self, packet: &Packet, addr: SocketAddr) -> io::Result<()> {
+        if self.loss > 0.0 && rand::thread_rng().gen_bool(self.loss) {
+            debug!("Injected loss of kind {} seq {}", packet.kind, packet.seq);
+            return Ok(());
+        }
+        self.socket.send_to(&packet.to_bytes(), addr)?;
+        Ok(())
+    }
+
+    /// Waits for a packet until `deadline`. Returns `None` on timeout.
+    fn recv_until(&self, deadline: Option<Instant>) -> io::Result<Option<(Packet, SocketAddr)>> {
+        let timeout = deadline.map(|deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1))
+        });
+        self.socket.set_read_timeout(timeout)?;
+        let mut buf = [0u8; HEADER_LEN + MAX_PAYLOAD];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, src)) => match Packet::parse(&buf[..size]) {
+                    Some(packet) => return Ok(Some((packet, src))),
+                    None => debug!("Ignoring malformed packet from {}", src),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn check_loss(loss: f64) -> anyhow::Result<()> {
+    if !(0.0..=1.0).contains(&loss) {
+        return Err(anyhow!("--loss must be between 0 and 1"));
+    }
+    Ok(())
+}
+
+pub fn server(address: &str, opts: &RudpOpts, net: &NetOpts) -> anyhow::Result<()> {
+    check_loss(opts.loss)?;
+    let socket = LossySocket {
+        socket: net.bind_udp(address)?,
+        loss: opts.loss,
+    };
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+
+    loop {
+        let deadline = sessions.values().filter_map(|s| s.retransmit_at).min();
+        let deadline = Some(deadline.unwrap_or_else(|| Instant::now() + IDLE_TIMEOUT));
+
+        if let Some((packet, src)) = socket.recv_until(deadline)? {
+            let session = sessions.entry(src).or_insert_with(|| {
+                debug!("New reliable session with {}", src);
+                Session::new()
+            });
+            let (delivered, mut out) = session.receive(packet);
+            for msg in delivered {
+                print!("{}", String::from_utf8_lossy(&msg));
+                out.extend(session.send(msg));
+            }
+            for packet in &out {
+                if let Err(e) = socket.send_to(packet, src) {
+                    error!("{}: {}", src, e);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        sessions.retain(|addr, session| match session.on_timer(now) {
+            Ok(out) => {
+                for packet in &out {
+                    if let Err(e) = socket.send_to(packet, *addr) {
+                        error!("{}: {}", addr, e);
+                    }
+                }
+                !(session.is_idle() && now.duration_since(session.last_heard) > IDLE_TIMEOUT)
+            }
+            Err(e) => {
+                error!("Dropping session with {}: {}", addr, e);
+                false
+            }
+        });
+    }
+}
+
+pub fn communicate(address: &str, opts: &RudpOpts, net: &NetOpts) -> anyhow::Result<()> {
+    check_loss(opts.loss)?;
+    let socket = net.connect_udp(address)?;
+    let target = socket.peer_addr()?;
+    let socket = LossySocket {
+        socket,
+        loss: opts.loss,
+    };
+    let mut session = Session::new();
+
+    loop {
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+        if input.len() > MAX_PAYLOAD {
+            error!("Message longer than {} bytes is not sent", MAX_PAYLOAD);
+            continue;
+        }
+        for packet in session.send(input.into_bytes()) {
+            socket.send_to(&packet, target)?;
+        }
+
+        // Wait for the echo while acknowledging and retransmitting as needed.
+        // Once our message is acknowledged the timer stops, and the server retransmits the echo itself.
+        let give_up = Instant::now() + REPLY_TIMEOUT;
+        let mut replied = false;
+        while !replied || !session.is_idle() {
+            let deadline = session.retransmit_at.map_or(give_up, |at| at.min(give_up));
+            if let Some((packet, src)) = socket.recv_until(Some(deadline))? {
+                if src != target {
+                    continue;
+                }
+                let (delivered, out) = session.receive(packet);
+                for packet in &out {
+                    socket.send_to(packet, target)?;
+                }
+                for msg in delivered {
+                    print!("{}", String::from_utf8_lossy(&msg));
+                    replied = true;
+                }
+            }
+            for packet in session.on_timer(Instant::now())? {
+                socket.send_to(&packet, target)?;
+            }
+            if !replied && Instant::now() >= give_up {
+                return Err(anyhow!("No reply from {}", target));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack(session: &Session, seq: u32) -> Packet {
+        Packet {
+            kind: ACK,
+            stream: session.stream,
+            seq,
+            base: 0,
+            payload: Vec::new(),
+        }
+    }
+
+    fn seqs(packets: &[Packet]) -> Vec<u32> {
+        packets.iter().map(|packet| packet.seq).collect()
+    }
+
+    #[test]
+    fn packets_survive_the_wire() {
+        let packet = Packet {
+            kind: DATA,
+            stream: 0xdeadbeef,
+            seq: 7,
+            base: 3,
+            payload: b"hi".to_vec(),
+        };
+        let parsed = Packet::parse(&packet.to_bytes()).unwrap();
+        assert_eq!(
+            (parsed.kind, parsed.stream, parsed.seq, parsed.base),
+            (DATA, 0xdeadbeef, 7, 3)
+        );
+        assert_eq!(parsed.payload, b"hi");
+        assert!(Packet::parse(&[DATA; HEADER_LEN - 1]).is_none());
+        assert!(Packet::parse(&[2; HEADER_LEN]).is_none());
+    }
+
+    #[test]
+    fn the_window_limits_packets_in_flight() {
+        let mut session = Session::new();
+        let mut sent = Vec::new();
+        for i in 0..WINDOW + 8 {
+            sent.extend(session.send(vec![i as u8]));
+        }
+        assert_eq!(seqs(&sent), (0..WINDOW as u32).collect::<Vec<_>>());
+        assert!(sent.iter().all(|packet| packet.base == 0));
+
+        // Acknowledging five packets makes room for five more.
+        let (_, out) = session.receive(ack(&session, 5));
+        assert_eq!(
+            seqs(&out),
+            (WINDOW as u32..WINDOW as u32 + 5).collect::<Vec<_>>()
+        );
+        assert!(out.iter().all(|packet| packet.base == 5));
+        assert_eq!(session.unacked.len(), WINDOW);
+        assert_eq!(session.pending.len(), 3);
+
+        let (_, out) = session.receive(ack(&session, WINDOW as u32 + 5));
+        assert_eq!(out.len(), 3);
+        session.receive(ack(&session, WINDOW as u32 + 8));
+        assert!(session.is_idle());
+        assert_eq!(session.retransmit_at, None);
+    }
+
+    #[test]
+    fn acks_for_another_stream_are_ignored() {
+        let mut session = Session::new();
+        session.send(b"a".to_vec());
+        let mut stale = ack(&session, 1);
+        stale.stream = session.stream.wrapping_add(1);
+        session.receive(stale);
+        assert_eq!(session.unacked.len(), 1);
+    }
+
+    #[test]
+    fn the_window_is_retransmitted_once_the_timer_expires() {
+        let mut session = Session::new();
+        session.send(b"a".to_vec());
+        session.send(b"b".to_vec());
+        let at = session.retransmit_at.unwrap();
+        assert!(session
+            .on_timer(at - Duration::from_millis(1))
+            .unwrap()
+            .is_empty());
+
+        let out = session.on_timer(at).unwrap();
+        assert_eq!(seqs(&out), [0, 1]);
+        assert_eq!(out[1].payload, b"b");
+
+        // Only what is still unacknowledged goes out again.
+        session.receive(ack(&session, 1));
+        session.send(b"c".to_vec());
+        let at = session.retransmit_at.unwrap();
+        assert_eq!(seqs(&session.on_timer(at).unwrap()), [1, 2]);
+    }
+
+    #[test]
+    fn the_timeout_backs_off_until_the_peer_is_given_up() {
+        let mut session = Session::new();
+        session.send(b"a".to_vec());
+        let mut rtos = Vec::new();
+        for _ in 0..MAX_RETRIES {
+            let at = session.retransmit_at.unwrap();
+            assert_eq!(session.on_timer(at).unwrap().len(), 1);
+            rtos.push(session.rto);
+            assert_eq!(session.retransmit_at, Some(at + session.rto));
+        }
+        assert_eq!(
+            &rtos[..4],
+            [400, 800, 1600, 3200].map(Duration::from_millis)
+        );
+        assert!(rtos[4..].iter().all(|rto| *rto == MAX_RTO));
+
+        let at = session.retransmit_at.unwrap();
+        let e = session.on_timer(at).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn progress_resets_the_timeout() {
+        let mut session = Session::new();
+        session.send(b"a".to_vec());
+        session.send(b"b".to_vec());
+        let at = session.retransmit_at.unwrap();
+        session.on_timer(at).unwrap();
+        assert_eq!(session.rto, INITIAL_RTO * 2);
+
+        session.receive(ack(&session, 1));
+        assert_eq!((session.rto, session.retries), (INITIAL_RTO, 0));
+    }
+
+    #[test]
+    fn data_is_delivered_in_order_and_always_acknowledged() {
+        let mut sender = Session::new();
+        let packets: Vec<Packet> = (0..3).flat_map(|i| sender.send(vec![b'0' + i])).collect();
+        let mut packets = packets.into_iter();
+        let (first, second, third) = (
+            packets.next().unwrap(),
+            packets.next().unwrap(),
+            packets.next().unwrap(),
+        );
+        let again = Packet::parse(&first.to_bytes()).unwrap();
+
+        let mut receiver = Session::new();
+        let (delivered, out) = receiver.receive(first);
+        assert_eq!(delivered, [b"0".to_vec()]);
+        assert_eq!(
+            (out[0].kind, out[0].stream, out[0].seq),
+            (ACK, sender.stream, 1)
+        );
+
+        // After a gap, nothing is delivered and the ACK still asks for the missing packet.
+        let (delivered, out) = receiver.receive(third);
+        assert!(delivered.is_empty());
+        assert_eq!(out[0].seq, 1);
+
+        let (delivered, out) = receiver.receive(again);
+        assert!(delivered.is_empty());
+        assert_eq!(out[0].seq, 1);
+
+        let (delivered, out) = receiver.receive(second);
+        assert_eq!(delivered, [b"1".to_vec()]);
+        assert_eq!(out[0].seq, 2);
+    }
+
+    #[test]
+    fn a_new_stream_is_joined_at_its_base() {
+        let mut receiver = Session::new();
+        let packet = Packet {
+            kind: DATA,
+            stream: 42,
+            seq: 10,
+            base: 9,
+            payload: b"late".to_vec(),
+        };
+        let (delivered, out) = receiver.receive(packet);
+        assert!(delivered.is_empty());
+        assert_eq!(out[0].seq, 9);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::net::{self, NetOpts};
+use anyhow::anyhow;
+use clap::Clap;
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clap, Debug)]
+pub struct ScanOpts {
+    /// Ports the scan role probes, as a list of ports and ranges such as `22,80,8000-8100`.
+    #[clap(long, default_value = "1-1024")]
+    pub ports: Ports,
+    /// How many ports the scan role probes at the same time.
+    #[clap(long, default_value = "100")]
+    pub parallelism: usize,
+    /// How long the scan role waits for each port to answer, in milliseconds. It takes the place of
+    /// `--read-timeout`.
+    #[clap(long, default_value = "1000")]
+    pub port_timeout: u64,
+    /// Also list closed ports instead of only counting them.
+    #[clap(long)]
+    pub show_closed: bool,
+}
+
+impl Default for ScanOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+/// A sorted set of ports without duplicates.
+#[derive(Debug, Clone)]
+pub struct Ports(Vec<u16>);
+
+impl FromStr for Ports {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("{:?} is not a port number", port))
+        };
+        let mut ports = BTreeSet::new();
+        for part in s.split(',').filter(|part| !part.trim().is_empty()) {
+            match part.find('-') {
+                Some(pos) => {
+                    let (first, last) = (parse(&part[..pos])?, parse(&part[pos + 1..])?);
+                    if first > last {
+                        return Err(format!("{} is an empty range", part));
+                    }
+                    ports.extend(first..=last);
+                }
+                None => {
+                    ports.insert(parse(part)?);
+                }
+            }
+        }
+        if ports.is_empty() {
+            return Err("no ports to scan".to_string());
+        }
+        Ok(Ports(ports.into_iter().collect()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    Open,
+    Closed,
+    /// No answer at all, as when a firewall drops the probe.
+    Filtered,
+    /// A UDP port that neither answered nor reported ICMP port unreachable.
+    OpenOrFiltered,
+}
+
+impl fmt::Display for PortState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::OpenOrFiltered => "open|filtered",
+        })
+    }
+}
+
+pub fn tcp(address: &str, opts: &ScanOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let ip = target_ip(address)?;
+    let start = Instant::now();
+    let results = scan(ip, &opts.ports, opts.parallelism, |addr| {
+        probe_tcp(addr, Duration::from_millis(opts.port_timeout), net)
+    })?;
+    report(ip, "tcp", &results, start.elapsed(), opts.show_closed);
+    Ok(())
+}
+
+pub fn udp(address: &str, opts: &ScanOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let ip = target_ip(address)?;
+    let start = Instant::now();
+    let results = scan(ip, &opts.ports, opts.parallelism, |addr| {
+        probe_udp(addr, Duration::from_millis(opts.port_timeout), net)
+    })?;
+    report(ip, "udp", &results, start.elapsed(), opts.show_closed);
+    Ok(())
+}
+
+/// Runs `probe` for every port with up to `parallelism` probes in flight.
+/// Returns the results in port order.
+pub fn scan<F>(
+    ip: IpAddr,
+    ports: &Ports,
+    parallelism: usize,
+    probe: F,
+) -> anyhow::Result<Vec<(u16, PortState)>>
+where
+    F: Fn(SocketAddr) -> PortState + Sync,
+{
+    if parallelism == 0 {
+        return Err(anyhow!("--parallelism must be at least 1"));
+    }
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(ports.0.len()));
+    thread::scope(|scope| {
+        for _ in 0..parallelism.min(ports.0.len()) {
+            scope.spawn(|| {
+                while let Some(&port) = ports.0.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let state = probe(SocketAddr::new(ip, port));
+                    results.lock().unwrap().push((port, state));
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(port, _)| *port);
+    Ok(results)
+}
+
+pub fn probe_tcp(addr: SocketAddr, timeout: Duration, net: &NetOpts) -> PortState {
+    let result = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .and_then(|socket| {
+            net.prepare(&socket, false)?;
+            socket.connect_timeout(&addr.into(), timeout)?;
+            // With `--linger 0` the probe ends in a reset, as in a SYN scan.
+            net.configure_tcp(&socket)
+        });
+    match result {
+        Ok(()) => PortState::Open,
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => PortState::Closed,
+        Err(e) => {
+            if e.kind() != ErrorKind::TimedOut && e.kind() != ErrorKind::WouldBlock {
+                debug!("{}: {}", addr, e);
+            }
+            PortState::Filtered
+        }
+    }
+}
+
+/// Sends an empty datagram. On a connected socket the OS reports an ICMP port
+/// unreachable reply as a refused connection, which is the only way to tell a closed port.
+pub fn probe_udp(addr: SocketAddr, timeout: Duration, net: &NetOpts) -> PortState {
+    let result = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP)).and_then(
+        |socket| {
+            net.prepare(&socket, false)?;
+            socket.bind(&net::unspecified(&addr).into())?;
+            socket.connect(&addr.into())?;
+            Ok(UdpSocket::from(socket))
+        },
+    );
+    let result = result.and_then(|socket| {
+        socket.set_read_timeout(Some(timeout))?;
+        socket.send(&[])?;
+        socket.recv(&mut [0u8; 1])
+    });
+    match result {
+        Ok(_) => PortState::Open,
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => PortState::Closed,
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            PortState::OpenOrFiltered
+        }
+        Err(e) => {
+            debug!("{}: {}", addr, e);
+            PortState::Filtered
+        }
+    }
+}
+
+/// The host of `--host`, with or without a port. A bare IPv6 literal needs no brackets.
+pub fn target_ip(address: &str) -> anyhow::Result<IpAddr> {
+    if let Ok(ip) = address.parse() {
+        return Ok(ip);
+    }
+    let addr = match net::resolve(address) {
+        Ok(addrs) => addrs[0],
+        Err(_) => (net::host_of(address), 0)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("{} did not resolve to any address", address))?,
+    };
+    Ok(addr.ip())
+}
+
+fn report(
+    ip: IpAddr,
+    protocol: &str,
+    results: &[(u16, PortState)],
+    elapsed: Duration,
+    show_closed: bool,
+) {
+    println!("{:<10}STATE", "PORT");
+    let mut counts = [0usize; 4];
+    for (port, state) in results {
+        counts[*state as usize] += 1;
+        if *state != PortState::Closed || show_closed {
+            println!("{:<10}{}", format!("{}/{}", port, protocol), state);
+        }
+    }
+    println!(
+        "Scanned {} ports on {} in {:.2}s: {} open, {} closed, {} filtered, {} open|filtered",
+        results.len(),
+        ip,
+        elapsed.as_secs_f64(),
+        counts[PortState::Open as usize],
+        counts[PortState::Closed as usize],
+        counts[PortState::Filtered as usize],
+        counts[PortState::OpenOrFiltered as usize],
+    );
+}
@@ -0,0 +1,304 @@
+use crate::framing::Framing;
+use crate::net::NetOpts;
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::debug;
+use regex::Regex;
+use std::fs;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::{TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clap, Debug)]
+pub struct ScriptOpts {
+    /// Run the TCP or UDP client from a file of send/expect steps instead of stdin.
+    #[clap(long, parse(from_os_str))]
+    pub script: Option<PathBuf>,
+    /// How long an `expect` step waits for a message unless the script sets `timeout`, in milliseconds.
+    #[clap(long, default_value = "5000")]
+    pub expect_timeout: u64,
+}
+
+impl Default for ScriptOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+impl ScriptOpts {
+    pub fn enabled(&self) -> bool {
+        self.script.is_some()
+    }
+
+    /// Reads the script. Over `datagrams` there is no connection, so no step may wait for it to close.
+    fn load(&self, datagrams: bool) -> anyhow::Result<Script> {
+        let path = self.script.as_ref().context("No --script given")?;
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Script::parse(path, &text, datagrams)
+    }
+}
+
+/// One line of a script. Blank lines and lines starting with `#` are skipped.
+enum Step {
+    /// `send <text>`: sends the rest of the line as one message. Supports `\n`, `\r`, `\t`, `\\` and `\xNN`.
+    Send(Vec<u8>),
+    /// `expect <regex>`: receives the next message and fails unless the regex matches it.
+    Expect(Regex),
+    /// `expect-close`: fails unless the peer closes the connection.
+    ExpectClose,
+    /// `timeout <ms>`: how long the following `expect` steps wait.
+    Timeout(Duration),
+    /// `sleep <ms>`
+    Sleep(Duration),
+}
+
+struct Script {
+    path: PathBuf,
+    /// Steps with their line numbers.
+    steps: Vec<(usize, Step)>,
+}
+
+impl Script {
+    fn parse(path: &Path, text: &str, datagrams: bool) -> anyhow::Result<Self> {
+        let mut steps = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            let mut words = line.trim_start().splitn(2, ' ');
+            let keyword = words.next().unwrap_or("");
+            let arg = words.next().unwrap_or("");
+            let step = match keyword {
+                "send" => Ok(Step::Send(unescape(arg))),
+                "expect" => Regex::new(arg).map(Step::Expect).map_err(Into::into),
+                "expect-close" if datagrams => {
+                    Err(anyhow!("expect-close needs a connection, and UDP has none"))
+                }
+                "expect-close" => Ok(Step::ExpectClose),
+                "timeout" => millis(arg).map(Step::Timeout),
+                "sleep" => millis(arg).map(Step::Sleep),
+                _ => Err(anyhow!("Unknown step {:?}", keyword)),
+            }
+            .with_context(|| format!("{}:{}", path.display(), number))?;
+            steps.push((number, step));
+        }
+        Ok(Script {
+            path: path.to_path_buf(),
+            steps,
+        })
+    }
+
+    fn run<T: Transport>(&self, transport: &mut T, timeout: Duration) -> anyhow::Result<()> {
+        let mut timeout = timeout;
+        for (number, step) in &self.steps {
+            let at = || format!("{}:{}", self.path.display(), number);
+            match step {
+                Step::Send(msg) => {
+                    println!("> {}", String::from_utf8_lossy(msg));
+                    transport.send(msg).with_context(at)?;
+                }
+                Step::Expect(regex) => match recv(transport, timeout).with_context(at)? {
+                    Some(msg) => {
+                        let msg = String::from_utf8_lossy(&msg);
+                        println!("< {}", msg);
+                        if !regex.is_match(&msg) {
+                            return Err(anyhow!("{}: expected /{}/, got {:?}", at(), regex, msg));
+                        }
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "{}: expected /{}/, but the connection was closed",
+                            at(),
+                            regex
+                        ))
+                    }
+                },
+                Step::ExpectClose => {
+                    if let Some(msg) = recv(transport, timeout).with_context(at)? {
+                        return Err(anyhow!(
+                            "{}: expected the connection to close, got {:?}",
+                            at(),
+                            String::from_utf8_lossy(&msg)
+                        ));
+                    }
+                }
+                Step::Timeout(duration) => timeout = *duration,
+                Step::Sleep(duration) => thread::sleep(*duration),
+            }
+        }
+        println!(
+            "{}: all {} steps passed",
+            self.path.display(),
+            self.steps.len()
+        );
+        Ok(())
+    }
+}
+
+/// A connection to the server under test, carrying whole messages.
+trait Transport {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()>;
+    /// Waits up to `timeout` for the next message. `None` means the peer closed the connection.
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>>;
+}
+
+struct TcpTransport {
+    reader: BufReader<TcpStream>,
+    framing: Framing,
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.framing.write_frame(self.reader.get_mut(), msg)
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        self.reader.get_ref().set_read_timeout(Some(timeout))?;
+        self.framing.read_frame(&mut self.reader)
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        UdpSocket::send(self, msg).map(|_| ())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        self.set_read_timeout(Some(timeout))?;
+        let mut buf = vec![0u8; 65536];
+        let size = UdpSocket::recv(self, &mut buf)?;
+        buf.truncate(size);
+        Ok(Some(buf))
+    }
+}
+
+fn recv<T: Transport>(transport: &mut T, timeout: Duration) -> anyhow::Result<Option<Vec<u8>>> {
+    match transport.recv(timeout) {
+        Ok(msg) => Ok(msg),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            Err(anyhow!("no message within {:?}", timeout))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn tcp(
+    address: &str,
+    framing: Framing,
+    opts: &ScriptOpts,
+    net: &NetOpts,
+) -> anyhow::Result<()> {
+    let script = opts.load(false)?;
+    let stream = net.connect_tcp(address)?;
+    debug!("Running {} against {}", script.path.display(), address);
+    let mut transport = TcpTransport {
+        reader: BufReader::new(stream),
+        framing,
+    };
+    script.run(&mut transport, Duration::from_millis(opts.expect_timeout))
+}
+
+pub fn udp(address: &str, opts: &ScriptOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let script = opts.load(true)?;
+    let mut socket = net.connect_udp(address)?;
+    debug!("Running {} against {}", script.path.display(), address);
+    script.run(&mut socket, Duration::from_millis(opts.expect_timeout))
+}
+
+fn millis(arg: &str) -> anyhow::Result<Duration> {
+    let ms = arg
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("{:?} is not a number of milliseconds", arg))?;
+    Ok(Duration::from_millis(ms))
+}
+
+fn unescape(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) => bytes.push(byte),
+                    Err(_) => bytes.extend_from_slice(format!("\\x{}", hex).as_bytes()),
+                }
+            }
+            Some(c) => {
+                let mut buf = [0u8; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+            None => bytes.push(b'\\'),
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str, datagrams: bool) -> anyhow::Result<Script> {
+        Script::parse(Path::new("test.script"), text, datagrams)
+    }
+
+    #[test]
+    fn steps_are_parsed_with_their_line_numbers() {
+        let script = parse(
+            "# greeting\nsend hello\\tworld\n\ntimeout 250\nexpect ^hello\\s\nsleep 10\nexpect-close\n",
+            false,
+        )
+        .unwrap();
+        let numbers: Vec<usize> = script.steps.iter().map(|(number, _)| *number).collect();
+        assert_eq!(numbers, [2, 4, 5, 6, 7]);
+        assert!(matches!(&script.steps[0].1, Step::Send(msg) if msg == b"hello\tworld"));
+        assert!(matches!(script.steps[1].1, Step::Timeout(d) if d == Duration::from_millis(250)));
+        assert!(matches!(&script.steps[2].1, Step::Expect(regex) if regex.is_match("hello there")));
+        assert!(matches!(script.steps[3].1, Step::Sleep(d) if d == Duration::from_millis(10)));
+        assert!(matches!(script.steps[4].1, Step::ExpectClose));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        for (text, error) in [
+            ("send a\nfrobnicate\n", "test.script:2"),
+            ("expect (\n", "test.script:1"),
+            ("timeout soon\n", "not a number of milliseconds"),
+        ] {
+            let e = format!("{:#}", parse(text, false).err().unwrap());
+            assert!(e.contains(error), "{:?}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn expect_close_is_rejected_over_udp() {
+        let e = format!(
+            "{:#}",
+            parse("send hi\nexpect-close\n", true).err().unwrap()
+        );
+        assert!(e.contains("test.script:2"), "{}", e);
+        assert!(e.contains("UDP"), "{}", e);
+        assert!(parse("send hi\nexpect hi\n", true).is_ok());
+    }
+
+    #[test]
+    fn escapes_are_unescaped() {
+        assert_eq!(unescape(r"a\nb\r\t\\\x41\xzz"), b"a\nb\r\t\\A\\xzz");
+        assert_eq!(unescape(r"\q\"), b"q\\");
+        assert_eq!(unescape("caf\u{e9}"), "caf\u{e9}".as_bytes());
+    }
+}
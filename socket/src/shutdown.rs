@@ -0,0 +1,84 @@
+use clap::Clap;
+use log::debug;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often blocking loops wake up to check whether they should stop.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clap, Debug)]
+pub struct ShutdownOpts {
+    /// Seconds a stopping server waits for open connections before closing them.
+    #[clap(long, default_value = "5")]
+    pub drain_timeout: u64,
+}
+
+impl Default for ShutdownOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+impl ShutdownOpts {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+}
+
+/// A flag that tells a server to stop accepting and wind down.
+/// Clones share the flag, so one can be handed to the server and another kept to stop it.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle that is triggered by SIGINT or SIGTERM.
+    /// A second signal while the server is draining exits the process at once.
+    pub fn on_signals() -> io::Result<Self> {
+        let shutdown = Self::new();
+        for signal in [SIGINT, SIGTERM] {
+            // Registered first, so it sees the flag as it was before this signal set it.
+            flag::register_conditional_shutdown(signal, 1, Arc::clone(&shutdown.requested))?;
+            flag::register(signal, Arc::clone(&shutdown.requested))?;
+        }
+        Ok(shutdown)
+    }
+
+    /// Stops the server in-process, for example at the end of a test.
+    pub fn trigger(&self) {
+        debug!("Shutdown requested");
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// What an echo server did before it stopped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// Accepted connections, or datagrams for UDP.
+    pub connections: u64,
+    pub bytes: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "served {} connections, echoed {} bytes",
+            self.connections, self.bytes
+        )
+    }
+}
@@ -0,0 +1,590 @@
+use crate::net::NetOpts;
+use crate::relay;
+use crate::tcp_server;
+use anyhow::anyhow;
+use clap::Clap;
+use log::{debug, error, info};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// How often the UDP relay checks whether its control connection has gone away.
+const UDP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clap, Debug, Clone, Default)]
+pub struct Socks5Opts {
+    /// Require SOCKS5 username/password authentication with this user name.
+    #[clap(long, requires = "password")]
+    pub username: Option<String>,
+    /// Password for `--username`.
+    #[clap(long, requires = "username")]
+    pub password: Option<String>,
+}
+
+/// A destination as sent by the client, before name resolution.
+#[derive(Debug)]
+enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl TargetAddr {
+    fn connect(&self, net: &NetOpts) -> io::Result<TcpStream> {
+        net.connect_tcp(&self.to_string())
+    }
+
+    /// Resolves the target to an address a socket bound to `local` can send to.
+    fn resolve(&self, local: SocketAddr) -> io::Result<SocketAddr> {
+        let candidates: Vec<SocketAddr> = match self {
+            TargetAddr::Ip(addr) => vec![*addr],
+            TargetAddr::Domain(host, port) => (host.as_str(), *port).to_socket_addrs()?.collect(),
+        };
+        // An IPv6 socket bound to an IPv4-mapped address only reaches IPv4 hosts.
+        let mapped = match local.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().is_some(),
+            IpAddr::V4(_) => false,
+        };
+        candidates
+            .into_iter()
+            .find_map(|addr| match (local, addr) {
+                (SocketAddr::V4(_), SocketAddr::V4(_)) => Some(addr),
+                (SocketAddr::V6(_), SocketAddr::V6(_)) if !mapped => Some(addr),
+                (SocketAddr::V6(_), SocketAddr::V4(v4)) if mapped => Some(SocketAddr::new(
+                    IpAddr::V6(v4.ip().to_ipv6_mapped()),
+                    v4.port(),
+                )),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("no address of the relay's family for {}", self),
+                )
+            })
+    }
+}
+
+pub fn server(address: &str, opts: &Socks5Opts, net: &NetOpts) -> anyhow::Result<()> {
+    let opts = opts.clone();
+    let upstream_net = net.clone();
+    tcp_server::serve(address, net, move |stream| {
+        handler(stream, &opts, &upstream_net)
+    })
+}
+
+fn handler(mut stream: TcpStream, opts: &Socks5Opts, net: &NetOpts) -> anyhow::Result<()> {
+    negotiate_method(&mut stream, opts)?;
+
+    let mut header = [0u8; 3];
+    stream.read_exact(&mut header)?;
+    if header[0] != VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {}", header[0]));
+    }
+    let target = match read_addr(&mut stream) {
+        Ok(target) => target,
+        Err(e) => {
+            // A broken connection gets no reply, only a request we cannot use does.
+            let rep = match e.kind() {
+                ErrorKind::Unsupported => REP_ADDRESS_TYPE_NOT_SUPPORTED,
+                ErrorKind::InvalidData => REP_GENERAL_FAILURE,
+                _ => return Err(e.into()),
+            };
+            send_reply(&mut stream, rep, None)?;
+            return Err(e.into());
+        }
+    };
+
+    match header[1] {
+        CMD_CONNECT => connect(stream, target, net),
+        CMD_UDP_ASSOCIATE => udp_associate(stream, target),
+        cmd => {
+            send_reply(&mut stream, REP_COMMAND_NOT_SUPPORTED, None)?;
+            Err(anyhow!("Unsupported SOCKS command {}", cmd))
+        }
+    }
+}
+
+fn negotiate_method(stream: &mut TcpStream, opts: &Socks5Opts) -> anyhow::Result<()> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+    if header[0] != VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {}", header[0]));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods)?;
+
+    let required = match opts.username {
+        Some(_) => METHOD_USER_PASS,
+        None => METHOD_NO_AUTH,
+    };
+    if !methods.contains(&required) {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE])?;
+        return Err(anyhow!(
+            "Client offered no acceptable authentication method"
+        ));
+    }
+    stream.write_all(&[VERSION, required])?;
+
+    if required == METHOD_USER_PASS {
+        // RFC 1929
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version)?;
+        if version[0] != AUTH_VERSION {
+            return Err(anyhow!("Unsupported authentication version {}", version[0]));
+        }
+        let username = read_short_string(stream)?;
+        let password = read_short_string(stream)?;
+        let accepted = opts.username.as_deref() == Some(username.as_str())
+            && opts.password.as_deref() == Some(password.as_str());
+        stream.write_all(&[AUTH_VERSION, if accepted { 0 } else { 1 }])?;
+        if !accepted {
+            return Err(anyhow!("Authentication failed for user {}", username));
+        }
+    }
+    Ok(())
+}
+
+fn connect(mut stream: TcpStream, target: TargetAddr, net: &NetOpts) -> anyhow::Result<()> {
+    let upstream = match target.connect(net) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            send_reply(&mut stream, reply_code(&e), None)?;
+            return Err(anyhow!("Failed to connect to {:?}: {}", target, e));
+        }
+    };
+    debug!("CONNECT {:?} via {}", target, upstream.local_addr()?);
+    send_reply(&mut stream, REP_SUCCEEDED, Some(upstream.local_addr()?))?;
+
+    let (sent, received) = relay::relay(stream, upstream)?;
+    info!(
+        "CONNECT {:?} finished: {} bytes sent, {} bytes received",
+        target, sent, received
+    );
+    Ok(())
+}
+
+fn udp_associate(mut stream: TcpStream, client_hint: TargetAddr) -> anyhow::Result<()> {
+    // Relay on the address the client reached us on, so that it is routable from the client.
+    let socket = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0))?;
+    socket.set_read_timeout(Some(UDP_POLL_INTERVAL))?;
+    send_reply(&mut stream, REP_SUCCEEDED, Some(socket.local_addr()?))?;
+
+    let client_ip = stream.peer_addr()?.ip();
+    // The client may announce the port it will send from; 0 means it does not know yet.
+    let client_port = match client_hint {
+        TargetAddr::Ip(addr) => addr.port(),
+        TargetAddr::Domain(_, port) => port,
+    };
+    debug!(
+        "UDP ASSOCIATE for {} on {}",
+        client_ip,
+        socket.local_addr()?
+    );
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let relay_closed = closed.clone();
+    let relay =
+        thread::spawn(move || relay_datagrams(socket, client_ip, client_port, &relay_closed));
+
+    // The association lasts as long as the control connection.
+    let mut buf = [0u8; 64];
+    while let Ok(n) = stream.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+    }
+    closed.store(true, Ordering::SeqCst);
+    relay
+        .join()
+        .map_err(|_| anyhow!("UDP relay thread panicked"))?
+}
+
+fn relay_datagrams(
+    socket: UdpSocket,
+    client_ip: IpAddr,
+    client_port: u16,
+    closed: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut client: Option<SocketAddr> = None;
+    let mut buf = [0u8; 65536];
+    let local = socket.local_addr()?;
+
+    while !closed.load(Ordering::SeqCst) {
+        let (size, src) = match socket.recv_from(&mut buf) {
+            Ok(t) => t,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Until the client's first datagram tells us its exact address, trust its announcement.
+        let from_client = match client {
+            Some(client) => src == client,
+            None => src.ip() == client_ip && (client_port == 0 || src.port() == client_port),
+        };
+        if from_client {
+            client = Some(src);
+            // RSV(2) FRAG(1) ATYP DST.ADDR DST.PORT DATA
+            let mut reader = &buf[..size];
+            let mut header = [0u8; 3];
+            if reader.read_exact(&mut header).is_err() || header[2] != 0 {
+                debug!("Dropping truncated or fragmented datagram");
+                continue;
+            }
+            let target = match read_addr(&mut reader).and_then(|target| target.resolve(local)) {
+                Ok(target) => target,
+                Err(e) => {
+                    error!("Dropping datagram: {}", e);
+                    continue;
+                }
+            };
+            // One undeliverable datagram must not end the whole association.
+            if let Err(e) = socket.send_to(reader, target) {
+                error!("Dropping datagram to {}: {}", target, e);
+            }
+        } else if let Some(client) = client {
+            let mut datagram = vec![0, 0, 0];
+            write_addr(
+                &mut datagram,
+                SocketAddr::new(src.ip().to_canonical(), src.port()),
+            );
+            datagram.extend_from_slice(&buf[..size]);
+            if let Err(e) = socket.send_to(&datagram, client) {
+                error!("Dropping datagram to {}: {}", client, e);
+            }
+        }
+    }
+    debug!("UDP association closed");
+    Ok(())
+}
+
+fn read_addr<R: Read>(reader: &mut R) -> io::Result<TargetAddr> {
+    let mut atyp = [0u8; 1];
+    reader.read_exact(&mut atyp)?;
+    let target = match atyp[0] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            reader.read_exact(&mut addr)?;
+            TargetAddr::Ip(SocketAddr::new(
+                Ipv4Addr::from(addr).into(),
+                read_port(reader)?,
+            ))
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            reader.read_exact(&mut addr)?;
+            TargetAddr::Ip(SocketAddr::new(
+                Ipv6Addr::from(addr).into(),
+                read_port(reader)?,
+            ))
+        }
+        ATYP_DOMAIN => {
+            let host = read_short_string(reader)?;
+            TargetAddr::Domain(host, read_port(reader)?)
+        }
+        atyp => {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported address type {}", atyp),
+            ))
+        }
+    };
+    Ok(target)
+}
+
+fn read_port<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut port = [0u8; 2];
+    reader.read_exact(&mut port)?;
+    Ok(u16::from_be_bytes(port))
+}
+
+/// Reads a string prefixed with its 1-byte length.
+fn read_short_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut len = [0u8; 1];
+    reader.read_exact(&mut len)?;
+    let mut buf = vec![0u8; len[0] as usize];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn send_reply(stream: &mut TcpStream, rep: u8, bound: Option<SocketAddr>) -> io::Result<()> {
+    let mut reply = vec![VERSION, rep, 0];
+    write_addr(
+        &mut reply,
+        bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))),
+    );
+    stream.write_all(&reply)
+}
+
+fn reply_code(error: &io::Error) -> u8 {
+    match error.kind() {
+        ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+        ErrorKind::HostUnreachable | ErrorKind::NotFound | ErrorKind::TimedOut => {
+            REP_HOST_UNREACHABLE
+        }
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+
+    /// Serves SOCKS5 on a loopback port. The result of every connection comes out of the receiver.
+    fn start(opts: Socks5Opts) -> (SocketAddr, Receiver<anyhow::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (opts, tx) = (opts.clone(), tx.clone());
+                thread::spawn(move || {
+                    let _ = tx.send(handler(stream.unwrap(), &opts, &NetOpts::default()));
+                });
+            }
+        });
+        (address, rx)
+    }
+
+    /// A TCP server that echoes one connection.
+    fn tcp_echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            io::copy(&mut reader, &mut stream).unwrap();
+        });
+        address
+    }
+
+    fn client(proxy: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(proxy).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    /// Offers no authentication and checks that the proxy accepts it.
+    fn greet(stream: &mut TcpStream) {
+        stream.write_all(&[VERSION, 1, METHOD_NO_AUTH]).unwrap();
+        assert_eq!(read(stream, 2), [VERSION, METHOD_NO_AUTH]);
+    }
+
+    fn request(stream: &mut TcpStream, cmd: u8, target: SocketAddr) {
+        let mut request = vec![VERSION, cmd, 0];
+        write_addr(&mut request, target);
+        stream.write_all(&request).unwrap();
+    }
+
+    /// Reads a reply and returns its code and bound address.
+    fn reply(stream: &mut TcpStream) -> (u8, SocketAddr) {
+        let header = read(stream, 3);
+        assert_eq!((header[0], header[2]), (VERSION, 0));
+        match read_addr(stream).unwrap() {
+            TargetAddr::Ip(addr) => (header[1], addr),
+            other => panic!("bound to {}", other),
+        }
+    }
+
+    fn read(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn connect_relays_both_ways() {
+        let (proxy, _) = start(Socks5Opts::default());
+        let mut stream = client(proxy);
+        greet(&mut stream);
+        request(&mut stream, CMD_CONNECT, tcp_echo());
+        assert_eq!(reply(&mut stream).0, REP_SUCCEEDED);
+        stream.write_all(b"through the proxy").unwrap();
+        assert_eq!(read(&mut stream, 17), b"through the proxy");
+    }
+
+    #[test]
+    fn domain_targets_are_resolved() {
+        let (proxy, _) = start(Socks5Opts::default());
+        let mut stream = client(proxy);
+        greet(&mut stream);
+        let port = tcp_echo().port();
+        let mut request = vec![VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, 9];
+        request.extend_from_slice(b"localhost");
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).unwrap();
+        assert_eq!(reply(&mut stream).0, REP_SUCCEEDED);
+    }
+
+    #[test]
+    fn passwords_are_checked() {
+        let opts = Socks5Opts {
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+        };
+        let (proxy, results) = start(opts);
+
+        // Without the password method on offer, there is nothing to agree on.
+        let mut stream = client(proxy);
+        stream.write_all(&[VERSION, 1, METHOD_NO_AUTH]).unwrap();
+        assert_eq!(read(&mut stream, 2), [VERSION, METHOD_NONE_ACCEPTABLE]);
+        assert!(results.recv().unwrap().is_err());
+
+        for (password, status) in [("wrong", 1), ("secret", 0)] {
+            let mut stream = client(proxy);
+            stream
+                .write_all(&[VERSION, 2, METHOD_NO_AUTH, METHOD_USER_PASS])
+                .unwrap();
+            assert_eq!(read(&mut stream, 2), [VERSION, METHOD_USER_PASS]);
+            let mut auth = vec![AUTH_VERSION, 4];
+            auth.extend_from_slice(b"user");
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).unwrap();
+            assert_eq!(read(&mut stream, 2), [AUTH_VERSION, status]);
+        }
+        let e = results.recv().unwrap().unwrap_err();
+        assert!(e.to_string().contains("Authentication failed"), "{}", e);
+    }
+
+    #[test]
+    fn failures_get_their_reply_code() {
+        let (proxy, _) = start(Socks5Opts::default());
+
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut stream = client(proxy);
+        greet(&mut stream);
+        request(&mut stream, CMD_CONNECT, refused);
+        assert_eq!(reply(&mut stream).0, REP_CONNECTION_REFUSED);
+
+        let mut stream = client(proxy);
+        greet(&mut stream);
+        request(&mut stream, 0x02, refused);
+        assert_eq!(reply(&mut stream).0, REP_COMMAND_NOT_SUPPORTED);
+
+        let mut stream = client(proxy);
+        greet(&mut stream);
+        stream.write_all(&[VERSION, CMD_CONNECT, 0, 0x02]).unwrap();
+        assert_eq!(reply(&mut stream).0, REP_ADDRESS_TYPE_NOT_SUPPORTED);
+
+        let mut stream = client(proxy);
+        greet(&mut stream);
+        stream
+            .write_all(&[VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, 2, 0xff, 0xfe, 0, 80])
+            .unwrap();
+        assert_eq!(reply(&mut stream).0, REP_GENERAL_FAILURE);
+    }
+
+    #[test]
+    fn a_cut_off_request_gets_no_reply() {
+        let (proxy, results) = start(Socks5Opts::default());
+        let mut stream = client(proxy);
+        greet(&mut stream);
+        stream
+            .write_all(&[VERSION, CMD_CONNECT, 0, ATYP_IPV4, 127])
+            .unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let e = results.recv().unwrap().unwrap_err();
+        let e = e.downcast::<io::Error>().unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty(), "{:?}", rest);
+    }
+
+    #[test]
+    fn udp_associate_relays_datagrams() {
+        let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (size, src) = echo.recv_from(&mut buf).unwrap();
+            echo.send_to(&buf[..size], src).unwrap();
+        });
+
+        let (proxy, _) = start(Socks5Opts::default());
+        let mut control = client(proxy);
+        greet(&mut control);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        request(
+            &mut control,
+            CMD_UDP_ASSOCIATE,
+            socket.local_addr().unwrap(),
+        );
+        let (rep, relay) = reply(&mut control);
+        assert_eq!(rep, REP_SUCCEEDED);
+
+        let mut datagram = vec![0, 0, 0];
+        write_addr(&mut datagram, echo_addr);
+        datagram.extend_from_slice(b"ping");
+        socket.send_to(&datagram, relay).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let (size, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(src, relay);
+        // The reply comes back with the same header, naming the echo server as its source.
+        assert_eq!(&buf[..size], &datagram[..]);
+    }
+}
@@ -0,0 +1,520 @@
+use crate::net::{self, NetOpts};
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::{debug, warn};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LEN: usize = 20;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_UNKNOWN_ATTRIBUTES: u16 = 0x000a;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_SOFTWARE: u16 = 0x8022;
+const ATTR_FINGERPRINT: u16 = 0x8028;
+/// From RFC 5780: the address the response was sent from.
+const ATTR_RESPONSE_ORIGIN: u16 = 0x802b;
+/// XORed into the CRC-32 of the message to make the FINGERPRINT attribute.
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+const SOFTWARE: &str = concat!("socket ", env!("CARGO_PKG_VERSION"));
+
+/// The first retransmission timeout; it doubles for every retry (RFC 5389 7.2.1).
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const REQUEST_TRIES: u32 = 4;
+
+#[derive(Clap, Debug, Default)]
+pub struct StunOpts {
+    /// Another STUN server the client asks, to check that the mapped address is the same.
+    /// May be given more than once.
+    #[clap(
+        long = "stun-server",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub stun_servers: Vec<String>,
+}
+
+/// A STUN message: its type, transaction ID and attributes in order.
+#[derive(Debug)]
+struct Message {
+    kind: u16,
+    transaction: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    fn new(kind: u16, transaction: [u8; 12]) -> Self {
+        Message {
+            kind,
+            transaction,
+            attributes: Vec::new(),
+        }
+    }
+
+    fn attribute(&self, kind: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Encodes the message, ending it with a FINGERPRINT attribute.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.kind.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction);
+        for (kind, value) in &self.attributes {
+            push_attribute(&mut buf, *kind, value);
+        }
+        // The length must already count the fingerprint when the CRC is taken.
+        set_length(&mut buf, 8);
+        let crc = crc32(&buf) ^ FINGERPRINT_XOR;
+        push_attribute(&mut buf, ATTR_FINGERPRINT, &crc.to_be_bytes());
+        buf
+    }
+
+    /// Parses a STUN message. Returns `None` for anything that is not one, which on a shared
+    /// port may well be some other protocol.
+    fn parse(buf: &[u8]) -> Option<Message> {
+        if buf.len() < HEADER_LEN || buf[0] & 0xc0 != 0 {
+            return None;
+        }
+        let kind = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+        let cookie = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if cookie != MAGIC_COOKIE || len % 4 != 0 || buf.len() != HEADER_LEN + len {
+            return None;
+        }
+        let mut transaction = [0u8; 12];
+        transaction.copy_from_slice(&buf[8..HEADER_LEN]);
+
+        let mut attributes = Vec::new();
+        let mut pos = HEADER_LEN;
+        while pos < buf.len() {
+            let header = buf.get(pos..pos + 4)?;
+            let attr_kind = u16::from_be_bytes([header[0], header[1]]);
+            let attr_len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+            let value = buf.get(pos + 4..pos + 4 + attr_len)?;
+            if attr_kind == ATTR_FINGERPRINT {
+                // The fingerprint covers everything before it and must come last.
+                let expected = crc32(&buf[..pos]) ^ FINGERPRINT_XOR;
+                if value.len() != 4 || value != expected.to_be_bytes() || pos + 8 != buf.len() {
+                    debug!("Bad STUN fingerprint");
+                    return None;
+                }
+            }
+            attributes.push((attr_kind, value.to_vec()));
+            pos += 4 + padded(attr_len);
+        }
+        Some(Message {
+            kind,
+            transaction,
+            attributes,
+        })
+    }
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn push_attribute(buf: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len() + padded(value.len()) - value.len(), 0);
+}
+
+/// Writes the message length into the header, plus `extra` bytes still to be added.
+fn set_length(buf: &mut [u8], extra: usize) {
+    let len = (buf.len() - HEADER_LEN + extra) as u16;
+    buf[2..4].copy_from_slice(&len.to_be_bytes());
+}
+
+/// The CRC-32 used by Ethernet and zlib, bit by bit; STUN messages are small.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Encodes an address attribute. XOR-MAPPED-ADDRESS hides the address from NATs that
+/// rewrite anything looking like one, by XORing it with the cookie and transaction ID.
+fn encode_address(addr: SocketAddr, xor: Option<&[u8; 12]>) -> Vec<u8> {
+    let mask = xor_mask(xor);
+    let mut value = vec![0];
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(FAMILY_IPV4);
+            ip.octets().to_vec()
+        }
+        IpAddr::V6(ip) => {
+            value.push(FAMILY_IPV6);
+            ip.octets().to_vec()
+        }
+    };
+    let port = addr.port().to_be_bytes();
+    value.extend(port.iter().zip(&mask).map(|(b, m)| b ^ m));
+    value.extend(ip.iter().zip(&mask).map(|(b, m)| b ^ m));
+    value
+}
+
+fn decode_address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let mask = xor_mask(xor);
+    let port = u16::from_be_bytes([value.get(2)? ^ mask[0], value.get(3)? ^ mask[1]]);
+    let ip: Vec<u8> = value
+        .get(4..)?
+        .iter()
+        .zip(&mask)
+        .map(|(b, m)| b ^ m)
+        .collect();
+    let ip = match (value[1], ip.len()) {
+        (FAMILY_IPV4, 4) => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+        (FAMILY_IPV6, 16) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&ip);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// The bytes an address is XORed with: the cookie, then the transaction ID. Zeros without XOR.
+fn xor_mask(transaction: Option<&[u8; 12]>) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    if let Some(transaction) = transaction {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+    mask
+}
+
+pub fn server(address: &str, net: &NetOpts) -> anyhow::Result<()> {
+    let socket = net.bind_udp(address)?;
+    let local_addr = socket.local_addr()?;
+    let mut buf = [0u8; 2048];
+    loop {
+        // Errors are about single datagrams, so they must not stop the server.
+        let (size, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Receive: {}", e);
+                continue;
+            }
+        };
+        let request = match Message::parse(&buf[..size]) {
+            Some(request) if request.kind == BINDING_REQUEST => request,
+            Some(request) => {
+                debug!("{}: ignoring STUN message type {:#06x}", src, request.kind);
+                continue;
+            }
+            None => {
+                debug!("{}: ignoring {} bytes that are not STUN", src, size);
+                continue;
+            }
+        };
+        let response = respond(&request, src, local_addr);
+        debug!(
+            "{}: binding request answered with {:#06x}",
+            src, response.kind
+        );
+        if let Err(e) = socket.send_to(&response.to_bytes(), src) {
+            warn!("{}: {}", src, e);
+        }
+    }
+}
+
+fn respond(request: &Message, src: SocketAddr, local_addr: SocketAddr) -> Message {
+    // Attributes below 0x8000 must be understood; we understand none in a request.
+    let unknown: Vec<u16> = request
+        .attributes
+        .iter()
+        .map(|(kind, _)| *kind)
+        .filter(|kind| *kind < 0x8000)
+        .collect();
+    if !unknown.is_empty() {
+        let mut response = Message::new(BINDING_ERROR, request.transaction);
+        let mut error = vec![0, 0, 4, 20];
+        error.extend_from_slice(b"Unknown Attribute");
+        response.attributes.push((ATTR_ERROR_CODE, error));
+        let kinds = unknown.iter().flat_map(|kind| kind.to_be_bytes()).collect();
+        response.attributes.push((ATTR_UNKNOWN_ATTRIBUTES, kinds));
+        return response;
+    }
+    let mut response = Message::new(BINDING_SUCCESS, request.transaction);
+    let attributes = [
+        (
+            ATTR_XOR_MAPPED_ADDRESS,
+            encode_address(src, Some(&request.transaction)),
+        ),
+        // For RFC 3489 clients, which do not know XOR-MAPPED-ADDRESS.
+        (ATTR_MAPPED_ADDRESS, encode_address(src, None)),
+        (ATTR_RESPONSE_ORIGIN, encode_address(local_addr, None)),
+        (ATTR_SOFTWARE, SOFTWARE.as_bytes().to_vec()),
+    ];
+    response.attributes.extend(attributes);
+    response
+}
+
+pub fn client(address: &str, opts: &StunOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let servers: Vec<SocketAddr> = std::iter::once(address)
+        .chain(opts.stun_servers.iter().map(String::as_str))
+        .map(|server| Ok(net::resolve(server)?[0]))
+        .collect::<anyhow::Result<_>>()?;
+    // One socket for every server: the mapping of this one local port is what gets compared.
+    let socket = net.bind_udp(&net::unspecified(&servers[0]).to_string())?;
+    println!("Local address {}", socket.local_addr()?);
+
+    let mut mapped = Vec::new();
+    for &server in &servers {
+        match binding(&socket, server) {
+            Ok((addr, rtt)) => {
+                println!(
+                    "{}: mapped address {} ({:.3} ms)",
+                    server,
+                    addr,
+                    rtt.as_secs_f64() * 1000.0
+                );
+                mapped.push(addr);
+            }
+            Err(e) => println!("{}: {:#}", server, e),
+        }
+    }
+
+    let first = *mapped.first().context("No STUN server answered")?;
+    if mapped.len() < servers.len() {
+        warn!(
+            "Only {} of {} servers answered",
+            mapped.len(),
+            servers.len()
+        );
+    }
+    if mapped.len() < 2 {
+        println!("Ask more than one server (--stun-server) to check the mapping");
+    } else if mapped.iter().all(|&addr| addr == first) {
+        println!(
+            "Same mapped address from all {} servers: endpoint-independent mapping",
+            mapped.len()
+        );
+    } else if mapped.iter().all(|addr| addr.ip() == first.ip()) {
+        return Err(anyhow!(
+            "Mapped port depends on the server: address- or port-dependent mapping"
+        ));
+    } else {
+        return Err(anyhow!("Mapped addresses differ between servers"));
+    }
+    Ok(())
+}
+
+/// Sends a binding request to `server`, retransmitting it as RFC 5389 does, and returns the
+/// mapped address and round-trip time.
+fn binding(socket: &UdpSocket, server: SocketAddr) -> anyhow::Result<(SocketAddr, Duration)> {
+    let transaction: [u8; 12] = rand::random();
+    let request = Message::new(BINDING_REQUEST, transaction).to_bytes();
+    let mut buf = [0u8; 2048];
+    let mut rto = INITIAL_RTO;
+    for _ in 0..REQUEST_TRIES {
+        let sent_at = Instant::now();
+        socket.send_to(&request, server)?;
+        let deadline = sent_at + rto;
+        rto *= 2;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+            let (size, src) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let response = match Message::parse(&buf[..size]) {
+                Some(response) if src == server && response.transaction == transaction => response,
+                // A late answer to an earlier server, or something else entirely.
+                _ => continue,
+            };
+            let rtt = sent_at.elapsed();
+            if response.kind == BINDING_ERROR {
+                let error = response.attribute(ATTR_ERROR_CODE).unwrap_or(&[]);
+                return Err(match error {
+                    [_, _, class, number, reason @ ..] => anyhow!(
+                        "error {}{:02}: {}",
+                        class & 0x07,
+                        number,
+                        String::from_utf8_lossy(reason)
+                    ),
+                    _ => anyhow!("error response"),
+                });
+            }
+            if response.kind != BINDING_SUCCESS {
+                continue;
+            }
+            let mapped = response
+                .attribute(ATTR_XOR_MAPPED_ADDRESS)
+                .and_then(|value| decode_address(value, Some(&transaction)))
+                .or_else(|| {
+                    response
+                        .attribute(ATTR_MAPPED_ADDRESS)
+                        .and_then(|value| decode_address(value, None))
+                })
+                .context("Response has no mapped address")?;
+            return Ok((mapped, rtt));
+        }
+        debug!("{}: no answer, retrying", server);
+    }
+    Err(anyhow!("no answer after {} tries", REQUEST_TRIES))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transaction ID of the RFC 5769 test vectors.
+    const TRANSACTION: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    /// The IPv4 response of RFC 5769, section 2.2.
+    const IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn rfc_5769_response_is_parsed() {
+        let response = Message::parse(&IPV4_RESPONSE).unwrap();
+        assert_eq!(response.kind, BINDING_SUCCESS);
+        assert_eq!(response.transaction, TRANSACTION);
+        assert_eq!(response.attribute(ATTR_SOFTWARE), Some(&b"test vector"[..]));
+        let mapped = response.attribute(ATTR_XOR_MAPPED_ADDRESS).unwrap();
+        assert_eq!(
+            decode_address(mapped, Some(&TRANSACTION)),
+            Some("192.0.2.1:32853".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn xor_mapped_addresses_match_rfc_5769() {
+        let v4: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        assert_eq!(
+            encode_address(v4, Some(&TRANSACTION)),
+            [0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]
+        );
+        // IPv6 addresses are masked with the transaction ID too.
+        let v6: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+        let encoded = encode_address(v6, Some(&TRANSACTION));
+        assert_eq!(
+            encoded[4..],
+            [
+                0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2,
+                0xb9, 0xd9
+            ]
+        );
+        assert_eq!(decode_address(&encoded, Some(&TRANSACTION)), Some(v6));
+        // Plain MAPPED-ADDRESS leaves the address alone.
+        assert_eq!(decode_address(&encode_address(v6, None), None), Some(v6));
+
+        assert_eq!(
+            decode_address(&[0, FAMILY_IPV6, 0, 0, 1, 2, 3, 4], None),
+            None
+        );
+        assert_eq!(decode_address(&[0, 3, 0, 0, 1, 2, 3, 4], None), None);
+        assert_eq!(decode_address(&[0, FAMILY_IPV4], None), None);
+    }
+
+    #[test]
+    fn fingerprint_is_checked() {
+        let bytes = Message::new(BINDING_REQUEST, TRANSACTION).to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + 8);
+        assert!(Message::parse(&bytes).is_some());
+
+        let mut tampered = IPV4_RESPONSE;
+        tampered[24] ^= 1;
+        assert!(Message::parse(&tampered).is_none());
+
+        // Nothing may follow the fingerprint.
+        let mut trailing = bytes.clone();
+        trailing[3] += 8;
+        trailing.extend_from_slice(&[0x80, 0x22, 0, 4, b't', b'e', b's', b't']);
+        assert!(Message::parse(&trailing).is_none());
+    }
+
+    #[test]
+    fn other_protocols_are_not_stun() {
+        let bytes = Message::new(BINDING_REQUEST, TRANSACTION).to_bytes();
+        let mut wrong_cookie = bytes.clone();
+        wrong_cookie[4] ^= 1;
+        assert!(Message::parse(&wrong_cookie).is_none());
+        let mut top_bits = bytes.clone();
+        top_bits[0] |= 0x80;
+        assert!(Message::parse(&top_bits).is_none());
+        assert!(Message::parse(&bytes[..bytes.len() - 1]).is_none());
+        assert!(Message::parse(b"GET / HTTP/1.1\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn responses_carry_the_source_address() {
+        let src: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let local: SocketAddr = "0.0.0.0:3478".parse().unwrap();
+        let request = Message::new(BINDING_REQUEST, TRANSACTION);
+        let response = Message::parse(&respond(&request, src, local).to_bytes()).unwrap();
+        assert_eq!(response.kind, BINDING_SUCCESS);
+        assert_eq!(response.transaction, TRANSACTION);
+        let xor = response.attribute(ATTR_XOR_MAPPED_ADDRESS).unwrap();
+        assert_eq!(decode_address(xor, Some(&TRANSACTION)), Some(src));
+        let plain = response.attribute(ATTR_MAPPED_ADDRESS).unwrap();
+        assert_eq!(decode_address(plain, None), Some(src));
+    }
+
+    #[test]
+    fn unknown_required_attributes_get_420() {
+        let src: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let mut request = Message::new(BINDING_REQUEST, TRANSACTION);
+        request.attributes.push((0x0003, vec![0, 0, 0, 0]));
+        request
+            .attributes
+            .push((ATTR_SOFTWARE, b"ignored".to_vec()));
+        let response = respond(&request, src, src);
+        assert_eq!(response.kind, BINDING_ERROR);
+        assert_eq!(
+            response.attribute(ATTR_ERROR_CODE).unwrap()[..4],
+            [0, 0, 4, 20]
+        );
+        assert_eq!(
+            response.attribute(ATTR_UNKNOWN_ATTRIBUTES),
+            Some(&[0, 3][..])
+        );
+    }
+}
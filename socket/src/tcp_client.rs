@@ -1,17 +1,90 @@
-use std::io::{self, Write, BufReader, BufRead};
-use std::str;
-use std::net::TcpStream;
+use crate::framing::Framing;
+use crate::net::NetOpts;
+use anyhow::anyhow;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread::{self, JoinHandle};
 
-pub fn connect(address: &str) -> anyhow::Result<()> {
-    let mut stream = TcpStream::connect(address)?;
+/// A blocking client for an echo server, one message at a time.
+pub struct EchoClient {
+    reader: BufReader<TcpStream>,
+    framing: Framing,
+}
+
+impl EchoClient {
+    pub fn connect(address: &str, framing: Framing, net: &NetOpts) -> anyhow::Result<Self> {
+        let stream = net.connect_tcp(address)?;
+        Ok(EchoClient {
+            reader: BufReader::new(stream),
+            framing,
+        })
+    }
+
+    /// Sends `msg` and waits for the reply.
+    pub fn request(&mut self, msg: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.framing.write_frame(self.reader.get_mut(), msg)?;
+        self.framing
+            .read_frame(&mut self.reader)?
+            .ok_or_else(|| anyhow!("Connection closed before the reply"))
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        self.reader.get_ref()
+    }
+}
+
+pub fn connect(address: &str, framing: Framing, net: &NetOpts) -> anyhow::Result<()> {
+    let stream = net.connect_tcp(address)?;
+    let printer = spawn_printer(stream.try_clone()?, framing);
+    send_stdin(&stream, framing)?;
+    stream.shutdown(Shutdown::Write)?;
+    printer
+        .join()
+        .map_err(|_| anyhow!("Printer thread panicked"))?
+}
+
+/// Sends each line read from stdin as one message until stdin is closed.
+pub fn send_stdin<W: Write>(mut writer: W, framing: Framing) -> anyhow::Result<()> {
+    loop {
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+        let msg = input.trim_end_matches(&['\r', '\n'][..]);
+        framing.write_frame(&mut writer, msg.as_bytes())?;
+    }
+}
+
+/// Prints every message from the server as it arrives, so that servers which talk
+/// without being asked (like the chat server) are shown right away.
+pub fn spawn_printer<R: Read + Send + 'static>(
+    reader: R,
+    framing: Framing,
+) -> JoinHandle<anyhow::Result<()>> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        while let Some(reply) = framing.read_frame(&mut reader)? {
+            println!("{}", String::from_utf8_lossy(&reply));
+        }
+        Ok(())
+    })
+}
+
+/// Sends each line read from stdin as one message and prints the reply.
+/// Used for streams which cannot be split into a read and a write half.
+pub fn communicate<S: Read + Write>(stream: S, framing: Framing) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream);
     loop {
         let mut input = String::new();
-        let _ = io::stdin().read_line(&mut input);
-        stream.write_all(input.as_bytes())?;
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+        let msg = input.trim_end_matches(&['\r', '\n'][..]);
+        framing.write_frame(reader.get_mut(), msg.as_bytes())?;
 
-        let mut reader = BufReader::new(&stream);
-        let mut buf = Vec::new();
-        let _ = reader.read_until(b'\n', &mut buf);
-        print!("{}", str::from_utf8(&buf)?);
+        match framing.read_frame(&mut reader)? {
+            Some(reply) => println!("{}", String::from_utf8_lossy(&reply)),
+            None => return Ok(()),
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,355 @@
+use crate::framing::{Framing, MAX_FRAME_LEN};
+use crate::net::NetOpts;
+use log::{debug, error};
+use mio::net::{TcpListener, TcpStream};
+use mio::{
+    event::{Event, Events},
+    Interest, Poll, Token,
+};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::time::Duration;
+
+const SERVER: Token = Token(0);
+/// Stop reading from a connection while this many echoed bytes are still waiting to be written.
+const HIGH_WATER_MARK: usize = 64 * 1024;
+/// How long to wait before accepting again after accept failed.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+pub fn server(address: &str, framing: Framing, net: &NetOpts) -> anyhow::Result<()> {
+    let mut server = EventServer::new(net.bind_tcp(address)?, framing, net.clone())?;
+    server.run()
+}
+
+/// A single-threaded echo server driven by readiness events instead of a thread per connection.
+pub struct EventServer {
+    listening_soc: TcpListener,
+    conns: HashMap<usize, Connection>,
+    next_conn_id: usize,
+    framing: Framing,
+    net: NetOpts,
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Received bytes that do not form a complete message yet.
+    read_buf: Vec<u8>,
+    /// Echoed messages the peer has not accepted yet.
+    write_buf: Vec<u8>,
+    interest: Interest,
+    read_closed: bool,
+}
+
+impl EventServer {
+    pub fn new(
+        listener: std::net::TcpListener,
+        framing: Framing,
+        net: NetOpts,
+    ) -> anyhow::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let listening_soc = TcpListener::from_std(listener);
+        Ok(EventServer {
+            listening_soc,
+            conns: HashMap::new(),
+            next_conn_id: 1,
+            framing,
+            net,
+        })
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let mut poll = Poll::new()?;
+        poll.registry()
+            .register(&mut self.listening_soc, SERVER, Interest::READABLE)?;
+
+        let mut events = Events::with_capacity(1024);
+        // Set when accepting failed with connections still queued. Readiness is edge-triggered,
+        // so no new event may come for them: poll with a timeout and try again.
+        let mut retry_accept = false;
+
+        loop {
+            let timeout = if retry_accept {
+                Some(ACCEPT_RETRY)
+            } else {
+                None
+            };
+            match poll.poll(&mut events, timeout) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+
+            if retry_accept {
+                retry_accept = !self.accept_all(&poll)?;
+            }
+            for event in &events {
+                match event.token() {
+                    SERVER => retry_accept = !self.accept_all(&poll)?,
+                    Token(conn_id) => {
+                        if let Err(e) = self.handle_echo(conn_id, event, &poll) {
+                            error!("conn_id {}: {}", conn_id, e);
+                            self.close_conn(conn_id, &poll);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accepts until the backlog is empty. Returns false if an error stopped it early.
+    fn accept_all(&mut self, poll: &Poll) -> anyhow::Result<bool> {
+        loop {
+            let (stream, remote_addr) = match self.listening_soc.accept() {
+                Ok(t) => t,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // For example out of file descriptors: the connection stays queued, and
+                    // accepting again right away would fail the same way.
+                    error!("Accept: {}", e);
+                    return Ok(false);
+                }
+            };
+            debug!("Connection from {}", &remote_addr);
+            // SAFETY: `stream` owns the descriptor and outlives this borrow.
+            let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+            if let Err(e) = self.net.configure_tcp(&fd) {
+                error!("{}: {}", remote_addr, e);
+                continue;
+            }
+
+            if let Err(e) = self.register_conn(poll, stream) {
+                error!("{}: {}", remote_addr, e);
+            }
+        }
+    }
+
+    fn register_conn(&mut self, poll: &Poll, mut stream: TcpStream) -> anyhow::Result<()> {
+        let token = Token(self.next_conn_id);
+        poll.registry()
+            .register(&mut stream, token, Interest::READABLE)?;
+
+        let conn = Connection {
+            stream,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            interest: Interest::READABLE,
+            read_closed: false,
+        };
+        if self.conns.insert(self.next_conn_id, conn).is_some() {
+            error!("Connection ID is already exist.");
+        }
+
+        self.next_conn_id += 1;
+
+        Ok(())
+    }
+
+    fn close_conn(&mut self, conn_id: usize, poll: &Poll) {
+        if let Some(mut conn) = self.conns.remove(&conn_id) {
+            let _ = poll.registry().deregister(&mut conn.stream);
+            debug!("Connection closed conn_id: {}", conn_id);
+        }
+    }
+
+    fn handle_echo(&mut self, conn_id: usize, event: &Event, poll: &Poll) -> anyhow::Result<()> {
+        let framing = self.framing;
+        let conn = match self.conns.get_mut(&conn_id) {
+            Some(conn) => conn,
+            // The connection was already closed by an earlier event in the same batch.
+            None => return Ok(()),
+        };
+        debug!(
+            "conn_id: {}, readable: {}, writable: {}",
+            conn_id,
+            event.is_readable(),
+            event.is_writable()
+        );
+
+        // Events are edge-triggered, so drain both directions until the socket would block
+        // or the write buffer is full enough to apply backpressure.
+        let mut buf = [0u8; 4096];
+        loop {
+            conn.flush()?;
+            if conn.read_closed || conn.write_buf.len() >= HIGH_WATER_MARK {
+                break;
+            }
+            match conn.stream.read(&mut buf) {
+                Ok(0) => conn.read_closed = true,
+                Ok(nbytes) => {
+                    conn.read_buf.extend_from_slice(&buf[..nbytes]);
+                    // Only the new bytes can end a line: rescanning a long partial one on every
+                    // read would be quadratic. Past the limit, `decode` rejects it.
+                    if framing == Framing::Line
+                        && !buf[..nbytes].contains(&b'\n')
+                        && conn.read_buf.len() <= MAX_FRAME_LEN
+                    {
+                        continue;
+                    }
+                    while let Some(msg) = framing.decode(&mut conn.read_buf)? {
+                        debug!("Echoing {:?}", String::from_utf8_lossy(&msg));
+                        framing.encode(&msg, &mut conn.write_buf)?;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if conn.read_closed && conn.write_buf.is_empty() {
+            self.close_conn(conn_id, poll);
+            return Ok(());
+        }
+
+        let interest = match (
+            conn.read_closed || conn.write_buf.len() >= HIGH_WATER_MARK,
+            conn.write_buf.is_empty(),
+        ) {
+            (false, true) => Interest::READABLE,
+            (false, false) => Interest::READABLE | Interest::WRITABLE,
+            (true, _) => Interest::WRITABLE,
+        };
+        if interest != conn.interest {
+            poll.registry()
+                .reregister(&mut conn.stream, Token(conn_id), interest)?;
+            conn.interest = interest;
+        }
+        Ok(())
+    }
+}
+
+impl Connection {
+    /// Writes as much of the pending echo data as the socket accepts without blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(nbytes) => {
+                    self.write_buf.drain(..nbytes);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::{Shutdown, SocketAddr};
+    use std::thread;
+
+    /// Starts a server on a loopback port. It runs until the test process exits.
+    fn start(framing: Framing) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = EventServer::new(listener, framing, NetOpts::default()).unwrap();
+        thread::spawn(move || server.run());
+        address
+    }
+
+    fn connect(address: SocketAddr) -> std::net::TcpStream {
+        let stream = std::net::TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    #[test]
+    fn messages_split_across_segments_are_echoed() {
+        for framing in [Framing::Line, Framing::Length] {
+            let address = start(framing);
+            let mut stream = connect(address);
+            let mut bytes = Vec::new();
+            framing.encode(b"hello", &mut bytes).unwrap();
+            framing.encode(b"", &mut bytes).unwrap();
+            framing.encode(b"world", &mut bytes).unwrap();
+            for chunk in bytes.chunks(3) {
+                stream.write_all(chunk).unwrap();
+                thread::sleep(Duration::from_millis(5));
+            }
+            let mut reader = BufReader::new(stream);
+            for expected in [&b"hello"[..], b"", b"world"] {
+                let msg = framing.read_frame(&mut reader).unwrap();
+                assert_eq!(msg.as_deref(), Some(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn connections_are_served_concurrently() {
+        let address = start(Framing::Line);
+        let mut streams: Vec<_> = (0..10).map(|_| connect(address)).collect();
+        for (i, stream) in streams.iter_mut().enumerate().rev() {
+            writeln!(stream, "client {}", i).unwrap();
+        }
+        for (i, stream) in streams.into_iter().enumerate() {
+            let msg = Framing::Line
+                .read_frame(&mut BufReader::new(stream))
+                .unwrap();
+            assert_eq!(msg, Some(format!("client {}", i).into_bytes()));
+        }
+    }
+
+    #[test]
+    fn echo_is_complete_when_the_client_reads_late() {
+        // Far more than the socket buffers hold, so that the server has to stop reading for a while.
+        let address = start(Framing::Line);
+        let mut stream = connect(address);
+        let line = [b'x'; 1023];
+        let writer = {
+            let mut stream = stream.try_clone().unwrap();
+            thread::spawn(move || {
+                for _ in 0..4096 {
+                    stream.write_all(&line).unwrap();
+                    stream.write_all(b"\n").unwrap();
+                }
+                stream.shutdown(Shutdown::Write).unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(200));
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).unwrap();
+        writer.join().unwrap();
+        assert_eq!(echoed.len(), 4096 * 1024);
+        assert!(echoed
+            .chunks(1024)
+            .all(|chunk| chunk[..1023] == line[..] && chunk[1023] == b'\n'));
+    }
+
+    #[test]
+    fn a_line_without_end_closes_the_connection() {
+        let address = start(Framing::Line);
+        let mut stream = connect(address);
+        let chunk = vec![b'x'; 1024 * 1024];
+        let mut sent = 0;
+        // The server closes the connection once the line passes the limit, failing our writes.
+        while sent <= 2 * MAX_FRAME_LEN {
+            if stream.write_all(&chunk).is_err() {
+                break;
+            }
+            sent += chunk.len();
+        }
+        let mut buf = [0u8; 16];
+        match stream.read(&mut buf) {
+            Ok(0) => {}
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            other => panic!("connection still open: {:?}", other),
+        }
+
+        // Other clients are still served.
+        let mut stream = connect(address);
+        stream.write_all(b"still there\n").unwrap();
+        let msg = Framing::Line
+            .read_frame(&mut BufReader::new(stream))
+            .unwrap();
+        assert_eq!(msg, Some(b"still there".to_vec()));
+    }
+}
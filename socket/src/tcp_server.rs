@@ -1,32 +1,340 @@
-use log::{debug, error};
-use std::net::{TcpListener, TcpStream};
-use std::{str, thread};
-use std::io::{Read, Write};
+use crate::framing::Framing;
+use crate::net::NetOpts;
+use crate::shutdown::{self, Shutdown, Summary};
+use anyhow::anyhow;
+use log::{debug, error, info};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-pub fn server(address: &str) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(address)?;
-    loop {
-        let (stream, _) = listener.accept()?;
-        thread::spawn(move || {
-            handler(stream).unwrap_or_else(|error| error!("{:?}", error));
-        });
+/// Handles one accepted connection.
+pub type Handler = Arc<dyn Fn(TcpStream) -> anyhow::Result<()> + Send + Sync>;
+
+/// A configurable TCP server which echoes messages unless it is given another handler.
+///
+/// ```no_run
+/// use socket::tcp_server::EchoServer;
+///
+/// let server = EchoServer::new("127.0.0.1:0").spawn()?;
+/// let address = server.local_addr();
+/// // ... talk to `address` ...
+/// let summary = server.stop()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct EchoServer {
+    address: String,
+    framing: Framing,
+    net: NetOpts,
+    handler: Option<Handler>,
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+}
+
+impl EchoServer {
+    /// A line-framed echo server for `address`, which may use port 0 to pick a free port.
+    pub fn new(address: &str) -> Self {
+        EchoServer {
+            address: address.to_string(),
+            framing: Framing::Line,
+            net: NetOpts::default(),
+            handler: None,
+            shutdown: Shutdown::new(),
+            drain_timeout: Duration::from_secs(5),
+        }
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    pub fn net(mut self, net: NetOpts) -> Self {
+        self.net = net;
+        self
+    }
+
+    /// Runs `handler` for each connection instead of echoing.
+    /// The summary then counts connections only.
+    pub fn handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(TcpStream) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Stops the server when `shutdown` is triggered, for example by `Shutdown::on_signals`.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// How long open connections may take to finish once the server stops.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// A handle which stops the server when triggered.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Binds and serves on the current thread until the server is stopped.
+    pub fn run(self) -> anyhow::Result<Summary> {
+        let listener = self.net.bind_tcp(&self.address)?;
+        self.serve(listener)
+    }
+
+    /// Binds on the current thread, so that the address is known and in use once this
+    /// returns, then serves on a background thread.
+    pub fn spawn(self) -> anyhow::Result<RunningServer> {
+        let listener = self.net.bind_tcp(&self.address)?;
+        let local_addr = listener.local_addr()?;
+        let shutdown = self.shutdown.clone();
+        let thread = thread::spawn(move || self.serve(listener));
+        Ok(RunningServer {
+            local_addr,
+            shutdown,
+            thread,
+        })
+    }
+
+    fn serve(self, listener: TcpListener) -> anyhow::Result<Summary> {
+        match self.handler {
+            Some(handler) => {
+                let connections = serve_until(
+                    listener,
+                    &self.net,
+                    &self.shutdown,
+                    self.drain_timeout,
+                    move |stream| handler(stream),
+                )?;
+                Ok(Summary {
+                    connections,
+                    bytes: 0,
+                })
+            }
+            None => echo(
+                listener,
+                self.framing,
+                &self.net,
+                &self.shutdown,
+                self.drain_timeout,
+            ),
+        }
     }
 }
 
-fn handler(mut stream: TcpStream) -> anyhow::Result<()> {
-    debug!("Handling data from {}", stream.peer_addr()?);
+/// An `EchoServer` serving on a background thread.
+pub struct RunningServer {
+    local_addr: SocketAddr,
+    shutdown: Shutdown,
+    thread: JoinHandle<anyhow::Result<Summary>>,
+}
 
-    let mut buf = [0u8; 1024];
+impl RunningServer {
+    /// The address the server is bound to, with the port it was given if it asked for 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
 
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Stops the server and waits until its connections have drained.
+    pub fn stop(self) -> anyhow::Result<Summary> {
+        self.shutdown.trigger();
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("Server thread panicked"))?
+    }
+}
+
+/// Runs the echo server on `listener` until `shutdown` is triggered and the open
+/// connections have drained.
+pub fn echo(
+    listener: TcpListener,
+    framing: Framing,
+    net: &NetOpts,
+    shutdown: &Shutdown,
+    drain_timeout: Duration,
+) -> anyhow::Result<Summary> {
+    let echoed = Arc::new(AtomicU64::new(0));
+    let counter = Arc::clone(&echoed);
+    let connections = serve_until(listener, net, shutdown, drain_timeout, move |stream| {
+        echo_counted(stream, framing, &counter)
+    })?;
+    Ok(Summary {
+        connections,
+        bytes: echoed.load(Ordering::SeqCst),
+    })
+}
+
+/// Accepts connections forever, running `handler` for each of them on its own thread.
+pub fn serve<F>(address: &str, net: &NetOpts, handler: F) -> anyhow::Result<()>
+where
+    F: Fn(TcpStream) -> anyhow::Result<()> + Send + Clone + 'static,
+{
+    let listener = net.bind_tcp(address)?;
     loop {
-        let nbytes = stream.read(&mut buf)?;
+        match listener.accept() {
+            Ok((stream, remote_addr)) => {
+                start(stream, remote_addr, net, handler.clone());
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                // Errors such as running out of file descriptors leave the connection queued,
+                // so wait a little instead of failing again at once.
+                error!("Accept: {}", e);
+                thread::sleep(shutdown::POLL_INTERVAL);
+            }
+        }
+    }
+}
 
-        if nbytes == 0 {
-            debug!("Connection closed");
-            return Ok(());
+/// Accepts connections until `shutdown` is triggered, running `handler` for each of them on
+/// its own thread. Then gives the open connections `drain_timeout` to finish before closing
+/// them, and returns how many connections were accepted.
+pub fn serve_until<F>(
+    listener: TcpListener,
+    net: &NetOpts,
+    shutdown: &Shutdown,
+    drain_timeout: Duration,
+    handler: F,
+) -> anyhow::Result<u64>
+where
+    F: Fn(TcpStream) -> anyhow::Result<()> + Send + Clone + 'static,
+{
+    // Wait for connections with a timeout instead of blocking in accept, which a signal
+    // handler cannot interrupt, so that the shutdown flag is seen within `POLL_INTERVAL`.
+    listener.set_nonblocking(true)?;
+    let mut poll = Poll::new()?;
+    poll.registry().register(
+        &mut SourceFd(&listener.as_raw_fd()),
+        Token(0),
+        Interest::READABLE,
+    )?;
+    let mut events = Events::with_capacity(1);
+    let mut workers: Vec<(TcpStream, JoinHandle<()>)> = Vec::new();
+    let mut accepted = 0;
+    while !shutdown.is_triggered() {
+        match poll.poll(&mut events, Some(shutdown::POLL_INTERVAL)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+        workers.retain(|(_, worker)| !worker.is_finished());
+        // Readiness is edge-triggered, so take every pending connection. Trying even without
+        // an event also picks up connections left behind by an earlier accept error.
+        loop {
+            let (stream, remote_addr) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Accept: {}", e);
+                    break;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(false) {
+                error!("{}: {}", remote_addr, e);
+                continue;
+            }
+            let closer = match stream.try_clone() {
+                Ok(closer) => closer,
+                Err(e) => {
+                    error!("{}: {}", remote_addr, e);
+                    continue;
+                }
+            };
+            if let Some(worker) = start(stream, remote_addr, net, handler.clone()) {
+                accepted += 1;
+                workers.push((closer, worker));
+            }
         }
+    }
+    drop(listener);
+    drain(workers, drain_timeout);
+    Ok(accepted)
+}
+
+/// Applies the socket options to an accepted connection and runs `handler` for it on a new
+/// thread. Returns `None` if the connection could not be set up.
+fn start<F>(
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    net: &NetOpts,
+    handler: F,
+) -> Option<JoinHandle<()>>
+where
+    F: Fn(TcpStream) -> anyhow::Result<()> + Send + 'static,
+{
+    if let Err(e) = net.configure_tcp(&stream) {
+        error!("{}: {}", remote_addr, e);
+        return None;
+    }
+    Some(thread::spawn(move || {
+        debug!("Handling data from {}", remote_addr);
+        handler(stream).unwrap_or_else(|error| error!("{:?}", error));
+    }))
+}
+
+/// Waits for the connection threads to finish, then closes whatever is still open.
+fn drain(mut workers: Vec<(TcpStream, JoinHandle<()>)>, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    workers.retain(|(_, worker)| !worker.is_finished());
+    if !workers.is_empty() {
+        info!(
+            "Draining {} connections for up to {:?}",
+            workers.len(),
+            timeout
+        );
+    }
+    while !workers.is_empty() && Instant::now() < deadline {
+        thread::sleep(shutdown::POLL_INTERVAL.min(deadline - Instant::now()));
+        workers.retain(|(_, worker)| !worker.is_finished());
+    }
+    if !workers.is_empty() {
+        info!("Closing {} connections still open", workers.len());
+    }
+    for (stream, worker) in workers {
+        let _ = stream.shutdown(net::Shutdown::Both);
+        let _ = worker.join();
+    }
+}
+
+/// Echoes every message received on `stream` back to the peer until it closes the connection.
+pub fn handler<S: Read + Write>(stream: S, framing: Framing) -> anyhow::Result<()> {
+    echo_counted(stream, framing, &AtomicU64::new(0))
+}
+
+/// Like `handler`, adding the size of every echoed message to `echoed`.
+fn echo_counted<S: Read + Write>(
+    stream: S,
+    framing: Framing,
+    echoed: &AtomicU64,
+) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let msg = match framing.read_frame(&mut reader)? {
+            Some(msg) => msg,
+            None => {
+                debug!("Connection closed");
+                return Ok(());
+            }
+        };
 
-        print!("{}", str::from_utf8(&buf[..nbytes])?);
-        stream.write_all(&buf[..nbytes])?;
+        debug!("Echoing {:?}", String::from_utf8_lossy(&msg));
+        framing.write_frame(reader.get_mut(), &msg)?;
+        echoed.fetch_add(msg.len() as u64, Ordering::SeqCst);
     }
 }
@@ -0,0 +1,957 @@
+use crate::net::{self, NetOpts};
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use log::{debug, error};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+const RRQ: u16 = 1;
+const WRQ: u16 = 2;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+/// Option acknowledgement (RFC 2347).
+const OACK: u16 = 6;
+
+const ERR_NOT_DEFINED: u16 = 0;
+const ERR_FILE_NOT_FOUND: u16 = 1;
+const ERR_ACCESS_VIOLATION: u16 = 2;
+const ERR_ILLEGAL_OPERATION: u16 = 4;
+const ERR_UNKNOWN_TID: u16 = 5;
+const ERR_FILE_EXISTS: u16 = 6;
+
+const DEFAULT_BLKSIZE: usize = 512;
+/// Block sizes a client may ask for (RFC 2348).
+const MIN_BLKSIZE: usize = 8;
+const MAX_BLKSIZE: usize = 65464;
+/// Transmissions of one packet before a transfer is given up.
+const MAX_TRIES: u32 = 5;
+
+#[derive(Clap, Debug)]
+pub struct TftpOpts {
+    /// Directory the TFTP server serves files from and stores uploads in.
+    #[clap(long, parse(from_os_str), default_value = ".")]
+    pub root: PathBuf,
+    /// File the TFTP client downloads into the current directory.
+    #[clap(long)]
+    pub get: Option<String>,
+    /// File the TFTP client uploads.
+    #[clap(long, parse(from_os_str))]
+    pub put: Option<PathBuf>,
+    /// Block size the TFTP client asks for, from 8 to 65464 bytes (RFC 2348).
+    #[clap(long)]
+    pub blksize: Option<usize>,
+    /// Seconds TFTP waits for an answer before sending a packet again.
+    #[clap(long, default_value = "1")]
+    pub retransmit_timeout: u64,
+}
+
+impl Default for TftpOpts {
+    fn default() -> Self {
+        Self::parse_from(["socket"])
+    }
+}
+
+impl TftpOpts {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.retransmit_timeout.max(1))
+    }
+}
+
+/// A TFTP packet. Requests carry the RFC 2347 options in the order they were given.
+#[derive(Debug, Clone, PartialEq)]
+enum Packet {
+    Request {
+        opcode: u16,
+        filename: String,
+        mode: String,
+        options: Vec<(String, String)>,
+    },
+    Data {
+        block: u16,
+        data: Vec<u8>,
+    },
+    Ack {
+        block: u16,
+    },
+    Error {
+        code: u16,
+        message: String,
+    },
+    OptionAck {
+        options: Vec<(String, String)>,
+    },
+}
+
+impl Packet {
+    fn parse(buf: &[u8]) -> Option<Packet> {
+        if buf.len() < 4 {
+            return None;
+        }
+        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+        let number = u16::from_be_bytes([buf[2], buf[3]]);
+        match opcode {
+            RRQ | WRQ => {
+                let mut strings = strings(&buf[2..])?;
+                if strings.len() < 2 || strings.len() % 2 != 0 {
+                    return None;
+                }
+                let options = pairs(strings.split_off(2));
+                let mode = strings.pop()?.to_ascii_lowercase();
+                let filename = strings.pop()?;
+                Some(Packet::Request {
+                    opcode,
+                    filename,
+                    mode,
+                    options,
+                })
+            }
+            DATA => Some(Packet::Data {
+                block: number,
+                data: buf[4..].to_vec(),
+            }),
+            ACK => Some(Packet::Ack { block: number }),
+            ERROR => Some(Packet::Error {
+                code: number,
+                message: strings(&buf[4..])?.into_iter().next().unwrap_or_default(),
+            }),
+            OACK => {
+                let strings = strings(&buf[2..])?;
+                if strings.len() % 2 != 0 {
+                    return None;
+                }
+                Some(Packet::OptionAck {
+                    options: pairs(strings),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Packet::Request {
+                opcode,
+                filename,
+                mode,
+                options,
+            } => {
+                buf.extend_from_slice(&opcode.to_be_bytes());
+                push_str(&mut buf, filename);
+                push_str(&mut buf, mode);
+                for (name, value) in options {
+                    push_str(&mut buf, name);
+                    push_str(&mut buf, value);
+                }
+            }
+            Packet::Data { block, data } => {
+                buf.extend_from_slice(&DATA.to_be_bytes());
+                buf.extend_from_slice(&block.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Packet::Ack { block } => {
+                buf.extend_from_slice(&ACK.to_be_bytes());
+                buf.extend_from_slice(&block.to_be_bytes());
+            }
+            Packet::Error { code, message } => {
+                buf.extend_from_slice(&ERROR.to_be_bytes());
+                buf.extend_from_slice(&code.to_be_bytes());
+                push_str(&mut buf, message);
+            }
+            Packet::OptionAck { options } => {
+                buf.extend_from_slice(&OACK.to_be_bytes());
+                for (name, value) in options {
+                    push_str(&mut buf, name);
+                    push_str(&mut buf, value);
+                }
+            }
+        }
+        buf
+    }
+
+    fn error(code: u16, message: impl Into<String>) -> Packet {
+        Packet::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+/// Splits NUL-terminated strings. `None` if the last one is not terminated.
+fn strings(buf: &[u8]) -> Option<Vec<String>> {
+    if buf.is_empty() {
+        return Some(Vec::new());
+    }
+    if buf.last() != Some(&0) {
+        return None;
+    }
+    Some(
+        buf[..buf.len() - 1]
+            .split(|&b| b == 0)
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect(),
+    )
+}
+
+fn pairs(strings: Vec<String>) -> Vec<(String, String)> {
+    strings
+        .chunks(2)
+        .map(|pair| (pair[0].to_ascii_lowercase(), pair[1].clone()))
+        .collect()
+}
+
+fn option<'a>(options: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    options
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// One transfer between two transfer IDs (ports), with retransmission on timeout.
+struct Transfer {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    /// Whether the peer's port is known. A client learns it from the server's first answer.
+    peer_known: bool,
+    blksize: usize,
+    timeout: Duration,
+}
+
+impl Transfer {
+    fn send(&self, packet: &Packet) -> io::Result<()> {
+        self.socket.send_to(&packet.to_bytes(), self.peer)?;
+        Ok(())
+    }
+
+    /// Sends `packet` and waits for an answer that `accept` takes, sending `packet` again
+    /// whenever the peer stays silent for a timeout.
+    fn exchange<T, F>(&mut self, packet: &Packet, mut accept: F) -> anyhow::Result<T>
+    where
+        F: FnMut(&Packet) -> Option<T>,
+    {
+        self.socket.set_read_timeout(Some(self.timeout))?;
+        let mut buf = vec![0u8; MAX_BLKSIZE + 4];
+        for _ in 0..MAX_TRIES {
+            self.send(packet)?;
+            loop {
+                let (size, src) = match self.socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e)
+                        if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                    {
+                        debug!("Timeout, sending {} again", kind(packet));
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                if !self.is_peer(src) {
+                    debug!("Packet from unknown transfer ID {}", src);
+                    let reply = Packet::error(ERR_UNKNOWN_TID, "Unknown transfer ID");
+                    let _ = self.socket.send_to(&reply.to_bytes(), src);
+                    continue;
+                }
+                let reply = match Packet::parse(&buf[..size]) {
+                    Some(reply) => reply,
+                    None => continue,
+                };
+                if let Packet::Error { code, message } = reply {
+                    return Err(anyhow!("Peer reported error {}: {}", code, message));
+                }
+                if let Some(accepted) = accept(&reply) {
+                    if !self.peer_known {
+                        self.peer = src;
+                        self.peer_known = true;
+                    }
+                    return Ok(accepted);
+                }
+                // Duplicates of earlier packets end up here. Only a timeout makes us resend,
+                // which avoids the Sorcerer's Apprentice problem.
+            }
+        }
+        Err(anyhow!(
+            "No answer from {} after {} tries",
+            self.peer,
+            MAX_TRIES
+        ))
+    }
+
+    /// Acknowledges the last DATA `block`, then dallies for a timeout so that a duplicate of
+    /// it, sent because our ACK was lost, is acknowledged again (RFC 1350, section 6).
+    fn finish(&mut self, block: u16) -> anyhow::Result<()> {
+        let ack = Packet::Ack { block };
+        self.send(&ack)?;
+        self.socket.set_read_timeout(Some(self.timeout))?;
+        let mut buf = vec![0u8; MAX_BLKSIZE + 4];
+        let mut acks = 1;
+        while acks < MAX_TRIES {
+            let (size, src) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if src != self.peer {
+                continue;
+            }
+            if let Some(Packet::Data { block: got, .. }) = Packet::parse(&buf[..size]) {
+                if got == block {
+                    debug!("Duplicate final DATA, sending ACK again");
+                    self.send(&ack)?;
+                    acks += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_peer(&self, src: SocketAddr) -> bool {
+        if self.peer_known {
+            src == self.peer
+        } else {
+            src.ip() == self.peer.ip()
+        }
+    }
+
+    /// Sends the file in blocks, each after `first` has been acknowledged.
+    /// `first` is the request, the OACK, or nothing for the server of a plain RRQ.
+    fn send_file<R: Read>(&mut self, reader: &mut R, first: Option<Packet>) -> anyhow::Result<u64> {
+        let mut block: u16 = 0;
+        if let Some(first) = first {
+            self.exchange(&first, |reply| match reply {
+                Packet::Ack { block: 0 } => Some(()),
+                _ => None,
+            })?;
+        }
+        let mut total = 0;
+        let mut data = vec![0u8; self.blksize];
+        loop {
+            let size = read_full(reader, &mut data)?;
+            block = block.wrapping_add(1);
+            let packet = Packet::Data {
+                block,
+                data: data[..size].to_vec(),
+            };
+            self.exchange(&packet, |reply| match reply {
+                Packet::Ack { block: acked } if *acked == block => Some(()),
+                _ => None,
+            })?;
+            total += size as u64;
+            // A short block, possibly empty, ends the transfer.
+            if size < self.blksize {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Receives the file from `block` on, sending `first` (an OACK or an ACK) to ask for it.
+    fn receive_file<W: Write>(
+        &mut self,
+        writer: &mut W,
+        first: Packet,
+        mut block: u16,
+    ) -> anyhow::Result<u64> {
+        let mut total = 0;
+        let mut packet = first;
+        loop {
+            let data = self.exchange(&packet, |reply| match reply {
+                Packet::Data { block: got, data } if *got == block => Some(data.clone()),
+                _ => None,
+            })?;
+            writer.write_all(&data)?;
+            total += data.len() as u64;
+            if data.len() < self.blksize {
+                writer.flush()?;
+                self.finish(block)?;
+                return Ok(total);
+            }
+            packet = Packet::Ack { block };
+            block = block.wrapping_add(1);
+        }
+    }
+}
+
+/// Reads until `buf` is full or the reader ends.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn kind(packet: &Packet) -> &'static str {
+    match packet {
+        Packet::Request { opcode: RRQ, .. } => "RRQ",
+        Packet::Request { .. } => "WRQ",
+        Packet::Data { .. } => "DATA",
+        Packet::Ack { .. } => "ACK",
+        Packet::Error { .. } => "ERROR",
+        Packet::OptionAck { .. } => "OACK",
+    }
+}
+
+pub fn server(address: &str, opts: &TftpOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let root = fs::canonicalize(&opts.root)
+        .with_context(|| format!("Cannot serve {}", opts.root.display()))?;
+    let socket = net.bind_udp(address)?;
+    let local_ip = socket.local_addr()?.ip();
+    let timeout = opts.timeout();
+    let mut buf = vec![0u8; MAX_BLKSIZE + 4];
+    loop {
+        let (size, client) = socket.recv_from(&mut buf)?;
+        let request = match Packet::parse(&buf[..size]) {
+            Some(request @ Packet::Request { .. }) => request,
+            other => {
+                debug!("{}: ignoring {:?}", client, other.as_ref().map(kind));
+                continue;
+            }
+        };
+        let root = root.clone();
+        thread::spawn(move || {
+            // Every transfer gets a port of its own, its transfer ID.
+            let result = UdpSocket::bind(SocketAddr::new(local_ip, 0))
+                .map_err(anyhow::Error::from)
+                .and_then(|socket| {
+                    let mut transfer = Transfer {
+                        socket,
+                        peer: client,
+                        peer_known: true,
+                        blksize: DEFAULT_BLKSIZE,
+                        timeout,
+                    };
+                    serve_request(&mut transfer, request, &root)
+                });
+            if let Err(e) = result {
+                error!("{}: {:#}", client, e);
+            }
+        });
+    }
+}
+
+fn serve_request(transfer: &mut Transfer, request: Packet, root: &Path) -> anyhow::Result<()> {
+    let (opcode, filename, mode, options) = match request {
+        Packet::Request {
+            opcode,
+            filename,
+            mode,
+            options,
+        } => (opcode, filename, mode, options),
+        _ => unreachable!("only requests are passed on"),
+    };
+    // netascii is passed through unchanged: line endings are the client's business.
+    if mode != "octet" && mode != "netascii" {
+        transfer.send(&Packet::error(
+            ERR_ILLEGAL_OPERATION,
+            format!("Unsupported mode {}", mode),
+        ))?;
+        return Err(anyhow!("Unsupported mode {}", mode));
+    }
+    let path = match resolve_path(root, &filename).map(|path| confine(root, &path)) {
+        Some(Ok(path)) => path,
+        Some(Err(e)) if e.kind() != ErrorKind::PermissionDenied => {
+            transfer.send(&io_error(&e))?;
+            return Err(anyhow!("{:?}: {}", filename, e));
+        }
+        _ => {
+            transfer.send(&Packet::error(ERR_ACCESS_VIOLATION, "Access violation"))?;
+            return Err(anyhow!("Refusing path {:?}", filename));
+        }
+    };
+
+    if opcode == RRQ {
+        let file = File::open(&path).and_then(|file| {
+            if file.metadata()?.is_file() {
+                Ok(file)
+            } else {
+                Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "Not a regular file",
+                ))
+            }
+        });
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                transfer.send(&io_error(&e))?;
+                return Err(anyhow!("{}: {}", path.display(), e));
+            }
+        };
+        let size = file.metadata()?.len();
+        let oack = negotiate(transfer, &options, Some(size));
+        debug!(
+            "Sending {} to {} in blocks of {}",
+            filename, transfer.peer, transfer.blksize
+        );
+        let sent = transfer.send_file(&mut BufReader::new(file), oack)?;
+        println!("{}: sent {} ({} bytes)", transfer.peer, filename, sent);
+    } else {
+        let file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                transfer.send(&io_error(&e))?;
+                return Err(anyhow!("{}: {}", path.display(), e));
+            }
+        };
+        let first = negotiate(transfer, &options, None).unwrap_or(Packet::Ack { block: 0 });
+        debug!(
+            "Receiving {} from {} in blocks of {}",
+            filename, transfer.peer, transfer.blksize
+        );
+        let result = transfer.receive_file(&mut BufWriter::new(file), first, 1);
+        match result {
+            Ok(received) => println!(
+                "{}: received {} ({} bytes)",
+                transfer.peer, filename, received
+            ),
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies the options the server supports. Returns the OACK to send, if any was accepted.
+/// `size` is the size of the file for a read request.
+fn negotiate(
+    transfer: &mut Transfer,
+    options: &[(String, String)],
+    size: Option<u64>,
+) -> Option<Packet> {
+    let mut accepted = Vec::new();
+    for (name, value) in options {
+        match name.as_str() {
+            "blksize" => {
+                if let Ok(requested) = value.parse::<usize>() {
+                    if requested >= MIN_BLKSIZE {
+                        transfer.blksize = requested.min(MAX_BLKSIZE);
+                        accepted.push((name.clone(), transfer.blksize.to_string()));
+                    }
+                }
+            }
+            // RFC 2349: a reader asks with 0 and learns the size, a writer announces it.
+            "tsize" => match size {
+                Some(size) => accepted.push((name.clone(), size.to_string())),
+                None => accepted.push((name.clone(), value.clone())),
+            },
+            _ => debug!("Ignoring option {}={}", name, value),
+        }
+    }
+    if accepted.is_empty() {
+        None
+    } else {
+        Some(Packet::OptionAck { options: accepted })
+    }
+}
+
+/// The file `filename` names under `root`, unless it tries to leave `root`.
+fn resolve_path(root: &Path, filename: &str) -> Option<PathBuf> {
+    let relative = Path::new(filename.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+    Some(root.join(relative))
+}
+
+/// Follows the symbolic links in `path` and fails with `PermissionDenied` if they lead out of
+/// `root`, which must be canonical. A file that does not exist yet is checked by its directory.
+fn confine(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let resolved = match fs::canonicalize(path) {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == ErrorKind::NotFound => match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => fs::canonicalize(dir)?.join(name),
+            _ => return Err(e),
+        },
+        Err(e) => return Err(e),
+    };
+    if !resolved.starts_with(root) {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is outside the root", resolved.display()),
+        ));
+    }
+    Ok(resolved)
+}
+
+fn io_error(e: &io::Error) -> Packet {
+    match e.kind() {
+        ErrorKind::NotFound => Packet::error(ERR_FILE_NOT_FOUND, "File not found"),
+        ErrorKind::PermissionDenied => Packet::error(ERR_ACCESS_VIOLATION, "Access violation"),
+        ErrorKind::AlreadyExists => Packet::error(ERR_FILE_EXISTS, "File already exists"),
+        _ => Packet::error(ERR_NOT_DEFINED, e.to_string()),
+    }
+}
+
+pub fn client(address: &str, opts: &TftpOpts, net: &NetOpts) -> anyhow::Result<()> {
+    if let Some(blksize) = opts.blksize {
+        if !(MIN_BLKSIZE..=MAX_BLKSIZE).contains(&blksize) {
+            return Err(anyhow!(
+                "--blksize must be between {} and {}",
+                MIN_BLKSIZE,
+                MAX_BLKSIZE
+            ));
+        }
+    }
+    let server = net::resolve(address)?[0];
+    let socket = net.bind_udp(&net::unspecified(&server).to_string())?;
+    let mut transfer = Transfer {
+        socket,
+        peer: server,
+        peer_known: false,
+        blksize: DEFAULT_BLKSIZE,
+        timeout: opts.timeout(),
+    };
+    match (&opts.get, &opts.put) {
+        (Some(remote), None) => get(&mut transfer, remote, opts.blksize),
+        (None, Some(local)) => put(&mut transfer, local, opts.blksize),
+        _ => Err(anyhow!(
+            "The tftp-client role requires one of --get and --put"
+        )),
+    }
+}
+
+fn get(transfer: &mut Transfer, remote: &str, blksize: Option<usize>) -> anyhow::Result<()> {
+    let name = Path::new(remote)
+        .file_name()
+        .with_context(|| format!("{} has no file name", remote))?;
+    let mut options = vec![("tsize".to_string(), "0".to_string())];
+    if let Some(blksize) = blksize {
+        options.push(("blksize".to_string(), blksize.to_string()));
+    }
+    let request = Packet::Request {
+        opcode: RRQ,
+        filename: remote.to_string(),
+        mode: "octet".to_string(),
+        options,
+    };
+
+    // The server answers with an OACK, or right away with block 1 if it knows no options.
+    let answer = transfer.exchange(&request, |reply| match reply {
+        Packet::OptionAck { .. } | Packet::Data { block: 1, .. } => Some(reply.clone()),
+        _ => None,
+    })?;
+    let path = PathBuf::from(name);
+    let mut file = BufWriter::new(File::create(&path)?);
+    let received = match answer {
+        Packet::OptionAck { options } => {
+            apply_oack(transfer, &options)?;
+            transfer.receive_file(&mut file, Packet::Ack { block: 0 }, 1)?
+        }
+        Packet::Data { data, .. } => {
+            // Take the block we already have, then carry on from block 2.
+            file.write_all(&data)?;
+            if data.len() < transfer.blksize {
+                file.flush()?;
+                transfer.finish(1)?;
+                data.len() as u64
+            } else {
+                data.len() as u64 + transfer.receive_file(&mut file, Packet::Ack { block: 1 }, 2)?
+            }
+        }
+        _ => unreachable!("only OACK and DATA are accepted"),
+    };
+    file.flush()?;
+    println!(
+        "Received {} ({} bytes) into {}",
+        remote,
+        received,
+        path.display()
+    );
+    Ok(())
+}
+
+fn put(transfer: &mut Transfer, local: &Path, blksize: Option<usize>) -> anyhow::Result<()> {
+    let name = local
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("{} has no usable file name", local.display()))?;
+    let file = File::open(local)?;
+    let size = file.metadata()?.len();
+    let mut options = vec![("tsize".to_string(), size.to_string())];
+    if let Some(blksize) = blksize {
+        options.push(("blksize".to_string(), blksize.to_string()));
+    }
+    let request = Packet::Request {
+        opcode: WRQ,
+        filename: name.to_string(),
+        mode: "octet".to_string(),
+        options,
+    };
+
+    let answer = transfer.exchange(&request, |reply| match reply {
+        Packet::OptionAck { .. } | Packet::Ack { block: 0 } => Some(reply.clone()),
+        _ => None,
+    })?;
+    if let Packet::OptionAck { options } = answer {
+        apply_oack(transfer, &options)?;
+    }
+    let sent = transfer.send_file(&mut BufReader::new(file), None)?;
+    println!("Sent {} ({} bytes)", local.display(), sent);
+    Ok(())
+}
+
+/// Takes the options the server agreed to. A server may only lower the block size.
+fn apply_oack(transfer: &mut Transfer, options: &[(String, String)]) -> anyhow::Result<()> {
+    if let Some(blksize) = option(options, "blksize") {
+        let blksize: usize = blksize.parse().context("Bad blksize in OACK")?;
+        if !(MIN_BLKSIZE..=MAX_BLKSIZE).contains(&blksize) {
+            return Err(anyhow!("Server chose an invalid blksize {}", blksize));
+        }
+        transfer.blksize = blksize;
+    }
+    if let Some(tsize) = option(options, "tsize") {
+        debug!("Transfer size {} bytes", tsize);
+    }
+    debug!("Using blocks of {} bytes", transfer.blksize);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// A transfer whose peer is `peer`, with a short timeout.
+    fn transfer(peer: SocketAddr) -> Transfer {
+        Transfer {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            peer,
+            peer_known: true,
+            blksize: DEFAULT_BLKSIZE,
+            timeout: Duration::from_millis(200),
+        }
+    }
+
+    fn unused_peer() -> SocketAddr {
+        "127.0.0.1:9".parse().unwrap()
+    }
+
+    #[test]
+    fn request_options_round_trip() {
+        let request = Packet::Request {
+            opcode: RRQ,
+            filename: "dir/File.bin".to_string(),
+            mode: "octet".to_string(),
+            options: options(&[("blksize", "1428"), ("tsize", "0")]),
+        };
+        assert_eq!(Packet::parse(&request.to_bytes()), Some(request));
+
+        let oack = Packet::OptionAck {
+            options: options(&[("blksize", "1024")]),
+        };
+        assert_eq!(Packet::parse(&oack.to_bytes()), Some(oack));
+    }
+
+    #[test]
+    fn option_names_and_mode_are_case_insensitive() {
+        let parsed = Packet::parse(b"\x00\x02up.txt\x00OCTET\x00BlkSize\x00512\x00");
+        assert_eq!(
+            parsed,
+            Some(Packet::Request {
+                opcode: WRQ,
+                filename: "up.txt".to_string(),
+                mode: "octet".to_string(),
+                options: options(&[("blksize", "512")]),
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        // Not NUL-terminated.
+        assert_eq!(Packet::parse(b"\x00\x01file\x00octet"), None);
+        // An option without a value.
+        assert_eq!(Packet::parse(b"\x00\x01file\x00octet\x00blksize\x00"), None);
+        // No mode.
+        assert_eq!(Packet::parse(b"\x00\x01file\x00"), None);
+        assert_eq!(Packet::parse(b"\x00\x09\x00\x00"), None);
+        assert_eq!(Packet::parse(b"\x00\x03"), None);
+    }
+
+    #[test]
+    fn negotiate_accepts_and_clamps_blksize() {
+        let mut clamped = transfer(unused_peer());
+        let oack = negotiate(&mut clamped, &options(&[("blksize", "100000")]), None);
+        assert_eq!(clamped.blksize, MAX_BLKSIZE);
+        assert_eq!(
+            oack,
+            Some(Packet::OptionAck {
+                options: options(&[("blksize", "65464")]),
+            })
+        );
+
+        let mut unchanged = transfer(unused_peer());
+        let ignored = options(&[("blksize", "7"), ("blksize", "big"), ("windowsize", "4")]);
+        assert_eq!(negotiate(&mut unchanged, &ignored, None), None);
+        assert_eq!(unchanged.blksize, DEFAULT_BLKSIZE);
+    }
+
+    #[test]
+    fn negotiate_answers_tsize() {
+        let mut transfer = transfer(unused_peer());
+        // A reader asks with 0 and is told the size of the file.
+        let oack = negotiate(&mut transfer, &options(&[("tsize", "0")]), Some(4096));
+        assert_eq!(
+            oack,
+            Some(Packet::OptionAck {
+                options: options(&[("tsize", "4096")]),
+            })
+        );
+        // A writer's announcement is echoed back.
+        let oack = negotiate(&mut transfer, &options(&[("tsize", "77")]), None);
+        assert_eq!(
+            oack,
+            Some(Packet::OptionAck {
+                options: options(&[("tsize", "77")]),
+            })
+        );
+    }
+
+    #[test]
+    fn oack_blksize_must_be_valid() {
+        let mut transfer = transfer(unused_peer());
+        apply_oack(
+            &mut transfer,
+            &options(&[("blksize", "1024"), ("tsize", "9")]),
+        )
+        .unwrap();
+        assert_eq!(transfer.blksize, 1024);
+        for bad in ["4", "65465", "x"] {
+            assert!(apply_oack(&mut transfer, &options(&[("blksize", bad)])).is_err());
+        }
+        assert_eq!(transfer.blksize, 1024);
+    }
+
+    #[test]
+    fn paths_cannot_leave_the_root() {
+        let root = Path::new("/srv/tftp");
+        assert_eq!(
+            resolve_path(root, "/boot/./pxelinux.0"),
+            Some(PathBuf::from("/srv/tftp/boot/./pxelinux.0"))
+        );
+        assert_eq!(resolve_path(root, "../etc/passwd"), None);
+        assert_eq!(resolve_path(root, "boot/../../etc/passwd"), None);
+    }
+
+    #[test]
+    fn symbolic_links_cannot_leave_the_root() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir(root.join("boot")).unwrap();
+        fs::write(root.join("boot/pxelinux.0"), b"boot").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), root.join("leak")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.join("out")).unwrap();
+        std::os::unix::fs::symlink("boot/pxelinux.0", root.join("default")).unwrap();
+
+        assert_eq!(
+            confine(&root, &root.join("default")).unwrap(),
+            root.join("boot/pxelinux.0")
+        );
+        assert_eq!(
+            confine(&root, &root.join("boot/new")).unwrap(),
+            root.join("boot/new")
+        );
+        for escape in ["leak", "out/secret", "out/new"] {
+            let e = confine(&root, &root.join(escape)).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::PermissionDenied, "{}", escape);
+        }
+        let e = confine(&root, &root.join("missing/new")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+
+    /// Serves one request from `root` and returns the client's first packet back.
+    fn first_reply(root: &Path, opcode: u16, filename: &str) -> Packet {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut transfer = transfer(peer.local_addr().unwrap());
+        let request = Packet::Request {
+            opcode,
+            filename: filename.to_string(),
+            mode: "octet".to_string(),
+            options: Vec::new(),
+        };
+        let root = root.to_path_buf();
+        let serving = thread::spawn(move || serve_request(&mut transfer, request, &root));
+        let mut buf = [0u8; 1024];
+        let size = peer.recv(&mut buf).unwrap();
+        if let Ok(Ok(())) = serving.join() {
+            panic!("{} was served", filename);
+        }
+        Packet::parse(&buf[..size]).unwrap()
+    }
+
+    #[test]
+    fn refused_requests_get_an_error_packet() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir(root.join("boot")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.join("out")).unwrap();
+
+        for (opcode, filename, code) in [
+            (RRQ, "boot", ERR_NOT_DEFINED),
+            (RRQ, "/", ERR_NOT_DEFINED),
+            (RRQ, "missing", ERR_FILE_NOT_FOUND),
+            (RRQ, "out/secret", ERR_ACCESS_VIOLATION),
+            (WRQ, "out/new", ERR_ACCESS_VIOLATION),
+            (RRQ, "../secret", ERR_ACCESS_VIOLATION),
+        ] {
+            match first_reply(&root, opcode, filename) {
+                Packet::Error { code: got, .. } => assert_eq!(got, code, "{}", filename),
+                other => panic!("{}: {:?}", filename, other),
+            }
+        }
+        assert!(!outside.path().join("new").exists());
+    }
+
+    #[test]
+    fn duplicate_final_data_is_acknowledged_again() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut transfer = transfer(peer.local_addr().unwrap());
+        let local = transfer.socket.local_addr().unwrap();
+        let finishing = thread::spawn(move || transfer.finish(9));
+
+        let mut buf = [0u8; 16];
+        let ack = Packet::Ack { block: 9 }.to_bytes();
+        let size = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &ack[..]);
+        // Our ACK got lost, as far as the sender knows, so it sends the last block again.
+        let last = Packet::Data {
+            block: 9,
+            data: b"end".to_vec(),
+        };
+        peer.send_to(&last.to_bytes(), local).unwrap();
+        let size = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &ack[..]);
+
+        finishing.join().unwrap().unwrap();
+    }
+}
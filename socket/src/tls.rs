@@ -0,0 +1,102 @@
+use crate::framing::Framing;
+use crate::net::{self, NetOpts};
+use crate::{tcp_client, tcp_server};
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Clap, Debug, Default)]
+pub struct TlsOpts {
+    /// PEM file with the certificate chain presented by the TLS server.
+    #[clap(long, parse(from_os_str))]
+    pub cert: Option<PathBuf>,
+    /// PEM file with the private key of the TLS server.
+    #[clap(long, parse(from_os_str))]
+    pub key: Option<PathBuf>,
+    /// PEM file with CA certificates the TLS client trusts instead of the bundled web roots.
+    #[clap(long, parse(from_os_str))]
+    pub ca: Option<PathBuf>,
+    /// Name to verify the server certificate against. Defaults to the host part of `--host`.
+    #[clap(long)]
+    pub server_name: Option<String>,
+}
+
+pub fn server(
+    address: &str,
+    framing: Framing,
+    opts: &TlsOpts,
+    net: &NetOpts,
+) -> anyhow::Result<()> {
+    let config = Arc::new(server_config(opts)?);
+    tcp_server::serve(address, net, move |stream| {
+        let conn = ServerConnection::new(config.clone())?;
+        tcp_server::handler(StreamOwned::new(conn, stream), framing)
+    })
+}
+
+pub fn connect(
+    address: &str,
+    framing: Framing,
+    opts: &TlsOpts,
+    net: &NetOpts,
+) -> anyhow::Result<()> {
+    let config = Arc::new(client_config(opts)?);
+    let server_name = match &opts.server_name {
+        Some(name) => name.clone(),
+        None => net::host_of(address).to_string(),
+    };
+    let server_name =
+        ServerName::try_from(server_name).map_err(|e| anyhow!("Invalid server name: {}", e))?;
+
+    let stream = net.connect_tcp(address)?;
+    let conn = ClientConnection::new(config, server_name)?;
+    tcp_client::communicate(StreamOwned::new(conn, stream), framing)
+}
+
+fn server_config(opts: &TlsOpts) -> anyhow::Result<ServerConfig> {
+    let cert_path = opts.cert.as_ref().context("TLS server requires --cert")?;
+    let key_path = opts.key.as_ref().context("TLS server requires --key")?;
+
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to load private key from {}", key_path.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(config)
+}
+
+fn client_config(opts: &TlsOpts) -> anyhow::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match &opts.ca {
+        Some(ca_path) => {
+            for cert in load_certs(ca_path)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(config)
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to load certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certs)
+}
@@ -1,15 +1,59 @@
+use crate::multicast::MulticastOpts;
+use crate::net::{self, NetOpts};
+use anyhow::{anyhow, Context};
+use std::io::{self, ErrorKind};
 use std::net::UdpSocket;
-use std::{io, str};
+use std::time::{Duration, Instant};
 
-pub fn communicate(address: &str) -> anyhow::Result<()> {
-    let socket = UdpSocket::bind("127.0.0.1:0")?;
+pub fn communicate(address: &str, multicast: &MulticastOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let target = net::resolve(address)?[0];
+    let socket = UdpSocket::bind(net::unspecified(&target))?;
+    net.configure_udp(&socket)?;
+    let many_replies = multicast.configure_client(&socket, &target)?;
+    // A unicast peer replies from the address we send to, so any of its addresses will do.
+    let socket = if many_replies {
+        socket
+    } else {
+        net.connect_udp(address)?
+    };
     loop {
         let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        socket.send_to(input.as_bytes(), address)?;
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
 
         let mut buf = [0u8; 1024];
-        let _ = socket.recv(&mut buf).expect("Failed to receive");
-        print!("{}", str::from_utf8(&buf)?);
+        if !many_replies {
+            socket.send(input.as_bytes())?;
+            let size = match socket.recv(&mut buf) {
+                Ok(size) => size,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    let timeout = socket.read_timeout()?.unwrap_or_default();
+                    return Err(anyhow!("No reply from {} within {:?}", address, timeout));
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Cannot receive from {}", address))
+                }
+            };
+            print!("{}", String::from_utf8_lossy(&buf[..size]));
+            continue;
+        }
+
+        // Any number of hosts may answer a group or broadcast datagram, so collect replies for a while.
+        socket.send_to(input.as_bytes(), target)?;
+        let deadline = Instant::now() + Duration::from_millis(multicast.wait);
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            match socket.recv_from(&mut buf) {
+                Ok((size, src)) => print!("{}: {}", src, String::from_utf8_lossy(&buf[..size])),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,14 +1,63 @@
-use log::debug;
+use crate::multicast::MulticastOpts;
+use crate::net::NetOpts;
+use crate::shutdown::{self, Shutdown, Summary};
+use anyhow::anyhow;
+use log::{debug, info};
+use std::io::ErrorKind;
 use std::net::UdpSocket;
-use std::str;
+use std::time::Instant;
 
-pub fn server(address: &str) -> anyhow::Result<()> {
-    let socket = UdpSocket::bind(&address)?;
-    loop {
-        let mut buf = [0u8; 1024];
-        let (size, src) = socket.recv_from(&mut buf)?;
+/// The largest UDP payload over IPv4: 65535 bytes less the IP and UDP headers.
+pub const MAX_UDP_PAYLOAD: usize = 65507;
+
+pub fn server(address: &str, multicast: &MulticastOpts, net: &NetOpts) -> anyhow::Result<()> {
+    let (socket, _membership) = multicast.bind_server(address, net)?;
+    let summary = echo(&socket, &Shutdown::on_signals()?)?;
+    info!(
+        "Stopped: echoed {} datagrams, {} bytes",
+        summary.connections, summary.bytes
+    );
+    Ok(())
+}
+
+/// Echoes every datagram back to its sender until `shutdown` is triggered.
+/// There are no connections to drain, so it stops within one poll interval.
+/// A read timeout already set on the socket, as by `--read-timeout`, still applies: no datagram
+/// for that long is an error.
+pub fn echo(socket: &UdpSocket, shutdown: &Shutdown) -> anyhow::Result<Summary> {
+    let read_timeout = socket.read_timeout()?;
+    // Wake up regularly to notice a shutdown, which does not interrupt recv_from.
+    let poll_interval = read_timeout.map_or(shutdown::POLL_INTERVAL, |timeout| {
+        timeout.min(shutdown::POLL_INTERVAL)
+    });
+    socket.set_read_timeout(Some(poll_interval))?;
+    let mut summary = Summary::default();
+    let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+    let mut last_received = Instant::now();
+    while !shutdown.is_triggered() {
+        let (size, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                match read_timeout {
+                    Some(timeout) if last_received.elapsed() >= timeout => {
+                        return Err(anyhow!("No datagram within {:?}", timeout))
+                    }
+                    _ => continue,
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+        last_received = Instant::now();
         debug!("handling data from {}", src);
-        print!("{}", str::from_utf8(&buf[..size])?);
-        socket.send_to(&buf, src)?;
+        debug!("Echoing {:?}", String::from_utf8_lossy(&buf[..size]));
+        socket.send_to(&buf[..size], src)?;
+        summary.connections += 1;
+        summary.bytes += size as u64;
     }
-}
\ No newline at end of file
+    Ok(summary)
+}
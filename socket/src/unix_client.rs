@@ -0,0 +1,38 @@
+use crate::framing::Framing;
+use crate::tcp_client;
+use crate::unix_server::{remove_stale_socket, BoundPath};
+use anyhow::anyhow;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::PathBuf;
+use std::process;
+
+pub fn connect(path: &str, framing: Framing) -> anyhow::Result<()> {
+    let stream = UnixStream::connect(path)?;
+    let printer = tcp_client::spawn_printer(stream.try_clone()?, framing);
+    tcp_client::send_stdin(&stream, framing)?;
+    stream.shutdown(Shutdown::Write)?;
+    printer
+        .join()
+        .map_err(|_| anyhow!("Printer thread panicked"))?
+}
+
+pub fn communicate(path: &str) -> anyhow::Result<()> {
+    // Unlike UDP there is no ephemeral address, so the server can only reply to a client bound to a path.
+    let local = PathBuf::from(format!("{}.client-{}", path, process::id()));
+    remove_stale_socket(&local)?;
+    let socket = UnixDatagram::bind(&local)?;
+    let _bound = BoundPath(local);
+    loop {
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+        socket.send_to(input.as_bytes(), path)?;
+
+        let mut buf = [0u8; 1024];
+        let size = socket.recv(&mut buf)?;
+        print!("{}", String::from_utf8_lossy(&buf[..size]));
+    }
+}
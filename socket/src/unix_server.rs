@@ -0,0 +1,122 @@
+use crate::framing::Framing;
+use crate::tcp_server;
+use log::{debug, error};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::{str, thread};
+
+pub fn server(path: &str, framing: Framing) -> anyhow::Result<()> {
+    remove_stale_socket(Path::new(path))?;
+    let listener = UnixListener::bind(path)?;
+    let _bound = BoundPath(PathBuf::from(path));
+    loop {
+        let (stream, _) = listener.accept()?;
+        debug!("Handling data on {}", path);
+        thread::spawn(move || {
+            tcp_server::handler(stream, framing).unwrap_or_else(|error| error!("{:?}", error));
+        });
+    }
+}
+
+pub fn dgram_server(path: &str) -> anyhow::Result<()> {
+    remove_stale_socket(Path::new(path))?;
+    let socket = UnixDatagram::bind(path)?;
+    let _bound = BoundPath(PathBuf::from(path));
+    loop {
+        let mut buf = [0u8; 1024];
+        let (size, src) = socket.recv_from(&mut buf)?;
+        let src = match src.as_pathname() {
+            Some(src) => src.to_path_buf(),
+            None => {
+                error!("Cannot reply to an unbound datagram socket");
+                continue;
+            }
+        };
+        debug!("handling data from {}", src.display());
+        debug!("Echoing {:?}", String::from_utf8_lossy(&buf[..size]));
+        socket.send_to(&buf[..size], &src)?;
+    }
+}
+
+/// Removes a socket file left behind by a previous run, refusing to touch any other kind of file
+/// or a socket that something still listens on.
+pub fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if is_listening(path)? {
+                return Err(anyhow::anyhow!("{}: address in use", path.display()));
+            }
+            debug!("Removing stale socket {}", path.display());
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Ok(_) => Err(anyhow::anyhow!(
+            "{} exists and is not a socket",
+            path.display()
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a stream or datagram socket is still bound to `path`. Only a refused connection means
+/// that nobody is; a socket of the other type answers with a different error.
+fn is_listening(path: &Path) -> io::Result<bool> {
+    match UnixStream::connect(path) {
+        Ok(_) => return Ok(true),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => return Ok(false),
+        Err(_) => {}
+    }
+    match UnixDatagram::unbound()?.connect(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Unlinks the socket file once the socket bound to it is no longer used.
+pub struct BoundPath(pub PathBuf);
+
+impl Drop for BoundPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_sockets_are_kept_and_stale_ones_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let stream = dir.path().join("stream");
+        let listener = UnixListener::bind(&stream).unwrap();
+        let e = remove_stale_socket(&stream).unwrap_err();
+        assert!(e.to_string().contains("address in use"), "{}", e);
+        drop(listener);
+        remove_stale_socket(&stream).unwrap();
+        assert!(!stream.exists());
+
+        let dgram = dir.path().join("dgram");
+        let socket = UnixDatagram::bind(&dgram).unwrap();
+        let e = remove_stale_socket(&dgram).unwrap_err();
+        assert!(e.to_string().contains("address in use"), "{}", e);
+        drop(socket);
+        remove_stale_socket(&dgram).unwrap();
+        assert!(!dgram.exists());
+    }
+
+    #[test]
+    fn other_files_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"data").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(file.exists());
+        remove_stale_socket(&dir.path().join("missing")).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! The tokio roles must behave like their blocking counterparts, whichever side they talk to.
+#![cfg(feature = "async")]
+
+mod common;
+
+use common::{free_tcp_port, free_udp_port, local, run, stderr, stdout, Server};
+use std::time::Duration;
+
+const INPUT: &str = "hello\n\nsecond line\n";
+
+fn mode(is_async: bool) -> &'static str {
+    if is_async {
+        "async"
+    } else {
+        "thread"
+    }
+}
+
+fn tcp_echo(server_async: bool, client_async: bool, framing: &str) -> String {
+    let address = local(free_tcp_port());
+    let server_args = ["tcp", "server", "--host", &address, "--framing", framing];
+    let _server = Server::tcp(
+        &[&server_args[..], &["--mode", mode(server_async)]].concat(),
+        &address,
+    );
+    let client_args = ["tcp", "client", "--host", &address, "--framing", framing];
+    let output = run(
+        &[&client_args[..], &["--mode", mode(client_async)]].concat(),
+        INPUT,
+    );
+    assert!(output.status.success(), "{:?}", output);
+    stdout(&output)
+}
+
+fn udp_echo(server_async: bool, client_async: bool) -> String {
+    let address = local(free_udp_port());
+    let _server = Server::udp(&[
+        "udp",
+        "server",
+        "--host",
+        &address,
+        "--mode",
+        mode(server_async),
+    ]);
+    let output = run(
+        &[
+            "udp",
+            "client",
+            "--host",
+            &address,
+            "--mode",
+            mode(client_async),
+        ],
+        INPUT,
+    );
+    assert!(output.status.success(), "{:?}", output);
+    stdout(&output)
+}
+
+#[test]
+fn tcp_roles_match_the_blocking_ones() {
+    for framing in ["line", "length"] {
+        let expected = tcp_echo(false, false, framing);
+        assert_eq!(expected, INPUT);
+        for (server_async, client_async) in [(true, false), (false, true), (true, true)] {
+            assert_eq!(
+                tcp_echo(server_async, client_async, framing),
+                expected,
+                "async server: {}, async client: {}, framing: {}",
+                server_async,
+                client_async,
+                framing
+            );
+        }
+    }
+}
+
+#[test]
+fn udp_roles_match_the_blocking_ones() {
+    let expected = udp_echo(false, false);
+    assert_eq!(expected, INPUT);
+    for (server_async, client_async) in [(true, false), (false, true), (true, true)] {
+        assert_eq!(udp_echo(server_async, client_async), expected);
+    }
+}
+
+#[test]
+fn async_server_stops_on_sigint() {
+    let address = local(free_tcp_port());
+    let mut server = Server::tcp(
+        &["tcp", "server", "--host", &address, "--mode", "async"],
+        &address,
+    );
+    server.interrupt();
+    let status = server.wait(Duration::from_secs(5));
+    assert!(status.success(), "{}", status);
+}
+
+#[test]
+fn async_udp_server_stops_on_sigint() {
+    let address = local(free_udp_port());
+    let mut server = Server::udp(&["udp", "server", "--host", &address, "--mode", "async"]);
+    server.interrupt();
+    let status = server.wait(Duration::from_secs(5));
+    assert!(status.success(), "{}", status);
+}
+
+#[test]
+fn async_udp_client_gives_up_after_the_read_timeout() {
+    // Bound but never answering, so the request is neither echoed nor refused.
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = silent.local_addr().unwrap().to_string();
+    let args = [
+        "udp",
+        "client",
+        "--host",
+        &address,
+        "--mode",
+        "async",
+        "--read-timeout",
+        "200",
+    ];
+    let output = run(&args, INPUT);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("No reply"), "{}", stderr(&output));
+}
+
+#[test]
+fn udp_servers_stop_after_the_read_timeout() {
+    for is_async in [false, true] {
+        let address = local(free_udp_port());
+        let mut server = Server::udp(&[
+            "udp",
+            "server",
+            "--host",
+            &address,
+            "--mode",
+            mode(is_async),
+            "--read-timeout",
+            "300",
+        ]);
+        let status = server.wait(Duration::from_secs(5));
+        assert!(!status.success(), "async: {}", is_async);
+    }
+}
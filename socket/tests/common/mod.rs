@@ -0,0 +1,133 @@
+//! Helpers shared by the integration tests, which run the `socket` binary the way a user would.
+
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a test waits for a server process to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn socket() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_socket"));
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+}
+
+/// A loopback port that was free a moment ago.
+pub fn free_tcp_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+pub fn free_udp_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A server process, killed when the test is done with it.
+pub struct Server(Child);
+
+/// Starts a server whose output nobody reads, so that it cannot fill a pipe and block.
+fn spawn(args: &[&str]) -> Server {
+    let mut command = socket();
+    command
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    Server(command.spawn().unwrap())
+}
+
+impl Server {
+    /// Starts `socket` with `args` and waits until `address` accepts TCP connections.
+    pub fn tcp(args: &[&str], address: &str) -> Server {
+        let server = spawn(args);
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while TcpStream::connect(address).is_err() {
+            assert!(
+                Instant::now() < deadline,
+                "{} did not start listening",
+                address
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        server
+    }
+
+    /// Starts `socket` with `args`. UDP has no handshake to wait for, so give it a moment to bind.
+    pub fn udp(args: &[&str]) -> Server {
+        let server = spawn(args);
+        thread::sleep(Duration::from_millis(300));
+        server
+    }
+
+    /// Sends SIGINT, as Ctrl-C in a terminal would.
+    pub fn interrupt(&self) {
+        let pid = self.0.id().to_string();
+        let status = Command::new("kill").args(["-INT", &pid]).status().unwrap();
+        assert!(status.success());
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        self.0.try_wait().unwrap().is_none()
+    }
+
+    /// Waits for the process to exit on its own, failing the test after `timeout`.
+    pub fn wait(&mut self, timeout: Duration) -> ExitStatus {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                return status;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "still running after {:?}",
+                timeout
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Runs `socket` with `args` to completion, feeding it `input` on stdin.
+pub fn run(args: &[&str], input: &str) -> Output {
+    let mut child = socket().args(args).stdin(Stdio::piped()).spawn().unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+pub fn local(port: u16) -> String {
+    SocketAddr::from(([127, 0, 0, 1], port)).to_string()
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::{free_udp_port, local, run, stderr, stdout, Server};
+use std::fs;
+use tempfile::TempDir;
+
+/// A zone whose `big` TXT records do not fit in a 512-byte UDP answer.
+fn zone() -> String {
+    let mut zone = String::from("$ORIGIN example.com.\n@ IN A 192.0.2.1\n");
+    for i in 0..4 {
+        zone.push_str(&format!("big TXT \"{}{}\"\n", i, "x".repeat(200)));
+    }
+    zone
+}
+
+fn server() -> (Server, String, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("example.com.zone");
+    fs::write(&path, zone()).unwrap();
+    let address = local(free_udp_port());
+    let server = Server::udp(&[
+        "dns",
+        "server",
+        "--host",
+        &address,
+        "--zone",
+        path.to_str().unwrap(),
+    ]);
+    (server, address, dir)
+}
+
+fn query(address: &str, name: &str, qtype: &str) -> String {
+    let output = run(
+        &[
+            "dns", "client", "--host", address, "--query", name, "--qtype", qtype,
+        ],
+        "",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    stdout(&output)
+}
+
+#[test]
+fn short_answers_come_over_udp() {
+    let (_server, address, _dir) = server();
+    let answer = query(&address, "example.com", "A");
+    assert!(answer.contains("status: NOERROR"), "{}", answer);
+    assert!(answer.contains("192.0.2.1"), "{}", answer);
+    assert!(
+        answer.contains(&format!("SERVER: {}(udp)", address)),
+        "{}",
+        answer
+    );
+}
+
+#[test]
+fn truncated_answers_are_retried_over_tcp() {
+    let (_server, address, _dir) = server();
+    let answer = query(&address, "big.example.com", "TXT");
+    assert!(answer.contains("ANSWER: 4"), "{}", answer);
+    for i in 0..4 {
+        assert!(
+            answer.contains(&format!("{}{}", i, "x".repeat(200))),
+            "{}",
+            answer
+        );
+    }
+    assert!(
+        answer.contains(&format!("SERVER: {}(tcp)", address)),
+        "{}",
+        answer
+    );
+}
+
+#[test]
+fn unknown_names_are_nxdomain() {
+    let (_server, address, _dir) = server();
+    let answer = query(&address, "missing.example.com", "A");
+    assert!(answer.contains("status: NXDOMAIN"), "{}", answer);
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use common::{free_tcp_port, local, Server};
+use socket::framing::Framing;
+use socket::net::NetOpts;
+use socket::shutdown::Summary;
+use socket::tcp_client::EchoClient;
+use socket::tcp_server::EchoServer;
+use std::io::Read;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn client(address: &str) -> EchoClient {
+    EchoClient::connect(address, Framing::Line, &NetOpts::default()).unwrap()
+}
+
+#[test]
+fn stop_reports_what_was_served() {
+    let server = EchoServer::new("127.0.0.1:0").spawn().unwrap();
+    let address = server.local_addr().to_string();
+    for msg in [&b"hello"[..], b"hi"] {
+        let mut client = client(&address);
+        assert_eq!(client.request(msg).unwrap(), msg);
+    }
+    let summary = server.stop().unwrap();
+    assert_eq!(
+        summary,
+        Summary {
+            connections: 2,
+            bytes: 7
+        }
+    );
+}
+
+#[test]
+fn open_connections_are_served_while_draining() {
+    let server = EchoServer::new("127.0.0.1:0")
+        .drain_timeout(Duration::from_secs(10))
+        .spawn()
+        .unwrap();
+    let address = server.local_addr().to_string();
+    let mut client = client(&address);
+    assert_eq!(client.request(b"before").unwrap(), b"before");
+
+    let shutdown = server.shutdown_handle();
+    let start = Instant::now();
+    let stopping = thread::spawn(move || server.stop());
+    shutdown.trigger();
+    thread::sleep(Duration::from_millis(300));
+    assert!(TcpStream::connect(&address).is_err(), "still accepting");
+    assert_eq!(client.request(b"during").unwrap(), b"during");
+
+    // The server stops as soon as its last connection is done, not at the drain timeout.
+    drop(client);
+    let summary = stopping.join().unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(summary.bytes, 12);
+}
+
+#[test]
+fn connections_still_open_after_the_drain_timeout_are_closed() {
+    let drain_timeout = Duration::from_millis(300);
+    let server = EchoServer::new("127.0.0.1:0")
+        .drain_timeout(drain_timeout)
+        .spawn()
+        .unwrap();
+    let client = client(&server.local_addr().to_string());
+    // Wait until the server has accepted the connection, which an idle client cannot tell.
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let summary = server.stop().unwrap();
+    assert!(start.elapsed() >= drain_timeout);
+    assert_eq!(summary.connections, 1);
+
+    let mut rest = Vec::new();
+    client
+        .stream()
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut stream = client.stream();
+    assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn server_role_drains_and_exits_on_sigint() {
+    let address = local(free_tcp_port());
+    let mut server = Server::tcp(&["tcp", "server", "--host", &address], &address);
+    let mut client = client(&address);
+    assert_eq!(client.request(b"hello").unwrap(), b"hello");
+
+    server.interrupt();
+    thread::sleep(Duration::from_millis(300));
+    assert!(server.is_running(), "exited without draining");
+    assert_eq!(client.request(b"draining").unwrap(), b"draining");
+
+    drop(client);
+    let status = server.wait(Duration::from_secs(5));
+    assert!(status.success(), "{}", status);
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{free_tcp_port, local, Server};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// An upstream that only answers once the client has finished sending.
+fn counting_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).unwrap();
+            write!(stream, "got {} bytes", request.len()).unwrap();
+        }
+    });
+    address
+}
+
+#[test]
+fn reply_arrives_after_the_client_closes_its_side() {
+    let upstream = counting_upstream();
+    let address = local(free_tcp_port());
+    let _forward = Server::tcp(
+        &[
+            "tcp",
+            "forward",
+            "--host",
+            &address,
+            "--upstream",
+            &upstream,
+        ],
+        &address,
+    );
+
+    for size in [0, 5, 100_000] {
+        let mut client = TcpStream::connect(&address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(&vec![b'x'; size]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, format!("got {} bytes", size));
+    }
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use common::{run, stderr, stdout};
+use std::net::UdpSocket;
+use std::process::Output;
+
+/// Runs an ICMP role, or returns `None` where this process may open no ICMP socket at all.
+fn icmp(args: &[&str]) -> Option<Output> {
+    let output = run(&[&["icmp"][..], args].concat(), "");
+    if stderr(&output).contains("ICMP needs a raw socket") {
+        eprintln!("Skipped: no permission to open ICMP sockets");
+        return None;
+    }
+    Some(output)
+}
+
+/// Whether this host has IPv6 loopback at all, which containers often lack.
+fn has_ipv6() -> bool {
+    if UdpSocket::bind("[::1]:0").is_ok() {
+        return true;
+    }
+    eprintln!("Skipped: no IPv6 loopback");
+    false
+}
+
+fn ping_gets_replies_from(host: &str) {
+    let output = match icmp(&["ping", "--host", host, "--count", "3", "--interval", "10"]) {
+        Some(output) => output,
+        None => return,
+    };
+    assert!(output.status.success(), "{:?}", output);
+    let report = stdout(&output);
+    assert!(
+        report.contains(&format!("PING {}: 56 data bytes", host)),
+        "{}",
+        report
+    );
+    for seq in 1..=3 {
+        assert!(
+            report.contains(&format!("bytes from {}: icmp_seq={}", host, seq)),
+            "{}",
+            report
+        );
+    }
+    assert!(
+        report.contains("3 packets transmitted, 3 received, 0.0% packet loss"),
+        "{}",
+        report
+    );
+    assert!(report.contains("rtt min/avg/max/mdev"), "{}", report);
+}
+
+#[test]
+fn ping_gets_replies_from_loopback() {
+    ping_gets_replies_from("127.0.0.1");
+}
+
+#[test]
+fn ping_gets_replies_from_ipv6_loopback() {
+    if has_ipv6() {
+        ping_gets_replies_from("::1");
+    }
+}
+
+fn ping_size_sets_the_payload_to(host: &str) {
+    let output = match icmp(&["ping", "--host", host, "--count", "1", "--ping-size", "100"]) {
+        Some(output) => output,
+        None => return,
+    };
+    assert!(output.status.success(), "{:?}", output);
+    // The reply is counted from the ICMP header on, like ping(8) does.
+    assert!(
+        stdout(&output).contains(&format!("108 bytes from {}", host)),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn ping_size_sets_the_payload() {
+    ping_size_sets_the_payload_to("127.0.0.1");
+}
+
+#[test]
+fn ping_size_sets_the_ipv6_payload() {
+    if has_ipv6() {
+        ping_size_sets_the_payload_to("::1");
+    }
+}
+
+fn traceroute_takes_one_hop_to(host: &str) {
+    let output = match icmp(&["traceroute", "--host", host, "--probes", "1"]) {
+        Some(output) => output,
+        None => return,
+    };
+    assert!(output.status.success(), "{:?}", output);
+    let lines: Vec<String> = stdout(&output).lines().map(str::to_string).collect();
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    assert!(lines[0].starts_with(&format!("traceroute to {}", host)));
+    assert!(
+        lines[1].starts_with(&format!(" 1  {}  ", host)),
+        "{:?}",
+        lines
+    );
+}
+
+#[test]
+fn traceroute_to_loopback_takes_one_hop() {
+    traceroute_takes_one_hop_to("127.0.0.1");
+}
+
+#[test]
+fn traceroute_to_ipv6_loopback_takes_one_hop() {
+    if has_ipv6() {
+        traceroute_takes_one_hop_to("::1");
+    }
+}
+
+#[test]
+fn traceroute_rejects_impossible_hop_limits() {
+    for max_hops in ["0", "256"] {
+        let output = run(
+            &[
+                "icmp",
+                "traceroute",
+                "--host",
+                "127.0.0.1",
+                "--max-hops",
+                max_hops,
+            ],
+            "",
+        );
+        assert!(!output.status.success());
+        assert!(stderr(&output).contains("--max-hops"), "{:?}", output);
+    }
+}
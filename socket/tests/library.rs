@@ -0,0 +1,116 @@
+//! The crate used as a library, the way another crate's tests would embed it.
+
+use socket::framing::Framing;
+use socket::net::NetOpts;
+use socket::shutdown::{Shutdown, Summary};
+use socket::tcp_client::EchoClient;
+use socket::tcp_server::EchoServer;
+use socket::udp_server;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn length_framing_carries_any_bytes() {
+    let server = EchoServer::new("127.0.0.1:0")
+        .framing(Framing::Length)
+        .spawn()
+        .unwrap();
+    let address = server.local_addr().to_string();
+    let mut client = EchoClient::connect(&address, Framing::Length, &NetOpts::default()).unwrap();
+    let msg: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+    assert_eq!(client.request(&msg).unwrap(), msg);
+    assert_eq!(client.request(b"").unwrap(), b"");
+    drop(client);
+    assert_eq!(server.stop().unwrap().bytes, 100_000);
+}
+
+#[test]
+fn custom_handler_replaces_the_echo() {
+    let server = EchoServer::new("127.0.0.1:0")
+        .handler(|mut stream: TcpStream| {
+            let mut line = String::new();
+            BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+            stream.write_all(line.to_uppercase().as_bytes())?;
+            Ok(())
+        })
+        .spawn()
+        .unwrap();
+    let address = server.local_addr().to_string();
+    let mut client = EchoClient::connect(&address, Framing::Line, &NetOpts::default()).unwrap();
+    assert_eq!(client.request(b"shout").unwrap(), b"SHOUT");
+    drop(client);
+    assert_eq!(
+        server.stop().unwrap(),
+        Summary {
+            connections: 1,
+            bytes: 0
+        }
+    );
+}
+
+#[test]
+fn socket_options_apply_to_both_ends() {
+    let net = NetOpts {
+        nodelay: true,
+        ..NetOpts::default()
+    };
+    let server = EchoServer::new("127.0.0.1:0")
+        .net(net.clone())
+        .spawn()
+        .unwrap();
+    let address = server.local_addr().to_string();
+    let mut client = EchoClient::connect(&address, Framing::Line, &net).unwrap();
+    assert!(client.stream().nodelay().unwrap());
+    assert_eq!(client.request(b"fast").unwrap(), b"fast");
+    drop(client);
+    server.stop().unwrap();
+}
+
+#[test]
+fn udp_echo_runs_until_triggered() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    let shutdown = Shutdown::new();
+    let handle = shutdown.clone();
+    let server = thread::spawn(move || udp_server::echo(&socket, &handle));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let big = vec![b'x'; udp_server::MAX_UDP_PAYLOAD];
+    let mut buf = vec![0u8; 65536];
+    for msg in [&b"ping"[..], &big] {
+        client.send_to(msg, address).unwrap();
+        let size = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], msg);
+    }
+
+    shutdown.trigger();
+    let summary = server.join().unwrap().unwrap();
+    assert_eq!(summary.connections, 2);
+    assert_eq!(summary.bytes, 4 + udp_server::MAX_UDP_PAYLOAD as u64);
+}
+
+#[test]
+fn udp_echo_keeps_the_read_timeout() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let start = Instant::now();
+    let server = thread::spawn(move || udp_server::echo(&socket, &Shutdown::new()));
+
+    // A datagram restarts the timeout.
+    thread::sleep(Duration::from_millis(300));
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.send_to(b"ping", address).unwrap();
+
+    let e = server.join().unwrap().unwrap_err();
+    assert!(e.to_string().contains("No datagram within"), "{}", e);
+    assert!(start.elapsed() >= Duration::from_millis(800));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use common::{free_udp_port, local, run, socket, stderr};
+use socket::udp_server::MAX_UDP_PAYLOAD;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::process::Stdio;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn udp_input_is_sent_in_datagrams_that_fit() {
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let address = peer.local_addr().unwrap().to_string();
+    let input = "x".repeat(100_000);
+    let netcat = thread::spawn(move || {
+        let args = ["udp", "connect", "-w", "1", "--host", &address];
+        run(&args, &input)
+    });
+
+    let mut buf = vec![0u8; 65536];
+    let mut received = 0;
+    while received < 100_000 {
+        let nbytes = peer.recv(&mut buf).unwrap();
+        assert!(
+            nbytes <= MAX_UDP_PAYLOAD,
+            "{} bytes in one datagram",
+            nbytes
+        );
+        received += nbytes;
+    }
+    assert_eq!(received, 100_000);
+    let output = netcat.join().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn a_refused_datagram_is_an_error() {
+    // Nothing listens on the port, so the kernel turns the first datagram into a refusal.
+    let address = local(free_udp_port());
+    let mut netcat = socket()
+        .args(["udp", "connect", "--host", &address])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The process may be gone before it has read all of this.
+    let _ = netcat.stdin.take().unwrap().write_all(&[b'x'; 200_000]);
+    let output = netcat.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("refused"), "{}", stderr(&output));
+}
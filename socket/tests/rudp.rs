@@ -0,0 +1,18 @@
+mod common;
+
+use common::{free_udp_port, local, run, stderr, stdout, Server};
+
+#[test]
+fn messages_arrive_intact_and_in_order_despite_loss() {
+    let address = local(free_udp_port());
+    let _server = Server::udp(&["rudp", "server", "--host", &address, "--loss", "0.2"]);
+    let input: String = (0..10)
+        .map(|i| format!("message {} {}\n", i, "x".repeat(i * 50)))
+        .collect();
+    let output = run(
+        &["rudp", "client", "--host", &address, "--loss", "0.2"],
+        &input,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), input);
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use common::{run, socket, stderr, stdout};
+use socket::net::NetOpts;
+use socket::scan::{self, PortState, Ports};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A loopback port nobody listens on, as far as a test can tell.
+fn closed_tcp_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn ports(ports: &[u16]) -> Ports {
+    let list: Vec<String> = ports.iter().map(u16::to_string).collect();
+    list.join(",").parse().unwrap()
+}
+
+#[test]
+fn port_lists_and_ranges_are_parsed() {
+    let ports: Ports = "8002, 8000-8002,8000".parse().unwrap();
+    let results = scan::scan(LOCALHOST, &ports, 2, |_| PortState::Closed).unwrap();
+    let scanned: Vec<u16> = results.iter().map(|(port, _)| *port).collect();
+    assert_eq!(scanned, [8000, 8001, 8002]);
+
+    assert!("10-1".parse::<Ports>().is_err());
+    assert!("80,http".parse::<Ports>().is_err());
+    assert!("65536".parse::<Ports>().is_err());
+    assert!(",".parse::<Ports>().is_err());
+}
+
+#[test]
+fn scan_keeps_port_order_whatever_the_parallelism() {
+    let ports: Ports = "1-200".parse().unwrap();
+    for parallelism in [1, 7, 500] {
+        let results = scan::scan(LOCALHOST, &ports, parallelism, |addr| {
+            if addr.port() % 2 == 0 {
+                PortState::Open
+            } else {
+                PortState::Closed
+            }
+        })
+        .unwrap();
+        assert_eq!(results.len(), 200);
+        for (i, (port, state)) in results.iter().enumerate() {
+            assert_eq!(*port as usize, i + 1);
+            assert_eq!(*state == PortState::Open, port % 2 == 0);
+        }
+    }
+    assert!(scan::scan(LOCALHOST, &ports, 0, |_| PortState::Closed).is_err());
+}
+
+#[test]
+fn tcp_probe_tells_open_from_closed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap().port();
+    let closed = closed_tcp_port();
+
+    let results = scan::scan(LOCALHOST, &ports(&[open, closed]), 2, |addr| {
+        scan::probe_tcp(addr, TIMEOUT, &NetOpts::default())
+    })
+    .unwrap();
+    assert!(results.contains(&(open, PortState::Open)));
+    assert!(results.contains(&(closed, PortState::Closed)));
+}
+
+#[test]
+fn udp_probe_tells_answering_silent_and_closed_ports_apart() {
+    let answering = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let answering_port = answering.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let mut buf = [0u8; 16];
+        while let Ok((_, src)) = answering.recv_from(&mut buf) {
+            let _ = answering.send_to(b"hi", src);
+        }
+    });
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let silent_port = silent.local_addr().unwrap().port();
+    let closed_port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let net = NetOpts::default();
+    let probe = |port| scan::probe_udp(SocketAddr::new(LOCALHOST, port), TIMEOUT, &net);
+    assert_eq!(probe(answering_port), PortState::Open);
+    assert_eq!(probe(silent_port), PortState::OpenOrFiltered);
+    assert_eq!(probe(closed_port), PortState::Closed);
+}
+
+#[test]
+fn scan_role_reports_open_ports() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap().port();
+    let closed = closed_tcp_port();
+    let list = format!("{},{}", open, closed);
+
+    let output = run(
+        &["tcp", "scan", "--host", "127.0.0.1", "--ports", &list],
+        "",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let report = stdout(&output);
+    assert!(report.contains(&format!("{}/tcp", open)), "{}", report);
+    assert!(!report.contains(&format!("{}/tcp", closed)), "{}", report);
+    assert!(report.contains("1 open, 1 closed"), "{}", report);
+
+    let output = run(
+        &[
+            "tcp",
+            "scan",
+            "--host",
+            "127.0.0.1",
+            "--ports",
+            &list,
+            "--show-closed",
+        ],
+        "",
+    );
+    let report = stdout(&output);
+    assert!(report.contains(&format!("{}/tcp", closed)), "{}", report);
+}
+
+#[test]
+fn scan_role_applies_the_socket_options() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap().port().to_string();
+
+    let output = socket()
+        .args(["tcp", "scan", "--host", "127.0.0.1", "--ports", &open])
+        .args(["--linger", "0"])
+        .env("RUST_LOG", "debug")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout(&output).contains("1 open"), "{:?}", output);
+    assert!(
+        stderr(&output).contains("SO_LINGER=Some(0ns)"),
+        "{}",
+        stderr(&output)
+    );
+}
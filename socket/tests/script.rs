@@ -0,0 +1,89 @@
+mod common;
+
+use common::{free_tcp_port, free_udp_port, local, run, stderr, stdout, Server};
+use std::fs;
+use tempfile::TempDir;
+
+fn script(text: &str) -> (TempDir, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.script");
+    fs::write(&path, text).unwrap();
+    let path = path.to_str().unwrap().to_string();
+    (dir, path)
+}
+
+const ECHO: &str = "# the echo server answers every message with itself
+send hello
+expect ^hello$
+timeout 1000
+send tab\\there
+expect ^tab\\there$
+";
+
+#[test]
+fn script_passes_against_the_tcp_echo_server() {
+    let address = local(free_tcp_port());
+    let _server = Server::tcp(&["tcp", "server", "--host", &address], &address);
+    let (_dir, path) = script(ECHO);
+    let output = run(
+        &["tcp", "client", "--host", &address, "--script", &path],
+        "",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("all 5 steps passed"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn script_passes_against_the_udp_echo_server() {
+    let address = local(free_udp_port());
+    let _server = Server::udp(&["udp", "server", "--host", &address]);
+    let (_dir, path) = script(ECHO);
+    let output = run(
+        &["udp", "client", "--host", &address, "--script", &path],
+        "",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("all 5 steps passed"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn a_mismatch_fails_the_script() {
+    let address = local(free_tcp_port());
+    let _server = Server::tcp(&["tcp", "server", "--host", &address], &address);
+    let (_dir, path) = script("send hello\nexpect ^goodbye$\n");
+    let output = run(
+        &["tcp", "client", "--host", &address, "--script", &path],
+        "",
+    );
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("test.script:2: expected /^goodbye$/"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn expect_close_is_refused_for_udp_before_anything_is_sent() {
+    let address = local(free_udp_port());
+    let (_dir, path) = script("send hello\nexpect-close\n");
+    let output = run(
+        &["udp", "client", "--host", &address, "--script", &path],
+        "",
+    );
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("test.script:2"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!stdout(&output).contains("> hello"), "{}", stdout(&output));
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use common::{free_udp_port, local, run, stderr, stdout, Server};
+use std::net::UdpSocket;
+use std::thread;
+
+const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+
+fn server() -> (Server, String) {
+    let address = local(free_udp_port());
+    (
+        Server::udp(&["stun", "server", "--host", &address]),
+        address,
+    )
+}
+
+/// A STUN server that maps every client to the port after its own, as a symmetric NAT would
+/// for a second destination.
+fn shifting_server() -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        while let Ok((size, src)) = socket.recv_from(&mut buf) {
+            if size < 20 {
+                continue;
+            }
+            let port = (src.port() + 1) ^ 0x2112;
+            let mut response = vec![0x01, 0x01, 0x00, 0x0c];
+            response.extend_from_slice(&MAGIC_COOKIE);
+            response.extend_from_slice(&buf[8..20]);
+            response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+            response.extend_from_slice(&port.to_be_bytes());
+            response.extend([127, 0, 0, 1].iter().zip(&MAGIC_COOKIE).map(|(b, m)| b ^ m));
+            let _ = socket.send_to(&response, src);
+        }
+    });
+    address
+}
+
+#[test]
+fn two_servers_see_the_same_mapping_on_loopback() {
+    let (_first, first) = server();
+    let (_second, second) = server();
+
+    let output = run(
+        &["stun", "client", "--host", &first, "--stun-server", &second],
+        "",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let report = stdout(&output);
+    let local_port = report
+        .lines()
+        .next()
+        .and_then(|line| line.rsplit(':').next())
+        .unwrap()
+        .to_string();
+    for server in [&first, &second] {
+        let line = format!("{}: mapped address 127.0.0.1:{} (", server, local_port);
+        assert!(report.contains(&line), "{}", report);
+    }
+    assert!(
+        report.contains("Same mapped address from all 2 servers"),
+        "{}",
+        report
+    );
+}
+
+#[test]
+fn one_server_cannot_tell_the_mapping() {
+    let (_server, address) = server();
+    let output = run(&["stun", "client", "--host", &address], "");
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout(&output).contains("Ask more than one server"));
+}
+
+#[test]
+fn differing_mappings_fail_the_client() {
+    let (_server, address) = server();
+    let shifting = shifting_server();
+    let output = run(
+        &[
+            "stun",
+            "client",
+            "--host",
+            &address,
+            "--stun-server",
+            &shifting,
+        ],
+        "",
+    );
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(
+        stderr(&output).contains("Mapped port depends on the server"),
+        "{:?}",
+        output
+    );
+}
+
+#[test]
+fn server_survives_garbage() {
+    let (mut server, address) = server();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for garbage in [&b""[..], b"hello", &[0u8; 20], &[0xffu8; 1500]] {
+        socket.send_to(garbage, &address).unwrap();
+    }
+    let output = run(&["stun", "client", "--host", &address], "");
+    assert!(output.status.success(), "{:?}", output);
+    assert!(server.is_running());
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{free_tcp_port, local, run, stderr, stdout, Server};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Writes a self-signed certificate for localhost and its key, returning their paths.
+fn self_signed(dir: &Path) -> (String, String) {
+    let names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    let certified = rcgen::generate_simple_self_signed(names).unwrap();
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    (
+        cert.to_str().unwrap().to_string(),
+        key.to_str().unwrap().to_string(),
+    )
+}
+
+fn server(dir: &TempDir) -> (Server, String, String) {
+    let (cert, key) = self_signed(dir.path());
+    let address = local(free_tcp_port());
+    let args = [
+        "tcp",
+        "tls-server",
+        "--host",
+        &address,
+        "--cert",
+        &cert,
+        "--key",
+        &key,
+    ];
+    (Server::tcp(&args, &address), address, cert)
+}
+
+#[test]
+fn client_trusting_the_certificate_gets_echoes() {
+    let dir = TempDir::new().unwrap();
+    let (_server, address, cert) = server(&dir);
+
+    let output = run(
+        &["tcp", "tls-client", "--host", &address, "--ca", &cert],
+        "hello\nworld\n",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(stdout(&output), "hello\nworld\n");
+}
+
+#[test]
+fn server_name_is_verified() {
+    let dir = TempDir::new().unwrap();
+    let (_server, address, cert) = server(&dir);
+
+    let args = ["tcp", "tls-client", "--host", &address, "--ca", &cert];
+    let output = run(
+        &[&args[..], &["--server-name", "localhost"]].concat(),
+        "hi\n",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(stdout(&output), "hi\n");
+
+    let output = run(
+        &[&args[..], &["--server-name", "example.com"]].concat(),
+        "hi\n",
+    );
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn untrusted_certificate_is_rejected() {
+    let dir = TempDir::new().unwrap();
+    let (_server, address, _cert) = server(&dir);
+
+    // Without --ca the client only trusts the web roots, which did not sign our certificate.
+    let output = run(&["tcp", "tls-client", "--host", &address], "hello\n");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("UnknownIssuer"), "{:?}", output);
+}